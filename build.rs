@@ -1,6 +1,15 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
 use dbc_codegen::{Config, FeatureConfig};
 
 fn main() {
+    // memory.x keeps the configuration store out of the program
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::copy("memory.x", out_dir.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out_dir.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     #[cfg(feature = "defmt")]
@@ -19,4 +28,447 @@ fn main() {
 
     let mut out = std::io::BufWriter::new(std::fs::File::create("src/messages.rs").unwrap());
     dbc_codegen::codegen(config, &mut out).expect("dbc-codegen failed");
+
+    let dbc = String::from_utf8_lossy(&dbc_file);
+    out.write_all(message_timing(&dbc).as_bytes())
+        .expect("writing message timing failed");
+
+    let ldf_path = "STM_LIN.ldf";
+    let ldf_file = std::fs::read_to_string(ldf_path).unwrap();
+    println!("cargo:rerun-if-changed={}", ldf_path);
+    std::fs::write("src/lin_frames.rs", lin_codegen(&ldf_file)).unwrap();
+}
+
+/// Message name as emitted by dbc-codegen, e.g. `SPEED_KMH` -> `SpeedKmh`.
+fn type_name(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            core::iter::once(first)
+                .chain(chars.map(|c| c.to_ascii_lowercase()))
+                .collect::<String>()
+        })
+        .collect()
+}
+
+/// Generates `MessageTiming` implementations from the `GenMsgCycleTime`,
+/// `GenMsgDelayTime` and `GenMsgSendType` attributes of every message that
+/// sets at least one of them.
+fn message_timing(dbc: &str) -> String {
+    #[derive(Default, Clone)]
+    struct Timing {
+        cycle_time: u64,
+        delay_time: u64,
+        send_type: usize,
+    }
+
+    let mut names = BTreeMap::new();
+    let mut send_types = Vec::new();
+    let mut defaults = Timing::default();
+    let mut timings: BTreeMap<u32, Timing> = BTreeMap::new();
+
+    for line in dbc.lines().map(str::trim) {
+        let tokens: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ';' || c == ':')
+            .filter(|t| !t.is_empty())
+            .collect();
+
+        match tokens.as_slice() {
+            ["BO_", id, name, ..] => {
+                names.insert(id.parse::<u32>().unwrap(), name.to_string());
+            }
+            ["BA_DEF_", "BO_", "\"GenMsgSendType\"", "ENUM", ..] => {
+                let values = line.split_once("ENUM").unwrap().1;
+                send_types = values
+                    .trim_end_matches(';')
+                    .split(',')
+                    .map(|v| v.trim().trim_matches('"').to_string())
+                    .collect();
+            }
+            ["BA_DEF_DEF_", "\"GenMsgCycleTime\"", value] => {
+                defaults.cycle_time = value.parse().unwrap();
+            }
+            ["BA_DEF_DEF_", "\"GenMsgDelayTime\"", value] => {
+                defaults.delay_time = value.parse().unwrap();
+            }
+            ["BA_DEF_DEF_", "\"GenMsgSendType\"", value] => {
+                let value = value.trim_matches('"');
+                defaults.send_type = send_types
+                    .iter()
+                    .position(|v| v == value)
+                    .expect("unknown default GenMsgSendType");
+            }
+            ["BA_", attribute, "BO_", id, value] => {
+                let id: u32 = id.parse().unwrap();
+                let timing = timings.entry(id).or_insert_with(|| defaults.clone());
+                match *attribute {
+                    "\"GenMsgCycleTime\"" => timing.cycle_time = value.parse().unwrap(),
+                    "\"GenMsgDelayTime\"" => timing.delay_time = value.parse().unwrap(),
+                    "\"GenMsgSendType\"" => timing.send_type = value.parse().unwrap(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut code = String::from(
+        r#"
+/// Transmission type from the `GenMsgSendType` DBC attribute
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SendType {
+"#,
+    );
+    for send_type in &send_types {
+        code += &format!("    {},\n", send_type);
+    }
+    code += r#"}
+
+/// Transmission timing from the DBC message attributes
+pub trait MessageTiming {
+    /// `GenMsgCycleTime`, 0 if the message is not sent periodically
+    const CYCLE_TIME_MS: u64;
+    /// `GenMsgDelayTime`, minimal gap between two event triggered transmissions
+    const DELAY_TIME_MS: u64;
+    /// `GenMsgSendType`
+    const SEND_TYPE: SendType;
+}
+"#;
+
+    for (id, timing) in timings {
+        let name = names.get(&id).expect("attribute for unknown message");
+        code += &format!(
+            r#"
+impl MessageTiming for {} {{
+    const CYCLE_TIME_MS: u64 = {};
+    const DELAY_TIME_MS: u64 = {};
+    const SEND_TYPE: SendType = SendType::{};
+}}
+"#,
+            type_name(name),
+            timing.cycle_time,
+            timing.delay_time,
+            send_types[timing.send_type],
+        );
+    }
+
+    code
+}
+
+struct LinFrame {
+    name: String,
+    id: u8,
+    publisher: String,
+    len: usize,
+    /// slave publishing or receiving the frame
+    node: usize,
+    kind: LinFrameKind,
+}
+
+enum LinFrameKind {
+    Unconditional,
+    /// indices of the associated frames, highest priority first
+    Sporadic(Vec<usize>),
+    /// associated frames and the collision resolving schedule table
+    EventTriggered(Vec<usize>, String),
+}
+
+/// Splits LDF source into identifiers, numbers and punctuation.
+fn ldf_tokens(ldf: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut chars = ldf.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '"' => {
+                let text: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push(format!("\"{}\"", text));
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '.' => token.push(c),
+            c => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                if !c.is_whitespace() {
+                    tokens.push(c.to_string());
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// `MasterReq` -> `MASTER_REQ`
+fn screaming_snake(name: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && prev_lower {
+            out.push('_');
+        }
+        prev_lower = c.is_ascii_lowercase();
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+fn ldf_number(token: &str) -> u64 {
+    match token.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).unwrap(),
+        None => token.parse().unwrap(),
+    }
+}
+
+/// Tokens between the braces of a top level LDF section.
+fn ldf_section<'a>(tokens: &'a [String], name: &str) -> &'a [String] {
+    let start = match tokens.iter().position(|t| t == name) {
+        Some(pos) if tokens[pos + 1] == "{" => pos + 2,
+        _ => return &[],
+    };
+    let mut depth = 1;
+    for (pos, token) in tokens[start..].iter().enumerate() {
+        match token.as_str() {
+            "{" => depth += 1,
+            "}" => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return &tokens[start..start + pos];
+        }
+    }
+    panic!("unterminated LDF section {}", name);
+}
+
+/// Splits a section into `header { body }` entries.
+fn ldf_blocks(tokens: &[String]) -> Vec<(&[String], &[String])> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let open = pos + tokens[pos..].iter().position(|t| t == "{").unwrap();
+        let mut depth = 0;
+        let mut close = open;
+        for (i, token) in tokens[open..].iter().enumerate() {
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                close = open + i;
+                break;
+            }
+        }
+        blocks.push((&tokens[pos..open], &tokens[open + 1..close]));
+        pos = close + 1;
+    }
+    blocks
+}
+
+/// Generates LIN frame definitions and schedule tables from the LDF.
+fn lin_codegen(ldf: &str) -> String {
+    let tokens = ldf_tokens(ldf);
+
+    let nodes = ldf_section(&tokens, "Nodes");
+    let master = &nodes[nodes.iter().position(|t| t == "Master").unwrap() + 2];
+    // Slaves : NAME , NAME ;
+    let slaves_start = nodes.iter().position(|t| t == "Slaves").unwrap() + 2;
+    let slaves: Vec<&String> = nodes[slaves_start..]
+        .iter()
+        .take_while(|t| *t != ";")
+        .filter(|t| *t != ",")
+        .collect();
+    let slave_index = |name: &String| slaves.iter().position(|s| *s == name);
+
+    // NAME : SIZE , INIT , PUBLISHER , SUBSCRIBER ... ;
+    let signals: Vec<&[String]> = ldf_section(&tokens, "Signals")
+        .split(|t| t == ";")
+        .filter(|signal| !signal.is_empty())
+        .collect();
+
+    let mut frames = Vec::new();
+    for (header, body) in ldf_blocks(ldf_section(&tokens, "Frames")) {
+        // NAME : ID , PUBLISHER , LENGTH { SIGNAL , OFFSET ; ... }
+        let publisher = header[4].clone();
+        let node = slave_index(&publisher).unwrap_or_else(|| {
+            let signal = signals.iter().find(|s| s[0] == body[0]).unwrap();
+            slave_index(&signal[8]).expect("frame without slave subscriber")
+        });
+        let len = ldf_number(&header[6]) as usize;
+        assert!(len <= 8, "LIN frame {} longer than 8 bytes", header[0]);
+        frames.push(LinFrame {
+            name: header[0].clone(),
+            id: ldf_number(&header[2]) as u8,
+            publisher,
+            len,
+            node,
+            kind: LinFrameKind::Unconditional,
+        });
+    }
+    for (header, _) in ldf_blocks(ldf_section(&tokens, "Diagnostic_frames")) {
+        // NAME : ID
+        let id = ldf_number(&header[2]) as u8;
+        frames.push(LinFrame {
+            name: header[0].clone(),
+            id,
+            publisher: if id == 0x3c {
+                master.clone()
+            } else {
+                String::new()
+            },
+            len: 8,
+            node: 0,
+            kind: LinFrameKind::Unconditional,
+        });
+    }
+
+    let frame_index = |frames: &[LinFrame], name: &String| {
+        frames
+            .iter()
+            .position(|f| f.name == *name)
+            .unwrap_or_else(|| panic!("unknown LIN frame {}", name))
+    };
+
+    // NAME : FRAME , FRAME ... ;
+    let sporadic: Vec<&[String]> = ldf_section(&tokens, "Sporadic_frames")
+        .split(|t| t == ";")
+        .filter(|frame| !frame.is_empty())
+        .collect();
+    for definition in sporadic {
+        let associated: Vec<usize> = definition[2..]
+            .iter()
+            .filter(|t| *t != ",")
+            .map(|name| frame_index(&frames, name))
+            .collect();
+        let first = &frames[associated[0]];
+        assert!(
+            associated.iter().all(|&f| frames[f].publisher == *master),
+            "sporadic frame {} with slave published frames",
+            definition[0]
+        );
+        frames.push(LinFrame {
+            name: definition[0].clone(),
+            id: first.id,
+            publisher: master.clone(),
+            len: associated.iter().map(|&f| frames[f].len).max().unwrap(),
+            node: first.node,
+            kind: LinFrameKind::Sporadic(associated),
+        });
+    }
+
+    // NAME : COLLISION_TABLE , ID , FRAME , FRAME ... ;
+    let event_triggered: Vec<&[String]> = ldf_section(&tokens, "Event_triggered_frames")
+        .split(|t| t == ";")
+        .filter(|frame| !frame.is_empty())
+        .collect();
+    for definition in event_triggered {
+        let associated: Vec<usize> = definition[6..]
+            .iter()
+            .filter(|t| *t != ",")
+            .map(|name| frame_index(&frames, name))
+            .collect();
+        let first = &frames[associated[0]];
+        assert!(
+            associated
+                .iter()
+                .all(|&f| frames[f].publisher != *master && frames[f].len == first.len),
+            "event triggered frame {} needs slave frames of equal length",
+            definition[0]
+        );
+        frames.push(LinFrame {
+            name: definition[0].clone(),
+            id: ldf_number(&definition[4]) as u8,
+            publisher: String::new(),
+            len: first.len,
+            node: first.node,
+            kind: LinFrameKind::EventTriggered(associated, definition[2].clone()),
+        });
+    }
+
+    let mut code = String::from(
+        "// Generated by build.rs from STM_LIN.ldf, do not edit\n\n\
+         use crate::lin_master::{Direction, FrameInfo, FrameKind, ScheduleTable, Slot};\n\n",
+    );
+
+    for (index, slave) in slaves.iter().enumerate() {
+        code += &format!("pub const LIN_NODE_{}: usize = {};\n", slave, index);
+    }
+    code += &format!(
+        "pub const LIN_NODE_COUNT: usize = {};\npub const LIN_NODES: [&str; LIN_NODE_COUNT] = {:?};\n\n",
+        slaves.len(),
+        slaves
+    );
+
+    for (index, frame) in frames.iter().enumerate() {
+        let name = screaming_snake(&frame.name);
+        code += &format!("pub const LIN_FRAME_{}: usize = {};\n", name, index);
+    }
+
+    code += &format!("\npub const LIN_FRAME_COUNT: usize = {};\n", frames.len());
+    code += "\npub const LIN_FRAMES: [FrameInfo; LIN_FRAME_COUNT] = [\n";
+    for frame in &frames {
+        let direction = if &frame.publisher == master {
+            "Publish"
+        } else {
+            "Subscribe"
+        };
+        let kind = match &frame.kind {
+            LinFrameKind::Unconditional => String::from("FrameKind::Unconditional"),
+            LinFrameKind::Sporadic(associated) => format!("FrameKind::Sporadic(&{:?})", associated),
+            LinFrameKind::EventTriggered(associated, table) => format!(
+                "FrameKind::EventTriggered {{\n            frames: &{:?},\n            collision: Schedule::{},\n        }}",
+                associated, table
+            ),
+        };
+        code += &format!(
+            "    FrameInfo {{\n        id: {:#04x},\n        len: {},\n        direction: Direction::{},\n        node: {},\n        kind: {},\n    }},\n",
+            frame.id, frame.len, direction, frame.node, kind
+        );
+    }
+    code += "];\n";
+
+    let tables = ldf_blocks(ldf_section(&tokens, "Schedule_tables"));
+    code += "\n#[derive(Copy, Clone, PartialEq, Eq)]\n\
+             #[cfg_attr(feature = \"defmt\", derive(defmt::Format))]\n\
+             pub enum Schedule {\n";
+    for (header, _) in &tables {
+        code += &format!("    {},\n", header[0]);
+    }
+    code += "}\n\nimpl Schedule {\n    pub fn table(self) -> &'static ScheduleTable {\n        match self {\n";
+    for (header, _) in &tables {
+        code += &format!(
+            "            Schedule::{} => &SCHEDULE_{},\n",
+            header[0],
+            screaming_snake(&header[0])
+        );
+    }
+    code += "        }\n    }\n}\n";
+
+    for (header, body) in &tables {
+        code += &format!(
+            "\npub static SCHEDULE_{}: ScheduleTable = ScheduleTable {{\n    slots: &[\n",
+            screaming_snake(&header[0])
+        );
+        // FRAME delay TIME ms ;
+        for slot in body.split(|t| t == ";").filter(|slot| !slot.is_empty()) {
+            let frame = frames
+                .iter()
+                .position(|f| f.name == slot[0])
+                .unwrap_or_else(|| panic!("unknown frame {} in schedule table", slot[0]));
+            assert_eq!(slot[1], "delay");
+            code += &format!(
+                "        Slot {{\n            frame: {},\n            delay_ms: {},\n        }},\n",
+                frame, slot[2]
+            );
+        }
+        code += "    ],\n};\n";
+    }
+
+    code
 }
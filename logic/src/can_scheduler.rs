@@ -0,0 +1,198 @@
+/// Fixed-priority cyclic transmit schedule.
+///
+/// Every message gets its own period with a phase offset so that messages
+/// with equal cycle times are not sent in one burst. Event triggered messages
+/// are sent as soon as they change, but not more often than their delay time.
+pub struct TxScheduler<const N: usize> {
+    entries: [TxEntry; N],
+}
+
+/// Timing of one message, from its DBC attributes.
+#[derive(Copy, Clone)]
+pub struct TxTiming {
+    /// 0 if the message is not sent periodically
+    pub cycle_ms: u64,
+    /// minimal gap between two event triggered transmissions
+    pub delay_ms: u64,
+    pub cyclic: bool,
+    pub on_change: bool,
+}
+
+impl TxTiming {
+    fn is_cyclic(&self) -> bool {
+        self.cyclic && self.cycle_ms > 0
+    }
+}
+
+#[derive(Copy, Clone)]
+struct TxEntry {
+    timing: TxTiming,
+    next_due_ms: u64,
+    last_sent_ms: Option<u64>,
+    pending: bool,
+}
+
+impl<const N: usize> TxScheduler<N> {
+    pub fn new(timings: [TxTiming; N], start_ms: u64, tick_ms: u64) -> Self {
+        let mut index = 0;
+        let entries = timings.map(|timing| {
+            let offset = match timing.cycle_ms {
+                0 => 0,
+                cycle => (index * tick_ms) % cycle,
+            };
+            index += 1;
+            TxEntry {
+                timing,
+                next_due_ms: start_ms + offset,
+                last_sent_ms: None,
+                pending: false,
+            }
+        });
+        Self { entries }
+    }
+
+    /// Requests an event triggered transmission if the message supports it.
+    pub fn changed(&mut self, index: usize) {
+        let entry = &mut self.entries[index];
+        if entry.timing.on_change {
+            entry.pending = true;
+        }
+    }
+
+    /// Returns a bit mask of messages to be sent at `now_ms`.
+    pub fn poll(&mut self, now_ms: u64) -> u32 {
+        let mut due = 0;
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            let mut send = false;
+
+            if entry.timing.is_cyclic() && now_ms >= entry.next_due_ms {
+                send = true;
+                entry.next_due_ms += entry.timing.cycle_ms;
+                if entry.next_due_ms <= now_ms {
+                    // we fell behind, do not try to catch up with a burst
+                    entry.next_due_ms = now_ms + entry.timing.cycle_ms;
+                }
+            }
+
+            if entry.pending {
+                let gap_elapsed = entry
+                    .last_sent_ms
+                    .map_or(true, |last| now_ms - last >= entry.timing.delay_ms);
+                send |= gap_elapsed;
+            }
+
+            if send {
+                entry.pending = false;
+                entry.last_sent_ms = Some(now_ms);
+                due |= 1 << index;
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_MS: u64 = 10;
+
+    const fn cyclic(cycle_ms: u64) -> TxTiming {
+        TxTiming {
+            cycle_ms,
+            delay_ms: 0,
+            cyclic: true,
+            on_change: false,
+        }
+    }
+
+    const fn on_change(delay_ms: u64) -> TxTiming {
+        TxTiming {
+            cycle_ms: 0,
+            delay_ms,
+            cyclic: false,
+            on_change: true,
+        }
+    }
+
+    /// Times at which message `index` is sent when polled every tick.
+    fn sent<const N: usize>(
+        scheduler: &mut TxScheduler<N>,
+        index: usize,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> Vec<u64> {
+        (from_ms..to_ms)
+            .step_by(TICK_MS as usize)
+            .filter(|&now| scheduler.poll(now) & (1 << index) != 0)
+            .collect()
+    }
+
+    #[test]
+    fn cyclic_with_phase_offset() {
+        let timings = [cyclic(50), cyclic(50), cyclic(30), cyclic(20)];
+        let mut scheduler = TxScheduler::new(timings, 1000, TICK_MS);
+        assert_eq!(scheduler.poll(1000), 0b0001);
+        // the offset of the last one wraps around its cycle
+        assert_eq!(scheduler.poll(1010), 0b1010);
+        assert_eq!(scheduler.poll(1020), 0b0100);
+        assert_eq!(scheduler.poll(1030), 0b1000);
+        assert_eq!(scheduler.poll(1040), 0);
+        assert_eq!(scheduler.poll(1050), 0b1101);
+        assert_eq!(scheduler.poll(1060), 0b0010);
+    }
+
+    #[test]
+    fn keeps_the_period() {
+        let mut scheduler = TxScheduler::new([cyclic(100)], 0, TICK_MS);
+        assert_eq!(sent(&mut scheduler, 0, 0, 500), [0, 100, 200, 300, 400]);
+    }
+
+    #[test]
+    fn late_poll_does_not_burst() {
+        let mut scheduler = TxScheduler::new([cyclic(20)], 0, TICK_MS);
+        assert_eq!(scheduler.poll(0), 1);
+        // one late poll sends once and restarts the period from there
+        assert_eq!(scheduler.poll(75), 1);
+        assert_eq!(scheduler.poll(85), 0);
+        assert_eq!(scheduler.poll(95), 1);
+    }
+
+    #[test]
+    fn change_is_sent_after_delay() {
+        let mut scheduler = TxScheduler::new([on_change(50)], 0, TICK_MS);
+        assert_eq!(sent(&mut scheduler, 0, 0, 100), []);
+
+        scheduler.changed(0);
+        assert_eq!(scheduler.poll(100), 1);
+        // the second change waits for the gap, changes meanwhile are merged
+        scheduler.changed(0);
+        assert_eq!(scheduler.poll(110), 0);
+        scheduler.changed(0);
+        assert_eq!(sent(&mut scheduler, 0, 120, 300), [150]);
+    }
+
+    #[test]
+    fn send_types() {
+        let cyclic_and_on_change = TxTiming {
+            on_change: true,
+            ..cyclic(100)
+        };
+        let mut scheduler = TxScheduler::new(
+            [cyclic(100), cyclic_and_on_change, on_change(0)],
+            0,
+            TICK_MS,
+        );
+        assert_eq!(scheduler.poll(0), 0b001);
+        assert_eq!(scheduler.poll(10), 0b010);
+
+        // changes of a cyclic only message are ignored
+        for index in 0..3 {
+            scheduler.changed(index);
+        }
+        assert_eq!(scheduler.poll(20), 0b110);
+        assert_eq!(scheduler.poll(30), 0);
+        assert_eq!(scheduler.poll(100), 0b001);
+        assert_eq!(scheduler.poll(110), 0b010);
+    }
+}
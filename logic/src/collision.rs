@@ -0,0 +1,301 @@
+use crate::ultrasound::UltrasoundResult;
+
+/// Nearest obstacle seen by the sensors facing one direction.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub enum Obstacle {
+    /// no echo, nothing in sensor range
+    Clear,
+    /// sensors not responding or results too old
+    Unknown,
+    /// some sensors not responding, the others see an obstacle in mm
+    Degraded(u64),
+    /// distance in mm
+    At(u64),
+}
+
+impl Obstacle {
+    /// A sensor that does not respond could be hiding a closer obstacle, so
+    /// any timeout makes the result at best `Degraded`.
+    pub fn nearest(results: &[UltrasoundResult]) -> Self {
+        let timeout = results
+            .iter()
+            .any(|result| matches!(result, UltrasoundResult::Timeout));
+        let nearest = results
+            .iter()
+            .filter_map(|result| match result {
+                UltrasoundResult::Measurement(mm) => Some(*mm),
+                _ => None,
+            })
+            .min();
+
+        match (nearest, timeout) {
+            (Some(mm), false) => Obstacle::At(mm),
+            (Some(mm), true) => Obstacle::Degraded(mm),
+            (None, true) => Obstacle::Unknown,
+            (None, false) => Obstacle::Clear,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BrakeAssistConfig {
+    /// obstacle closer than this blocks driving towards it
+    pub stop_distance_mm: u64,
+    /// blocked direction is released once the obstacle is further than this
+    pub release_distance_mm: u64,
+    /// time to collision that blocks driving towards the obstacle
+    pub brake_ttc_s: f32,
+    /// time to collision that releases the block
+    pub release_ttc_s: f32,
+    /// speed limit towards sensors that are not working
+    pub unknown_limit_kmh: f32,
+}
+
+pub const DEFAULT_BRAKE_ASSIST: BrakeAssistConfig = BrakeAssistConfig {
+    stop_distance_mm: 250,
+    release_distance_mm: 350,
+    brake_ttc_s: 0.6,
+    release_ttc_s: 1.0,
+    unknown_limit_kmh: 2.0,
+};
+
+/// Allowed speed range, both limits are positive magnitudes in km/h.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub struct DriveLimit {
+    pub max_forward_kmh: f32,
+    pub max_reverse_kmh: f32,
+}
+
+impl DriveLimit {
+    pub const NONE: DriveLimit = DriveLimit {
+        max_forward_kmh: f32::INFINITY,
+        max_reverse_kmh: f32::INFINITY,
+    };
+
+    pub fn apply(&self, speed_kmh: f32) -> f32 {
+        speed_kmh.clamp(-self.max_reverse_kmh, self.max_forward_kmh)
+    }
+}
+
+/// Hysteresis state for one driving direction.
+#[derive(Copy, Clone, Default)]
+struct DirectionGuard {
+    blocked: bool,
+}
+
+impl DirectionGuard {
+    /// Returns the allowed speed towards the obstacle, `approach_kmh` is
+    /// positive when moving towards it.
+    fn update(&mut self, config: &BrakeAssistConfig, obstacle: Obstacle, approach_kmh: f32) -> f32 {
+        let mm = match obstacle {
+            Obstacle::Clear => {
+                self.blocked = false;
+                return f32::INFINITY;
+            }
+            Obstacle::Unknown => {
+                self.blocked = false;
+                return config.unknown_limit_kmh;
+            }
+            Obstacle::Degraded(mm) => {
+                let limit = self.update(config, Obstacle::At(mm), approach_kmh);
+                return limit.min(config.unknown_limit_kmh);
+            }
+            Obstacle::At(mm) => mm,
+        };
+
+        let distance_m = mm as f32 / 1000.0;
+        let ttc_s = match approach_kmh {
+            v if v > 0.0 => distance_m / (v / 3.6),
+            _ => f32::INFINITY,
+        };
+
+        if self.blocked {
+            self.blocked = mm < config.release_distance_mm || ttc_s < config.release_ttc_s;
+        } else {
+            self.blocked = mm < config.stop_distance_mm || ttc_s < config.brake_ttc_s;
+        }
+
+        if self.blocked {
+            0.0
+        } else {
+            // the speed that still leaves release_ttc_s to the remaining gap
+            let gap_m = distance_m - config.stop_distance_mm as f32 / 1000.0;
+            (gap_m / config.release_ttc_s * 3.6).max(0.0)
+        }
+    }
+}
+
+/// Emergency braking assistant limiting drive commands near obstacles.
+pub struct BrakeAssist {
+    config: BrakeAssistConfig,
+    forward: DirectionGuard,
+    reverse: DirectionGuard,
+}
+
+impl BrakeAssist {
+    pub fn new(config: BrakeAssistConfig) -> Self {
+        Self {
+            config,
+            forward: DirectionGuard::default(),
+            reverse: DirectionGuard::default(),
+        }
+    }
+
+    /// Takes effect with the next update, the blocked directions are kept.
+    pub fn set_config(&mut self, config: BrakeAssistConfig) {
+        self.config = config;
+    }
+
+    /// Computes the allowed speed range for the current obstacles and the
+    /// signed vehicle speed, positive when driving forward.
+    pub fn update(&mut self, front: Obstacle, rear: Obstacle, speed_kmh: f32) -> DriveLimit {
+        DriveLimit {
+            max_forward_kmh: self.forward.update(&self.config, front, speed_kmh),
+            max_reverse_kmh: self.reverse.update(&self.config, rear, -speed_kmh),
+        }
+    }
+
+    /// Set while any direction is fully blocked.
+    pub fn is_braking(&self) -> bool {
+        self.forward.blocked || self.reverse.blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use UltrasoundResult::{Measurement, NoEcho, NotFitted, Timeout};
+
+    #[test]
+    fn nearest_measurement_wins() {
+        assert_eq!(
+            Obstacle::nearest(&[Measurement(800), NoEcho, Measurement(500)]),
+            Obstacle::At(500)
+        );
+        assert_eq!(
+            Obstacle::nearest(&[NoEcho, NotFitted, NoEcho]),
+            Obstacle::Clear
+        );
+        assert_eq!(Obstacle::nearest(&[NotFitted; 3]), Obstacle::Clear);
+    }
+
+    #[test]
+    fn timeout_is_never_clear() {
+        assert_eq!(
+            Obstacle::nearest(&[NoEcho, Timeout, NoEcho]),
+            Obstacle::Unknown
+        );
+        assert_eq!(
+            Obstacle::nearest(&[Measurement(2000), Timeout, NoEcho]),
+            Obstacle::Degraded(2000)
+        );
+    }
+
+    #[test]
+    fn clear_does_not_limit() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        let limit = assist.update(Obstacle::Clear, Obstacle::Clear, 10.0);
+        assert_eq!(limit, DriveLimit::NONE);
+        assert!(!assist.is_braking());
+    }
+
+    #[test]
+    fn unknown_applies_the_unknown_limit() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        let limit = assist.update(Obstacle::Unknown, Obstacle::Clear, 0.0);
+        assert_eq!(
+            limit.max_forward_kmh,
+            DEFAULT_BRAKE_ASSIST.unknown_limit_kmh
+        );
+        assert_eq!(limit.max_reverse_kmh, f32::INFINITY);
+    }
+
+    #[test]
+    fn degraded_applies_the_unknown_limit() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        let limit = assist.update(Obstacle::Degraded(5000), Obstacle::Clear, 0.0);
+        assert_eq!(
+            limit.max_forward_kmh,
+            DEFAULT_BRAKE_ASSIST.unknown_limit_kmh
+        );
+    }
+
+    #[test]
+    fn degraded_still_blocks_close_obstacles() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        let limit = assist.update(Obstacle::Degraded(200), Obstacle::Clear, 0.0);
+        assert_eq!(limit.max_forward_kmh, 0.0);
+        assert!(assist.is_braking());
+    }
+
+    #[test]
+    fn stop_distance_blocks_with_hysteresis() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        assert!(
+            assist
+                .update(Obstacle::At(300), Obstacle::Clear, 0.0)
+                .max_forward_kmh
+                > 0.0
+        );
+
+        assert_eq!(
+            assist
+                .update(Obstacle::At(240), Obstacle::Clear, 0.0)
+                .max_forward_kmh,
+            0.0
+        );
+        assert!(assist.is_braking());
+
+        // still blocked between the stop and the release distance
+        assert_eq!(
+            assist
+                .update(Obstacle::At(300), Obstacle::Clear, 0.0)
+                .max_forward_kmh,
+            0.0
+        );
+        assert!(
+            assist
+                .update(Obstacle::At(400), Obstacle::Clear, 0.0)
+                .max_forward_kmh
+                > 0.0
+        );
+        assert!(!assist.is_braking());
+    }
+
+    #[test]
+    fn time_to_collision_blocks() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        // 2 m at 15 km/h is less than 0.6 s away
+        let limit = assist.update(Obstacle::At(2000), Obstacle::Clear, 15.0);
+        assert_eq!(limit.max_forward_kmh, 0.0);
+
+        // released once the time to collision exceeds 1 s
+        let limit = assist.update(Obstacle::At(2000), Obstacle::Clear, 5.0);
+        assert!(limit.max_forward_kmh > 0.0);
+    }
+
+    #[test]
+    fn speed_limited_by_remaining_gap() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        // 0.75 m left to the stop distance within 1 s
+        let limit = assist.update(Obstacle::At(1000), Obstacle::Clear, 0.0);
+        assert!((limit.max_forward_kmh - 2.7).abs() < 1e-4);
+    }
+
+    #[test]
+    fn directions_are_independent() {
+        let mut assist = BrakeAssist::new(DEFAULT_BRAKE_ASSIST);
+        // reversing towards a close rear obstacle, driving away from the front one
+        let limit = assist.update(Obstacle::At(2000), Obstacle::At(2000), -15.0);
+        assert!(limit.max_forward_kmh > 0.0);
+        assert_eq!(limit.max_reverse_kmh, 0.0);
+        assert_eq!(limit.apply(-5.0), 0.0);
+        assert_eq!(limit.apply(1.0), 1.0);
+    }
+}
@@ -0,0 +1,262 @@
+pub type Color = (u8, u8, u8);
+
+/// Hue steps of [`Hsv`], 256 per sector of the color wheel.
+pub const HUE_STEPS: i32 = 6 * 256;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hsv {
+    /// 0..HUE_STEPS, red at 0, green at 512, blue at 1024
+    pub hue: u16,
+    pub saturation: u8,
+    pub value: u8,
+}
+
+impl Hsv {
+    pub fn from_rgb((r, g, b): Color) -> Self {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        if delta == 0 {
+            return Self {
+                hue: 0,
+                saturation: 0,
+                value: max as u8,
+            };
+        }
+
+        let hue = if max == r {
+            256 * (g - b) / delta
+        } else if max == g {
+            512 + 256 * (b - r) / delta
+        } else {
+            1024 + 256 * (r - g) / delta
+        };
+        Self {
+            hue: hue.rem_euclid(HUE_STEPS) as u16,
+            saturation: (255 * delta / max) as u8,
+            value: max as u8,
+        }
+    }
+
+    pub fn to_rgb(self) -> Color {
+        let (s, v) = (self.saturation as u32, self.value as u32);
+        let f = (self.hue % 256) as u32;
+        let p = (v * (255 - s) / 255) as u8;
+        let q = (v * (255 - s * f / 255) / 255) as u8;
+        let t = (v * (255 - s * (255 - f) / 255) / 255) as u8;
+        let v = v as u8;
+        match self.hue / 256 {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    /// straight line in RGB, mixing passes through desaturated colors
+    Rgb,
+    /// along the shorter arc of the color wheel, keeps colors saturated
+    Hsv,
+}
+
+/// Cycles through a list of colors, blending from one to the next over
+/// `step_ms`.
+pub struct ColorTransition<'a> {
+    colors: &'a [Color],
+    step_ms: u32,
+    interpolation: Interpolation,
+}
+
+impl<'a> ColorTransition<'a> {
+    pub fn new(colors: &'a [Color], step_ms: u32, interpolation: Interpolation) -> Self {
+        Self {
+            colors,
+            step_ms: step_ms.max(1),
+            interpolation,
+        }
+    }
+
+    /// Color `elapsed_ms` after the start of the cycle.
+    pub fn at(&self, elapsed_ms: u64) -> Color {
+        let step_ms = self.step_ms as u64;
+        let position = elapsed_ms % (step_ms * self.colors.len() as u64);
+        let index = (position / step_ms) as usize;
+        let start = self.colors[index];
+        let end = self.colors[(index + 1) % self.colors.len()];
+        let fraction = (position % step_ms) as i32;
+
+        match self.interpolation {
+            Interpolation::Rgb => interpolate_color(start, end, fraction, step_ms as i32),
+            Interpolation::Hsv => interpolate_hsv(start, end, fraction, step_ms as i32),
+        }
+    }
+}
+
+fn interpolate(start: u8, end: u8, step: i32, total_steps: i32) -> i32 {
+    start as i32 + (end as i32 - start as i32) * step / total_steps
+}
+
+fn interpolate_color(start: Color, end: Color, step: i32, total_steps: i32) -> Color {
+    let (r1, g1, b1) = start;
+    let (r2, g2, b2) = end;
+
+    let r = interpolate(r1, r2, step, total_steps);
+    let g = interpolate(g1, g2, step, total_steps);
+    let b = interpolate(b1, b2, step, total_steps);
+
+    (r as u8, g as u8, b as u8)
+}
+
+fn interpolate_hsv(start: Color, end: Color, step: i32, total_steps: i32) -> Color {
+    let mut start = Hsv::from_rgb(start);
+    let mut end = Hsv::from_rgb(end);
+    // grey has no hue, fade the saturation instead of sweeping the wheel
+    if start.saturation == 0 {
+        start.hue = end.hue;
+    }
+    if end.saturation == 0 {
+        end.hue = start.hue;
+    }
+
+    let mut hue_delta = end.hue as i32 - start.hue as i32;
+    if hue_delta > HUE_STEPS / 2 {
+        hue_delta -= HUE_STEPS;
+    } else if hue_delta < -HUE_STEPS / 2 {
+        hue_delta += HUE_STEPS;
+    }
+    let hue = start.hue as i32 + hue_delta * step / total_steps;
+
+    Hsv {
+        hue: hue.rem_euclid(HUE_STEPS) as u16,
+        saturation: interpolate(start.saturation, end.saturation, step, total_steps) as u8,
+        value: interpolate(start.value, end.value, step, total_steps) as u8,
+    }
+    .to_rgb()
+}
+
+const fn gamma_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        // x^2.2 approximated by 0.8 x^2 + 0.2 x^3, within 1 % of full scale
+        let x = i as u32;
+        let divisor = 5 * 255 * 255;
+        table[i] = ((4 * 255 * x * x + x * x * x + divisor / 2) / divisor) as u8;
+        i += 1;
+    }
+    table
+}
+
+const GAMMA: [u8; 256] = gamma_table();
+
+/// Maps perceived brightness to PWM duty, the LED is far brighter at low
+/// duty than a linear scale suggests.
+pub fn gamma((r, g, b): Color) -> Color {
+    (GAMMA[r as usize], GAMMA[g as usize], GAMMA[b as usize])
+}
+
+/// Scales a color by `level` out of 255.
+pub fn dim((r, g, b): Color, level: u8) -> Color {
+    let scale = |c: u8| (c as u32 * level as u32 / 255) as u8;
+    (scale(r), scale(g), scale(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = (255, 0, 0);
+    const GREEN: Color = (0, 255, 0);
+    const BLUE: Color = (0, 0, 255);
+
+    #[test]
+    fn gamma_table_follows_power_curve() {
+        assert_eq!(GAMMA[0], 0);
+        assert_eq!(GAMMA[255], 255);
+        for (i, &duty) in GAMMA.iter().enumerate() {
+            let exact = 255.0 * (i as f32 / 255.0).powf(2.2);
+            assert!((duty as f32 - exact).abs() <= 3.0, "{i}: {duty} vs {exact}");
+        }
+        assert!(GAMMA.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn hsv_of_primaries() {
+        assert_eq!(Hsv::from_rgb(RED).hue, 0);
+        assert_eq!(Hsv::from_rgb(GREEN).hue, 512);
+        assert_eq!(Hsv::from_rgb(BLUE).hue, 1024);
+        let grey = Hsv::from_rgb((80, 80, 80));
+        assert_eq!((grey.saturation, grey.value), (0, 80));
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for color in [
+            RED,
+            GREEN,
+            BLUE,
+            (255, 120, 0),
+            (12, 200, 90),
+            (80, 80, 80),
+            (0, 0, 0),
+        ] {
+            let (r, g, b) = Hsv::from_rgb(color).to_rgb();
+            let close = |a: u8, b: u8| a.abs_diff(b) <= 2;
+            assert!(
+                close(r, color.0) && close(g, color.1) && close(b, color.2),
+                "{color:?} became {:?}",
+                (r, g, b)
+            );
+        }
+    }
+
+    #[test]
+    fn hsv_interpolation_takes_the_shorter_arc() {
+        // hues just below and above red, the midpoint must not pass green or blue
+        let start = (255, 0, 64);
+        let end = (255, 64, 0);
+        assert_eq!(interpolate_hsv(start, end, 1, 2), RED);
+        assert_eq!(interpolate_hsv(end, start, 1, 2), RED);
+        assert_eq!(
+            interpolate_hsv(start, end, 0, 2),
+            Hsv::from_rgb(start).to_rgb()
+        );
+    }
+
+    #[test]
+    fn hsv_interpolation_from_grey_keeps_hue() {
+        let (r, g, b) = interpolate_hsv((128, 128, 128), BLUE, 1, 2);
+        assert!(b > r && b > g);
+        assert_eq!(r, g);
+    }
+
+    #[test]
+    fn rgb_interpolation() {
+        assert_eq!(interpolate_color(RED, BLUE, 1, 2), (128, 0, 127));
+        assert_eq!(interpolate_color(RED, BLUE, 2, 2), BLUE);
+    }
+
+    #[test]
+    fn transition_cycles_through_colors() {
+        let colors = [RED, GREEN, BLUE];
+        let transition = ColorTransition::new(&colors, 100, Interpolation::Rgb);
+        assert_eq!(transition.at(0), RED);
+        assert_eq!(transition.at(100), GREEN);
+        assert_eq!(transition.at(200), BLUE);
+        assert_eq!(transition.at(300), RED);
+        assert_eq!(transition.at(250), interpolate_color(BLUE, RED, 50, 100));
+    }
+
+    #[test]
+    fn dim_scales_every_channel() {
+        assert_eq!(dim((255, 128, 0), 255), (255, 128, 0));
+        assert_eq!(dim((255, 128, 0), 0), (0, 0, 0));
+        assert_eq!(dim((255, 128, 0), 128), (128, 64, 0));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    filter::{FilterConfig, DEFAULT_FILTER},
+    motor::{MotorConfig, DEFAULT_MOTOR},
+    steering::{SteeringLimits, DEFAULT_STEERING},
+    steering_cal::{CalibrationRecord, DEFAULT_CALIBRATION},
+    ultrasound::{FiringSchedule, ULTRASOUND_CHANNELS},
+};
+
+/// Reported position without a sensor.
+pub const ULTRASOUND_UNMAPPED: u8 = 0xff;
+
+/// Tunables read by the tasks at startup.
+#[derive(Copy, Clone)]
+pub struct Config {
+    pub ticks_per_cm: f32,
+    pub kl15_r1_ohm: u32,
+    pub kl15_r2_ohm: u32,
+    pub steering: CalibrationRecord,
+    /// `ULTRASOUNDS` position i reports sensor channel `ultrasound_map[i]`
+    pub ultrasound_map: [u8; ULTRASOUND_CHANNELS],
+    pub ultrasound_schedule: FiringSchedule,
+    pub can_bitrate: u32,
+    pub can_data_bitrate: u32,
+    pub motor: MotorConfig,
+    pub steering_limits: SteeringLimits,
+    pub filter: FilterConfig,
+}
+
+/// Compiled-in values, used for every key missing from or invalid in flash.
+pub const DEFAULT_CONFIG: Config = Config {
+    ticks_per_cm: 61.5,
+    kl15_r1_ohm: 4700,
+    kl15_r2_ohm: 1500,
+    steering: DEFAULT_CALIBRATION,
+    ultrasound_map: [0, 1, 2, 3, 4, 5],
+    ultrasound_schedule: FiringSchedule::CrosstalkAvoidance,
+    can_bitrate: 500_000,
+    can_data_bitrate: 1_000_000,
+    motor: DEFAULT_MOTOR,
+    steering_limits: DEFAULT_STEERING,
+    filter: DEFAULT_FILTER,
+};
+
+impl Config {
+    /// Checks the relations between parameters, each of them is in range.
+    pub fn is_consistent(&self) -> bool {
+        let brake = &self.motor.brake_assist;
+        let steering = &self.steering_limits;
+        brake.release_distance_mm >= brake.stop_distance_mm
+            && brake.release_ttc_s >= brake.brake_ttc_s
+            && steering.high_speed_kmh > steering.full_angle_below_kmh
+    }
+}
+
+pub fn valid_ultrasound_map(map: &[u8]) -> bool {
+    map.iter()
+        .all(|&ch| ch == ULTRASOUND_UNMAPPED || (ch as usize) < ULTRASOUND_CHANNELS)
+}
+
+const NOMINAL_BITRATES: [u32; 4] = [125_000, 250_000, 500_000, 1_000_000];
+// the 170 MHz FDCAN clock divides into whole time quanta at these
+const DATA_BITRATES: [u32; 3] = [1_000_000, 2_000_000, 5_000_000];
+
+/// Bitrates the bus runs at, the data phase is either off, at the nominal
+/// bitrate, or faster.
+pub fn valid_bitrates(bitrate: u32, data_bitrate: u32) -> bool {
+    NOMINAL_BITRATES.contains(&bitrate)
+        && (data_bitrate == bitrate
+            || (DATA_BITRATES.contains(&data_bitrate) && data_bitrate > bitrate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(DEFAULT_CONFIG.is_consistent());
+        assert!(valid_ultrasound_map(&DEFAULT_CONFIG.ultrasound_map));
+        assert!(valid_bitrates(
+            DEFAULT_CONFIG.can_bitrate,
+            DEFAULT_CONFIG.can_data_bitrate
+        ));
+    }
+
+    #[test]
+    fn bitrate_whitelist() {
+        for bitrate in NOMINAL_BITRATES {
+            assert!(valid_bitrates(bitrate, bitrate));
+        }
+        assert!(valid_bitrates(125_000, 1_000_000));
+        assert!(valid_bitrates(500_000, 2_000_000));
+        assert!(valid_bitrates(1_000_000, 5_000_000));
+
+        // nominal bitrates the bus does not use
+        assert!(!valid_bitrates(0, 1_000_000));
+        assert!(!valid_bitrates(100_000, 1_000_000));
+        assert!(!valid_bitrates(333_333, 1_000_000));
+        assert!(!valid_bitrates(2_000_000, 2_000_000));
+        // data bitrates the clock does not divide or slower than nominal
+        assert!(!valid_bitrates(500_000, 4_000_000));
+        assert!(!valid_bitrates(500_000, 8_000_000));
+        assert!(!valid_bitrates(500_000, 1_500_000));
+        assert!(!valid_bitrates(500_000, 250_000));
+        assert!(!valid_bitrates(500_000, 0));
+    }
+
+    #[test]
+    fn ultrasound_map() {
+        assert!(valid_ultrasound_map(&[
+            5,
+            ULTRASOUND_UNMAPPED,
+            0,
+            0,
+            ULTRASOUND_UNMAPPED,
+            1
+        ]));
+        assert!(!valid_ultrasound_map(&[0, 1, 2, 3, 4, 6]));
+        assert!(!valid_ultrasound_map(&[0xfe, 1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn consistency() {
+        let mut config = DEFAULT_CONFIG;
+        config.motor.brake_assist.release_distance_mm = config.motor.brake_assist.stop_distance_mm;
+        assert!(config.is_consistent());
+        config.motor.brake_assist.release_distance_mm -= 1;
+        assert!(!config.is_consistent());
+
+        let mut config = DEFAULT_CONFIG;
+        config.motor.brake_assist.release_ttc_s = config.motor.brake_assist.brake_ttc_s - 0.1;
+        assert!(!config.is_consistent());
+
+        let mut config = DEFAULT_CONFIG;
+        config.steering_limits.high_speed_kmh = config.steering_limits.full_angle_below_kmh;
+        assert!(!config.is_consistent());
+    }
+}
@@ -0,0 +1,610 @@
+pub const PAGE_SIZE: u32 = 2048;
+/// Pages written round robin, each compaction moves to the next one.
+pub const PAGE_COUNT: u32 = 4;
+const SLOT_SIZE: usize = 16;
+const SLOTS: u32 = PAGE_SIZE / SLOT_SIZE as u32;
+
+pub const MAX_KEYS: usize = 32;
+pub const MAX_VALUE_LEN: usize = 8;
+
+const PAGE_MAGIC: u32 = 0x3147_4643;
+const KIND_VALUE: u8 = 1;
+const KIND_COMMIT: u8 = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StoreError {
+    Flash,
+    /// more keys than `MAX_KEYS`
+    Full,
+    ValueTooLong,
+}
+
+/// Flash region holding `PAGE_COUNT` pages of `PAGE_SIZE`, offsets are
+/// relative to the start of the region.
+pub trait Storage {
+    fn read(&mut self, offset: u32, data: &mut [u8]) -> Result<(), StoreError>;
+    /// `offset` and the length are multiples of 8 and the target is erased.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StoreError>;
+    /// Sets the page to all 0xff.
+    fn erase_page(&mut self, page: u32) -> Result<(), StoreError>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Entry {
+    key: u16,
+    len: u8,
+    value: [u8; MAX_VALUE_LEN],
+}
+
+impl Entry {
+    fn new(key: u16, value: &[u8]) -> Result<Self, StoreError> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(StoreError::ValueTooLong);
+        }
+        let mut data = [0; MAX_VALUE_LEN];
+        data[..value.len()].copy_from_slice(value);
+        Ok(Self {
+            key,
+            len: value.len() as u8,
+            value: data,
+        })
+    }
+}
+
+enum Slot {
+    Erased,
+    Value(Entry),
+    /// closes a transaction of the given number of values
+    Commit(u16),
+    /// torn write or bit errors
+    Corrupt,
+}
+
+// slot layout: kind u8, len u8, key u16, value [u8; 8], reserved u16, crc u16
+fn encode_slot(kind: u8, entry: &Entry) -> [u8; SLOT_SIZE] {
+    let mut data = [0; SLOT_SIZE];
+    data[0] = kind;
+    data[1] = entry.len;
+    data[2..4].copy_from_slice(&entry.key.to_le_bytes());
+    data[4..12].copy_from_slice(&entry.value);
+    let crc = crc16(&data[..14]);
+    data[14..16].copy_from_slice(&crc.to_le_bytes());
+    data
+}
+
+fn decode_slot(data: &[u8; SLOT_SIZE]) -> Slot {
+    if data.iter().all(|&b| b == 0xff) {
+        return Slot::Erased;
+    }
+    if u16::from_le_bytes([data[14], data[15]]) != crc16(&data[..14]) {
+        return Slot::Corrupt;
+    }
+    let key = u16::from_le_bytes([data[2], data[3]]);
+    match data[0] {
+        KIND_VALUE if data[1] as usize <= MAX_VALUE_LEN => {
+            let mut value = [0; MAX_VALUE_LEN];
+            value.copy_from_slice(&data[4..12]);
+            Slot::Value(Entry {
+                key,
+                len: data[1],
+                value,
+            })
+        }
+        KIND_COMMIT => Slot::Commit(key),
+        _ => Slot::Corrupt,
+    }
+}
+
+// header layout: magic u32, sequence u32, reserved, crc u16
+fn encode_header(sequence: u32) -> [u8; SLOT_SIZE] {
+    let mut data = [0; SLOT_SIZE];
+    data[0..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+    data[4..8].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc16(&data[..14]);
+    data[14..16].copy_from_slice(&crc.to_le_bytes());
+    data
+}
+
+fn decode_header(data: &[u8; SLOT_SIZE]) -> Option<u32> {
+    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let crc = u16::from_le_bytes([data[14], data[15]]);
+    (magic == PAGE_MAGIC && crc == crc16(&data[..14]))
+        .then(|| u32::from_le_bytes([data[4], data[5], data[6], data[7]]))
+}
+
+/// Key/value store for configuration in internal flash.
+///
+/// Values are appended as records of one flash slot each, a change of
+/// several keys is closed by a commit record and only takes effect once the
+/// commit is written, so a reset in the middle of a write leaves the previous
+/// values in place. When the page is full the current values are copied to
+/// the next page, which only becomes valid when its header with a higher
+/// sequence number is written last.
+pub struct ConfigStore<S: Storage> {
+    storage: S,
+    /// page holding the newest values and its sequence number
+    active: Option<(u32, u32)>,
+    next_slot: u32,
+    entries: [Option<Entry>; MAX_KEYS],
+}
+
+impl<S: Storage> ConfigStore<S> {
+    pub fn mount(mut storage: S) -> Result<Self, StoreError> {
+        let mut active: Option<(u32, u32)> = None;
+        for page in 0..PAGE_COUNT {
+            let mut header = [0; SLOT_SIZE];
+            storage.read(page * PAGE_SIZE, &mut header)?;
+            if let Some(sequence) = decode_header(&header) {
+                if active.map_or(true, |(_, newest)| sequence > newest) {
+                    active = Some((page, sequence));
+                }
+            }
+        }
+
+        let mut store = Self {
+            storage,
+            active,
+            next_slot: SLOTS,
+            entries: [None; MAX_KEYS],
+        };
+        if let Some((page, _)) = active {
+            store.scan(page)?;
+        }
+        Ok(store)
+    }
+
+    fn scan(&mut self, page: u32) -> Result<(), StoreError> {
+        let mut pending = [None; MAX_KEYS];
+        let mut pending_len = 0;
+
+        for slot in 1..SLOTS {
+            let mut data = [0; SLOT_SIZE];
+            self.storage.read(slot_offset(page, slot), &mut data)?;
+            match decode_slot(&data) {
+                Slot::Erased => {
+                    self.next_slot = slot;
+                    break;
+                }
+                Slot::Value(entry) => {
+                    if pending_len == MAX_KEYS {
+                        // leftovers of torn transactions, keep the newest
+                        pending.copy_within(1.., 0);
+                        pending_len -= 1;
+                    }
+                    pending[pending_len] = Some(entry);
+                    pending_len += 1;
+                }
+                // a commit covers the values right before it
+                Slot::Commit(count) if count as usize <= pending_len => {
+                    for entry in pending[pending_len - count as usize..pending_len]
+                        .iter()
+                        .flatten()
+                    {
+                        self.entries = merge(&self.entries, entry)?;
+                    }
+                    pending_len = 0;
+                }
+                Slot::Commit(_) | Slot::Corrupt => pending_len = 0,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: u16) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.key == key)
+            .map(|entry| &entry.value[..entry.len as usize])
+    }
+
+    /// Writes all changes in one transaction, either all or none of them
+    /// survive a reset.
+    pub fn commit(&mut self, changes: &[(u16, &[u8])]) -> Result<(), StoreError> {
+        let mut merged = self.entries;
+        let mut written = [None; MAX_KEYS];
+        for (i, &(key, value)) in changes.iter().enumerate() {
+            let entry = Entry::new(key, value)?;
+            merged = merge(&merged, &entry)?;
+            *written.get_mut(i).ok_or(StoreError::Full)? = Some(entry);
+        }
+
+        let needed = changes.len() as u32 + 1;
+        match self.active {
+            Some((page, _)) if self.next_slot + needed <= SLOTS => {
+                let mut slot = self.next_slot;
+                // a failed write leaves slots that are neither erased nor
+                // valid, the next commit compacts to a fresh page instead
+                self.next_slot = SLOTS;
+                for entry in written.iter().flatten() {
+                    self.write_slot(page, slot, &encode_slot(KIND_VALUE, entry))?;
+                    slot += 1;
+                }
+                self.write_commit(page, slot, changes.len() as u16)?;
+                self.next_slot = slot + 1;
+            }
+            _ => self.compact(&merged)?,
+        }
+        self.entries = merged;
+        Ok(())
+    }
+
+    fn compact(&mut self, entries: &[Option<Entry>; MAX_KEYS]) -> Result<(), StoreError> {
+        let (page, sequence) = match self.active {
+            Some((page, sequence)) => ((page + 1) % PAGE_COUNT, sequence + 1),
+            None => (0, 1),
+        };
+        self.storage.erase_page(page)?;
+
+        let mut slot = 1;
+        for entry in entries.iter().flatten() {
+            self.write_slot(page, slot, &encode_slot(KIND_VALUE, entry))?;
+            slot += 1;
+        }
+        self.write_commit(page, slot, (slot - 1) as u16)?;
+        self.write_slot(page, 0, &encode_header(sequence))?;
+
+        self.active = Some((page, sequence));
+        self.next_slot = slot + 1;
+        Ok(())
+    }
+
+    fn write_commit(&mut self, page: u32, slot: u32, count: u16) -> Result<(), StoreError> {
+        let commit = Entry {
+            key: count,
+            len: 0,
+            value: [0; MAX_VALUE_LEN],
+        };
+        self.write_slot(page, slot, &encode_slot(KIND_COMMIT, &commit))
+    }
+
+    fn write_slot(
+        &mut self,
+        page: u32,
+        slot: u32,
+        data: &[u8; SLOT_SIZE],
+    ) -> Result<(), StoreError> {
+        self.storage.write(slot_offset(page, slot), data)
+    }
+}
+
+fn slot_offset(page: u32, slot: u32) -> u32 {
+    page * PAGE_SIZE + slot * SLOT_SIZE as u32
+}
+
+fn merge(
+    entries: &[Option<Entry>; MAX_KEYS],
+    entry: &Entry,
+) -> Result<[Option<Entry>; MAX_KEYS], StoreError> {
+    let mut merged = *entries;
+    let index = merged
+        .iter()
+        .position(|e| e.is_some_and(|e| e.key == entry.key))
+        .or_else(|| merged.iter().position(Option::is_none))
+        .ok_or(StoreError::Full)?;
+    merged[index] = Some(*entry);
+    Ok(merged)
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    const REGION_SIZE: usize = (PAGE_COUNT * PAGE_SIZE) as usize;
+
+    /// Flash region in RAM, clones share the content so a store can be
+    /// mounted again after a simulated reset.
+    #[derive(Clone)]
+    struct RamStorage {
+        data: Rc<RefCell<Vec<u8>>>,
+        /// writes left before the power fails, the failing write is torn
+        writes_left: Rc<RefCell<Option<usize>>>,
+    }
+
+    impl RamStorage {
+        fn new() -> Self {
+            Self {
+                data: Rc::new(RefCell::new(vec![0xff; REGION_SIZE])),
+                writes_left: Rc::new(RefCell::new(None)),
+            }
+        }
+
+        fn fail_after(&self, writes: usize) {
+            *self.writes_left.borrow_mut() = Some(writes);
+        }
+
+        fn slot(&self, page: u32, slot: u32) -> [u8; SLOT_SIZE] {
+            let offset = slot_offset(page, slot) as usize;
+            self.data.borrow()[offset..offset + SLOT_SIZE]
+                .try_into()
+                .unwrap()
+        }
+
+        fn corrupt(&self, page: u32, slot: u32) {
+            self.data.borrow_mut()[slot_offset(page, slot) as usize + 5] ^= 0x40;
+        }
+    }
+
+    impl Storage for RamStorage {
+        fn read(&mut self, offset: u32, data: &mut [u8]) -> Result<(), StoreError> {
+            let offset = offset as usize;
+            data.copy_from_slice(&self.data.borrow()[offset..offset + data.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StoreError> {
+            assert!(offset % 8 == 0 && data.len() % 8 == 0);
+            let offset = offset as usize;
+            let mut flash = self.data.borrow_mut();
+            let target = &mut flash[offset..offset + data.len()];
+            assert!(target.iter().all(|&b| b == 0xff), "not erased");
+
+            let mut writes_left = self.writes_left.borrow_mut();
+            match *writes_left {
+                Some(0) => {
+                    // only the first double word made it
+                    target[..8].copy_from_slice(&data[..8]);
+                    return Err(StoreError::Flash);
+                }
+                Some(ref mut left) => *left -= 1,
+                None => {}
+            }
+            target.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, page: u32) -> Result<(), StoreError> {
+            assert!(page < PAGE_COUNT);
+            let start = (page * PAGE_SIZE) as usize;
+            self.data.borrow_mut()[start..start + PAGE_SIZE as usize].fill(0xff);
+            Ok(())
+        }
+    }
+
+    fn mount(storage: &RamStorage) -> ConfigStore<RamStorage> {
+        ConfigStore::mount(storage.clone()).unwrap()
+    }
+
+    fn active_page(storage: &RamStorage) -> u32 {
+        mount(storage).active.unwrap().0
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn empty_region() {
+        let storage = RamStorage::new();
+        let store = mount(&storage);
+        assert_eq!(store.active, None);
+        assert_eq!(store.get(1), None);
+    }
+
+    #[test]
+    fn values_survive_mount() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        store.commit(&[(1, &[1, 2, 3]), (2, &[])]).unwrap();
+        store.commit(&[(1, &[4])]).unwrap();
+        assert_eq!(store.get(1), Some(&[4][..]));
+
+        let store = mount(&storage);
+        assert_eq!(store.get(1), Some(&[4][..]));
+        assert_eq!(store.get(2), Some(&[][..]));
+        assert_eq!(store.get(3), None);
+    }
+
+    #[test]
+    fn torn_commit_keeps_previous_values() {
+        for writes in 0..3 {
+            let storage = RamStorage::new();
+            let mut store = mount(&storage);
+            store.commit(&[(1, &[1]), (2, &[2])]).unwrap();
+
+            // two values and the commit, the power fails on write `writes`
+            storage.fail_after(writes);
+            assert_eq!(
+                store.commit(&[(1, &[10]), (2, &[20])]),
+                Err(StoreError::Flash)
+            );
+
+            let storage = RamStorage {
+                writes_left: Rc::new(RefCell::new(None)),
+                ..storage
+            };
+            let mut store = mount(&storage);
+            assert_eq!(store.get(1), Some(&[1][..]), "torn at write {}", writes);
+            assert_eq!(store.get(2), Some(&[2][..]));
+
+            // the torn slots are skipped by later transactions
+            store.commit(&[(2, &[30])]).unwrap();
+            let store = mount(&storage);
+            assert_eq!(store.get(1), Some(&[1][..]));
+            assert_eq!(store.get(2), Some(&[30][..]));
+        }
+    }
+
+    #[test]
+    fn failed_write_compacts_on_next_commit() {
+        for writes in 0..3 {
+            let storage = RamStorage::new();
+            let mut store = mount(&storage);
+            store.commit(&[(1, &[1]), (2, &[2])]).unwrap();
+
+            // the flash fails once, without a reset in between
+            storage.fail_after(writes);
+            assert_eq!(
+                store.commit(&[(1, &[10]), (2, &[20])]),
+                Err(StoreError::Flash)
+            );
+            *storage.writes_left.borrow_mut() = None;
+            assert_eq!(store.get(1), Some(&[1][..]));
+
+            store.commit(&[(2, &[30])]).unwrap();
+            assert_eq!(store.active.unwrap().0, 1, "failed at write {}", writes);
+            let store = mount(&storage);
+            assert_eq!(store.get(1), Some(&[1][..]));
+            assert_eq!(store.get(2), Some(&[30][..]));
+        }
+    }
+
+    #[test]
+    fn corrupt_slot_drops_its_transaction() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        store.commit(&[(1, &[1])]).unwrap();
+        store.commit(&[(1, &[2]), (2, &[2])]).unwrap();
+        store.commit(&[(3, &[3])]).unwrap();
+
+        // header, value 1 and its commit, then the second transaction
+        storage.corrupt(0, 4);
+        let store = mount(&storage);
+        assert_eq!(store.get(1), Some(&[1][..]));
+        assert_eq!(store.get(2), None);
+        assert_eq!(store.get(3), Some(&[3][..]));
+    }
+
+    #[test]
+    fn corrupt_commit_drops_its_transaction() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        store.commit(&[(1, &[1])]).unwrap();
+        store.commit(&[(1, &[2])]).unwrap();
+
+        storage.corrupt(0, 4);
+        assert_eq!(mount(&storage).get(1), Some(&[1][..]));
+    }
+
+    #[test]
+    fn compaction_wraps_around_the_pages() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        store.commit(&[(7, &[7; MAX_VALUE_LEN])]).unwrap();
+
+        let mut pages = Vec::new();
+        for i in 0..(SLOTS * PAGE_COUNT + 10) {
+            store.commit(&[(1, &i.to_le_bytes())]).unwrap();
+            let page = store.active.unwrap().0;
+            if pages.last() != Some(&page) {
+                pages.push(page);
+            }
+        }
+        assert_eq!(pages, [0, 1, 2, 3, 0, 1, 2, 3, 0]);
+
+        let store = mount(&storage);
+        let last = SLOTS * PAGE_COUNT + 9;
+        assert_eq!(store.get(1), Some(&last.to_le_bytes()[..]));
+        assert_eq!(store.get(7), Some(&[7; MAX_VALUE_LEN][..]));
+    }
+
+    #[test]
+    fn mount_selects_newest_page() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        // the first compaction starts page 0 with sequence 1
+        let mut i = 0u32;
+        while store.active.map(|(page, _)| page) != Some(2) {
+            store.commit(&[(1, &i.to_le_bytes())]).unwrap();
+            i += 1;
+        }
+        assert_eq!(active_page(&storage), 2);
+        assert_eq!(mount(&storage).active.unwrap().1, 3);
+
+        // older pages still hold valid headers
+        assert_eq!(decode_header(&storage.slot(0, 0)), Some(1));
+        assert_eq!(decode_header(&storage.slot(1, 0)), Some(2));
+        assert_eq!(mount(&storage).get(1), Some(&(i - 1).to_le_bytes()[..]));
+
+        // a corrupt header of the newest page falls back to the one before
+        storage.corrupt(2, 0);
+        let store = mount(&storage);
+        assert_eq!(store.active.unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn torn_compaction_keeps_previous_page() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        store.commit(&[(2, &[2])]).unwrap();
+        let mut i = 0u32;
+        while store.next_slot + 2 <= SLOTS {
+            store.commit(&[(1, &i.to_le_bytes())]).unwrap();
+            i += 1;
+        }
+
+        // values and commit are copied, the power fails before the header
+        storage.fail_after(3);
+        assert_eq!(store.commit(&[(1, &[0xaa])]), Err(StoreError::Flash));
+
+        let storage = RamStorage {
+            writes_left: Rc::new(RefCell::new(None)),
+            ..storage
+        };
+        let mut store = mount(&storage);
+        assert_eq!(store.active.unwrap().0, 0);
+        assert_eq!(store.get(1), Some(&(i - 1).to_le_bytes()[..]));
+        assert_eq!(store.get(2), Some(&[2][..]));
+
+        // the next compaction erases the torn page again
+        store.commit(&[(1, &[0xbb])]).unwrap();
+        let store = mount(&storage);
+        assert_eq!(store.active.unwrap(), (1, 2));
+        assert_eq!(store.get(1), Some(&[0xbb][..]));
+    }
+
+    #[test]
+    fn full_and_value_too_long() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        assert_eq!(
+            store.commit(&[(1, &[0; MAX_VALUE_LEN + 1])]),
+            Err(StoreError::ValueTooLong)
+        );
+
+        for key in 0..MAX_KEYS as u16 {
+            store.commit(&[(key, &[key as u8])]).unwrap();
+        }
+        // existing keys can still change, a new one does not fit
+        store.commit(&[(0, &[0xff])]).unwrap();
+        assert_eq!(
+            store.commit(&[(0, &[1]), (MAX_KEYS as u16, &[])]),
+            Err(StoreError::Full)
+        );
+
+        // failed commits leave the values untouched
+        let store = mount(&storage);
+        assert_eq!(store.get(0), Some(&[0xff][..]));
+        assert_eq!(store.get(1), Some(&[1][..]));
+        assert_eq!(store.get(MAX_KEYS as u16), None);
+    }
+
+    #[test]
+    fn too_many_changes() {
+        let storage = RamStorage::new();
+        let mut store = mount(&storage);
+        let changes = [(1, &[][..]); MAX_KEYS + 1];
+        assert_eq!(store.commit(&changes), Err(StoreError::Full));
+        assert_eq!(mount(&storage).active, None);
+    }
+}
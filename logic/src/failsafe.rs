@@ -0,0 +1,159 @@
+/// Command messages received from the Orin ECU that are supervised.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    WheelAngle = 0,
+    Drive = 1,
+}
+
+pub const COMMAND_COUNT: usize = 2;
+
+impl Command {
+    pub const ALL: [Command; COMMAND_COUNT] = [Command::WheelAngle, Command::Drive];
+
+    pub const fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Tracks the age of every supervised command.
+///
+/// The timeout of a command is armed by its first frame, a command never
+/// received since boot is not supervised. The actuators rest in their
+/// neutral position until then, and an HMI not sending every command does
+/// not keep the car in the safe state.
+pub struct CommandMonitor {
+    timeout_ms: [u64; COMMAND_COUNT],
+    last_rx_ms: [Option<u64>; COMMAND_COUNT],
+    timed_out: u8,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Transition {
+    /// commands that just exceeded their timeout
+    pub lost: u8,
+    /// commands that are fresh again after a timeout
+    pub recovered: u8,
+    /// all commands currently timed out
+    pub timed_out: u8,
+}
+
+impl CommandMonitor {
+    /// `timeout_ms` is indexed by `Command`.
+    pub const fn new(timeout_ms: [u64; COMMAND_COUNT]) -> Self {
+        Self {
+            timeout_ms,
+            last_rx_ms: [None; COMMAND_COUNT],
+            timed_out: 0,
+        }
+    }
+
+    pub fn received(&mut self, command: Command, now_ms: u64) {
+        self.last_rx_ms[command as usize] = Some(now_ms);
+    }
+
+    pub fn update(&mut self, now_ms: u64) -> Transition {
+        let mut timed_out = 0;
+        for command in Command::ALL {
+            let expired = match self.last_rx_ms[command as usize] {
+                Some(last_rx_ms) => {
+                    now_ms.saturating_sub(last_rx_ms) > self.timeout_ms[command as usize]
+                }
+                None => false,
+            };
+            if expired {
+                timed_out |= command.mask();
+            }
+        }
+
+        let transition = Transition {
+            lost: timed_out & !self.timed_out,
+            recovered: self.timed_out & !timed_out,
+            timed_out,
+        };
+        self.timed_out = timed_out;
+        transition
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.timed_out != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHEEL: u8 = Command::WheelAngle.mask();
+    const DRIVE: u8 = Command::Drive.mask();
+
+    fn monitor() -> CommandMonitor {
+        CommandMonitor::new([30, 60])
+    }
+
+    #[test]
+    fn armed_by_first_frame() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.update(0).timed_out, 0);
+        assert_eq!(monitor.update(1000).timed_out, 0);
+        assert!(!monitor.is_active());
+
+        // only the command received is supervised
+        monitor.received(Command::WheelAngle, 1000);
+        assert_eq!(monitor.update(1030).timed_out, 0);
+        let transition = monitor.update(1031);
+        assert_eq!(transition.lost, WHEEL);
+        assert_eq!(transition.timed_out, WHEEL);
+    }
+
+    #[test]
+    fn recovers_per_command() {
+        let mut monitor = monitor();
+        monitor.received(Command::WheelAngle, 0);
+        monitor.received(Command::Drive, 0);
+        assert_eq!(monitor.update(100).timed_out, WHEEL | DRIVE);
+
+        monitor.received(Command::WheelAngle, 105);
+        let transition = monitor.update(110);
+        assert_eq!(transition.recovered, WHEEL);
+        assert_eq!(transition.timed_out, DRIVE);
+        assert!(monitor.is_active());
+
+        monitor.received(Command::Drive, 112);
+        let transition = monitor.update(120);
+        assert_eq!(transition.recovered, DRIVE);
+        assert_eq!(transition.timed_out, 0);
+        assert!(!monitor.is_active());
+    }
+
+    #[test]
+    fn times_out_after_own_timeout() {
+        let mut monitor = monitor();
+        monitor.received(Command::WheelAngle, 100);
+        monitor.received(Command::Drive, 100);
+        assert_eq!(monitor.update(130).timed_out, 0);
+
+        let transition = monitor.update(131);
+        assert_eq!(transition.lost, WHEEL);
+        assert_eq!(transition.timed_out, WHEEL);
+
+        // reported as lost only once but stays timed out
+        let transition = monitor.update(140);
+        assert_eq!(transition.lost, 0);
+        assert_eq!(transition.timed_out, WHEEL);
+
+        let transition = monitor.update(161);
+        assert_eq!(transition.lost, DRIVE);
+        assert_eq!(transition.timed_out, WHEEL | DRIVE);
+    }
+
+    #[test]
+    fn late_frame_before_update_does_not_time_out() {
+        let mut monitor = monitor();
+        monitor.received(Command::WheelAngle, 0);
+        monitor.received(Command::Drive, 0);
+        monitor.update(10);
+        monitor.received(Command::WheelAngle, 40);
+        assert_eq!(monitor.update(40).timed_out & WHEEL, 0);
+    }
+}
@@ -0,0 +1,407 @@
+use movavg::MovAvg;
+
+/// Stage of the ultrasound distance filter chain.
+pub trait Filter {
+    /// Feeds a distance in mm measured at `now_ms`, returns `None` when the
+    /// sample was rejected or the stage has no output yet.
+    fn feed(&mut self, sample: u64, now_ms: u64) -> Option<u64>;
+    fn reset(&mut self);
+}
+
+/// Median of the last `N` samples, removes single spikes without lag on steps.
+pub struct Median<const N: usize> {
+    window: [u64; N],
+    len: usize,
+    pos: usize,
+}
+
+impl<const N: usize> Median<N> {
+    pub const fn new() -> Self {
+        Self {
+            window: [0; N],
+            len: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<const N: usize> Filter for Median<N> {
+    fn feed(&mut self, sample: u64, _now_ms: u64) -> Option<u64> {
+        self.window[self.pos] = sample;
+        self.pos = (self.pos + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        let mut sorted = self.window;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        Some(sorted[self.len / 2])
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.pos = 0;
+    }
+}
+
+/// Rejects samples implying a faster change than physically possible.
+///
+/// After `MAX_REJECTED` consecutive rejections the new value is accepted, as
+/// the obstacle really moved (e.g. something stepped in front of the sensor).
+pub struct OutlierRejection {
+    max_rate_mm_per_s: u64,
+    last: Option<(u64, u64)>,
+    rejected: u8,
+}
+
+impl OutlierRejection {
+    const MAX_REJECTED: u8 = 3;
+
+    pub const fn new(max_rate_mm_per_s: u64) -> Self {
+        Self {
+            max_rate_mm_per_s,
+            last: None,
+            rejected: 0,
+        }
+    }
+}
+
+impl Filter for OutlierRejection {
+    fn feed(&mut self, sample: u64, now_ms: u64) -> Option<u64> {
+        if let Some((last, ts)) = self.last {
+            // allow at least one rate worth of 10 ms to tolerate back to back samples
+            let elapsed_ms = now_ms.saturating_sub(ts).max(10);
+            let max_change = self.max_rate_mm_per_s * elapsed_ms / 1000;
+            if sample.abs_diff(last) > max_change && self.rejected < Self::MAX_REJECTED {
+                self.rejected += 1;
+                return None;
+            }
+        }
+
+        self.rejected = 0;
+        self.last = Some((sample, now_ms));
+        Some(sample)
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+        self.rejected = 0;
+    }
+}
+
+/// Exponential smoothing, `alpha` close to 1.0 follows the input quickly.
+pub struct Exponential {
+    alpha: f32,
+    value: Option<f32>,
+}
+
+impl Exponential {
+    pub const fn new(alpha: f32) -> Self {
+        Self { alpha, value: None }
+    }
+}
+
+impl Filter for Exponential {
+    fn feed(&mut self, sample: u64, _now_ms: u64) -> Option<u64> {
+        let sample = sample as f32;
+        let value = match self.value {
+            Some(value) => value + self.alpha * (sample - value),
+            None => sample,
+        };
+        self.value = Some(value);
+        Some(value as u64)
+    }
+
+    fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+/// Moving average over the last `N` samples.
+pub struct MovingAverage<const N: usize> {
+    avg: MovAvg<u64, i64, N>,
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub fn new() -> Self {
+        Self { avg: MovAvg::new() }
+    }
+}
+
+impl<const N: usize> Filter for MovingAverage<N> {
+    fn feed(&mut self, sample: u64, _now_ms: u64) -> Option<u64> {
+        self.avg.feed(sample);
+        self.avg.try_get().ok()
+    }
+
+    fn reset(&mut self) {
+        self.avg = MovAvg::new();
+    }
+}
+
+/// Enabled stages of the filter chain, applied in the order of the fields.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FilterConfig {
+    pub median: bool,
+    pub outlier_rejection: bool,
+    pub exponential: bool,
+    pub moving_average: bool,
+    pub max_rate_mm_per_s: u64,
+    pub alpha: f32,
+}
+
+impl FilterConfig {
+    /// Same limits as the tuning parameters, `alpha` 0 freezes the exponential
+    /// stage and a low rate rejects nearly every sample.
+    pub fn is_valid(&self) -> bool {
+        (0.01..=1.0).contains(&self.alpha) && (100..=20_000).contains(&self.max_rate_mm_per_s)
+    }
+}
+
+pub const DEFAULT_FILTER: FilterConfig = FilterConfig {
+    median: true,
+    outlier_rejection: true,
+    exponential: true,
+    moving_average: false,
+    max_rate_mm_per_s: 3000,
+    alpha: 0.5,
+};
+
+pub struct FilterChain {
+    config: FilterConfig,
+    median: Median<5>,
+    outlier_rejection: OutlierRejection,
+    exponential: Exponential,
+    moving_average: MovingAverage<12>,
+    output: Option<u64>,
+}
+
+impl FilterChain {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            median: Median::new(),
+            outlier_rejection: OutlierRejection::new(config.max_rate_mm_per_s),
+            exponential: Exponential::new(config.alpha),
+            moving_average: MovingAverage::new(),
+            output: None,
+        }
+    }
+
+    /// Replaces the configuration, restarting the filter when it changed.
+    pub fn configure(&mut self, config: FilterConfig) {
+        if config != self.config {
+            *self = Self::new(config);
+        }
+    }
+
+    /// Last filtered value.
+    pub fn output(&self) -> Option<u64> {
+        self.output
+    }
+
+    fn stages(&mut self) -> [Option<&mut dyn Filter>; 4] {
+        let config = self.config;
+        [
+            Some(&mut self.median as &mut dyn Filter).filter(|_| config.median),
+            Some(&mut self.outlier_rejection as &mut dyn Filter)
+                .filter(|_| config.outlier_rejection),
+            Some(&mut self.exponential as &mut dyn Filter).filter(|_| config.exponential),
+            Some(&mut self.moving_average as &mut dyn Filter).filter(|_| config.moving_average),
+        ]
+    }
+}
+
+impl Filter for FilterChain {
+    fn feed(&mut self, sample: u64, now_ms: u64) -> Option<u64> {
+        let mut value = Some(sample);
+        for stage in self.stages().into_iter().flatten() {
+            value = value.and_then(|value| stage.feed(value, now_ms));
+        }
+
+        if value.is_some() {
+            self.output = value;
+        }
+        value
+    }
+
+    fn reset(&mut self) {
+        for stage in self.stages().into_iter().flatten() {
+            stage.reset();
+        }
+        self.output = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_MS: u64 = 50;
+    // steady obstacle at 1 m with a single reflection at 3 m
+    const SPIKE: [u64; 8] = [1000, 1000, 1000, 1000, 3000, 1000, 1000, 1000];
+    // obstacle moving from 1 m to 2 m at once
+    const STEP: [u64; 8] = [1000, 1000, 1000, 1000, 2000, 2000, 2000, 2000];
+
+    fn run(filter: &mut impl Filter, samples: &[u64]) -> Vec<Option<u64>> {
+        samples
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| filter.feed(sample, i as u64 * PERIOD_MS))
+            .collect()
+    }
+
+    #[test]
+    fn median_removes_spike() {
+        let out = run(&mut Median::<5>::new(), &SPIKE);
+        assert!(out.iter().all(|&value| value == Some(1000)));
+    }
+
+    #[test]
+    fn median_follows_step_after_half_the_window() {
+        let out = run(&mut Median::<5>::new(), &STEP);
+        assert_eq!(out[4..], [Some(1000), Some(1000), Some(2000), Some(2000)]);
+    }
+
+    #[test]
+    fn median_reset_drops_old_samples() {
+        let mut median = Median::<5>::new();
+        run(&mut median, &[1000; 5]);
+        median.reset();
+        assert_eq!(median.feed(2000, 0), Some(2000));
+    }
+
+    #[test]
+    fn outlier_rejection_drops_spike() {
+        let out = run(&mut OutlierRejection::new(3000), &SPIKE);
+        assert_eq!(out[4], None);
+        assert!(out
+            .iter()
+            .filter(|value| value.is_some())
+            .all(|&value| value == Some(1000)));
+    }
+
+    #[test]
+    fn outlier_rejection_accepts_persistent_step() {
+        let out = run(&mut OutlierRejection::new(3000), &STEP);
+        assert_eq!(out[4..], [None, None, None, Some(2000)]);
+    }
+
+    #[test]
+    fn outlier_rejection_allows_more_change_after_dropout() {
+        let mut filter = OutlierRejection::new(3000);
+        assert_eq!(filter.feed(1000, 0), Some(1000));
+        // nothing measured for a second, the obstacle may have moved 3 m
+        assert_eq!(filter.feed(3500, 1000), Some(3500));
+        // back to back samples still allow 10 ms worth of change
+        assert_eq!(filter.feed(3530, 1000), Some(3530));
+        assert_eq!(filter.feed(3600, 1000), None);
+    }
+
+    #[test]
+    fn exponential_smooths_step() {
+        let out = run(&mut Exponential::new(0.5), &STEP);
+        assert_eq!(
+            out[3..],
+            [Some(1000), Some(1500), Some(1750), Some(1875), Some(1937)]
+        );
+    }
+
+    #[test]
+    fn exponential_with_alpha_one_passes_through() {
+        let out = run(&mut Exponential::new(1.0), &SPIKE);
+        assert_eq!(out, SPIKE.map(Some));
+    }
+
+    #[test]
+    fn moving_average_smooths_step() {
+        let out = run(&mut MovingAverage::<4>::new(), &STEP);
+        assert_eq!(
+            out[3..],
+            [Some(1000), Some(1250), Some(1500), Some(1750), Some(2000)]
+        );
+    }
+
+    #[test]
+    fn moving_average_reset_drops_old_samples() {
+        let mut average = MovingAverage::<4>::new();
+        run(&mut average, &[1000; 4]);
+        average.reset();
+        assert_eq!(average.feed(2000, 0), Some(2000));
+    }
+
+    #[test]
+    fn chain_removes_spike() {
+        let mut chain = FilterChain::new(DEFAULT_FILTER);
+        let out = run(&mut chain, &SPIKE);
+        assert!(out.iter().flatten().all(|&value| value == 1000));
+        assert_eq!(chain.output(), Some(1000));
+    }
+
+    #[test]
+    fn chain_follows_step() {
+        let mut chain = FilterChain::new(DEFAULT_FILTER);
+        let samples = [[1000; 5].as_slice(), &[2000; 12]].concat();
+        run(&mut chain, &samples);
+        assert!(chain.output().unwrap() > 1900);
+    }
+
+    #[test]
+    fn chain_keeps_output_of_rejected_samples() {
+        let config = FilterConfig {
+            median: false,
+            ..DEFAULT_FILTER
+        };
+        let mut chain = FilterChain::new(config);
+        run(&mut chain, &SPIKE[..4]);
+        assert_eq!(chain.feed(3000, 4 * PERIOD_MS), None);
+        assert_eq!(chain.output(), Some(1000));
+    }
+
+    #[test]
+    fn chain_reset_after_dropout() {
+        let mut chain = FilterChain::new(DEFAULT_FILTER);
+        run(&mut chain, &[1000; 5]);
+        chain.reset();
+        assert_eq!(chain.output(), None);
+        assert_eq!(chain.feed(2500, 10_000), Some(2500));
+    }
+
+    #[test]
+    fn configure_restarts_only_on_change() {
+        let mut chain = FilterChain::new(DEFAULT_FILTER);
+        run(&mut chain, &[1000; 5]);
+        chain.configure(DEFAULT_FILTER);
+        assert_eq!(chain.output(), Some(1000));
+        chain.configure(FilterConfig {
+            alpha: 0.2,
+            ..DEFAULT_FILTER
+        });
+        assert_eq!(chain.output(), None);
+    }
+
+    #[test]
+    fn config_limits() {
+        assert!(DEFAULT_FILTER.is_valid());
+        for config in [
+            FilterConfig {
+                alpha: 0.0,
+                ..DEFAULT_FILTER
+            },
+            FilterConfig {
+                alpha: 1.5,
+                ..DEFAULT_FILTER
+            },
+            FilterConfig {
+                max_rate_mm_per_s: 0,
+                ..DEFAULT_FILTER
+            },
+            FilterConfig {
+                max_rate_mm_per_s: 65535,
+                ..DEFAULT_FILTER
+            },
+        ] {
+            assert!(!config.is_valid());
+        }
+    }
+}
@@ -0,0 +1,547 @@
+/// Largest message either way, longer first frames are answered with an
+/// overflow flow control.
+pub const MAX_PAYLOAD: usize = 128;
+pub const FRAME_LEN: usize = 8;
+pub type Frame = [u8; FRAME_LEN];
+
+const PADDING: u8 = 0xaa;
+
+const PCI_SINGLE: u8 = 0x0;
+const PCI_FIRST: u8 = 0x1;
+const PCI_CONSECUTIVE: u8 = 0x2;
+const PCI_FLOW_CONTROL: u8 = 0x3;
+
+const FLOW_CONTINUE: u8 = 0x0;
+const FLOW_WAIT: u8 = 0x1;
+const FLOW_OVERFLOW: u8 = 0x2;
+
+// N_Cr, N_Bs: time allowed for the next consecutive frame or flow control
+const N_CR_MS: u64 = 1000;
+const N_BS_MS: u64 = 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IsoTpError {
+    InvalidFrame,
+    /// consecutive frame out of order, the reception is aborted
+    Sequence,
+    /// consecutive frame without a first frame or after N_Cr
+    Unexpected,
+    /// the message does not fit `MAX_PAYLOAD`
+    Overflow,
+    /// no flow control within N_Bs
+    Timeout,
+}
+
+fn padded(data: &[u8]) -> Frame {
+    let mut frame = [PADDING; FRAME_LEN];
+    frame[..data.len()].copy_from_slice(data);
+    frame
+}
+
+fn flow_control(status: u8) -> Frame {
+    // block size 0 and no separation time, the receiver keeps up
+    padded(&[PCI_FLOW_CONTROL << 4 | status, 0, 0])
+}
+
+pub fn is_single_frame(data: &[u8]) -> bool {
+    data.first().is_some_and(|pci| pci >> 4 == PCI_SINGLE)
+}
+
+pub fn is_flow_control(data: &[u8]) -> bool {
+    data.first().is_some_and(|pci| pci >> 4 == PCI_FLOW_CONTROL)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxStatus {
+    /// nothing to do until the next frame
+    Pending,
+    /// send the frame back to the tester
+    FlowControl(Frame),
+    /// `Receiver::message` holds a complete message
+    Complete,
+    Error(IsoTpError),
+}
+
+/// Reassembles messages from single, first and consecutive frames.
+pub struct Receiver {
+    buffer: [u8; MAX_PAYLOAD],
+    len: usize,
+    received: usize,
+    sequence: u8,
+    deadline_ms: Option<u64>,
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_PAYLOAD],
+            len: 0,
+            received: 0,
+            sequence: 0,
+            deadline_ms: None,
+        }
+    }
+
+    /// Last complete message.
+    pub fn message(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn on_frame(&mut self, data: &[u8], now_ms: u64) -> RxStatus {
+        let Some(&pci) = data.first() else {
+            return RxStatus::Error(IsoTpError::InvalidFrame);
+        };
+        match pci >> 4 {
+            PCI_SINGLE => {
+                // a new message aborts one in progress
+                self.deadline_ms = None;
+                let len = (pci & 0x0f) as usize;
+                if len == 0 || len >= data.len() {
+                    return RxStatus::Error(IsoTpError::InvalidFrame);
+                }
+                self.buffer[..len].copy_from_slice(&data[1..=len]);
+                self.len = len;
+                RxStatus::Complete
+            }
+            PCI_FIRST => {
+                self.deadline_ms = None;
+                let len = ((pci & 0x0f) as usize) << 8 | *data.get(1).unwrap_or(&0) as usize;
+                if data.len() != FRAME_LEN || len < FRAME_LEN {
+                    return RxStatus::Error(IsoTpError::InvalidFrame);
+                }
+                if len > MAX_PAYLOAD {
+                    return RxStatus::FlowControl(flow_control(FLOW_OVERFLOW));
+                }
+                self.buffer[..FRAME_LEN - 2].copy_from_slice(&data[2..]);
+                self.len = len;
+                self.received = FRAME_LEN - 2;
+                self.sequence = 1;
+                self.deadline_ms = Some(now_ms + N_CR_MS);
+                RxStatus::FlowControl(flow_control(FLOW_CONTINUE))
+            }
+            PCI_CONSECUTIVE => {
+                match self.deadline_ms {
+                    Some(deadline) if now_ms <= deadline => {}
+                    _ => {
+                        self.deadline_ms = None;
+                        return RxStatus::Error(IsoTpError::Unexpected);
+                    }
+                }
+                if pci & 0x0f != self.sequence {
+                    self.deadline_ms = None;
+                    return RxStatus::Error(IsoTpError::Sequence);
+                }
+                let count = (self.len - self.received).min(FRAME_LEN - 1);
+                if data.len() <= count {
+                    self.deadline_ms = None;
+                    return RxStatus::Error(IsoTpError::InvalidFrame);
+                }
+                self.buffer[self.received..self.received + count].copy_from_slice(&data[1..=count]);
+                self.received += count;
+                self.sequence = (self.sequence + 1) & 0x0f;
+                if self.received == self.len {
+                    self.deadline_ms = None;
+                    RxStatus::Complete
+                } else {
+                    self.deadline_ms = Some(now_ms + N_CR_MS);
+                    RxStatus::Pending
+                }
+            }
+            // flow control belongs to the transmitter
+            PCI_FLOW_CONTROL => RxStatus::Pending,
+            _ => RxStatus::Error(IsoTpError::InvalidFrame),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxStep {
+    Send(Frame),
+    /// call `poll` again at this time or when a frame arrives
+    WaitUntil(u64),
+    Done,
+    Failed(IsoTpError),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    WaitFlowControl {
+        deadline_ms: u64,
+    },
+    Sending {
+        next_ms: u64,
+        block_left: Option<u8>,
+    },
+}
+
+/// Segments a message into single or first and consecutive frames, paced
+/// by the flow control of the tester.
+pub struct Transmitter {
+    buffer: [u8; MAX_PAYLOAD],
+    len: usize,
+    sent: usize,
+    sequence: u8,
+    separation_ms: u64,
+    state: TxState,
+}
+
+impl Transmitter {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_PAYLOAD],
+            len: 0,
+            sent: 0,
+            sequence: 0,
+            separation_ms: 0,
+            state: TxState::Idle,
+        }
+    }
+
+    /// Returns the single or first frame, consecutive frames follow from
+    /// `poll`.
+    pub fn start(&mut self, payload: &[u8], now_ms: u64) -> Result<Frame, IsoTpError> {
+        let len = payload.len();
+        if len == 0 || len > MAX_PAYLOAD {
+            return Err(IsoTpError::Overflow);
+        }
+        if len < FRAME_LEN {
+            self.state = TxState::Idle;
+            let mut frame = padded(&[PCI_SINGLE << 4 | len as u8]);
+            frame[1..=len].copy_from_slice(payload);
+            return Ok(frame);
+        }
+
+        self.buffer[..len].copy_from_slice(payload);
+        self.len = len;
+        self.sent = FRAME_LEN - 2;
+        self.sequence = 1;
+        self.state = TxState::WaitFlowControl {
+            deadline_ms: now_ms + N_BS_MS,
+        };
+        let mut frame = [
+            PCI_FIRST << 4 | (len >> 8) as u8,
+            len as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        frame[2..].copy_from_slice(&payload[..FRAME_LEN - 2]);
+        Ok(frame)
+    }
+
+    /// Handles a frame from the tester, everything but flow control while
+    /// waiting for it is ignored.
+    pub fn on_frame(&mut self, data: &[u8], now_ms: u64) -> Result<(), IsoTpError> {
+        if !matches!(self.state, TxState::WaitFlowControl { .. }) {
+            return Ok(());
+        }
+        let (Some(&pci), Some(&block_size), Some(&separation)) =
+            (data.first(), data.get(1), data.get(2))
+        else {
+            return Ok(());
+        };
+        if pci >> 4 != PCI_FLOW_CONTROL {
+            return Ok(());
+        }
+        match pci & 0x0f {
+            FLOW_CONTINUE => {
+                self.separation_ms = match separation {
+                    0x00..=0x7f => separation as u64,
+                    // 100 to 900 us, rounded up to the timer resolution
+                    0xf1..=0xf9 => 1,
+                    // reserved values mean the longest time
+                    _ => 0x7f,
+                };
+                self.state = TxState::Sending {
+                    next_ms: now_ms,
+                    block_left: (block_size != 0).then_some(block_size),
+                };
+                Ok(())
+            }
+            FLOW_WAIT => {
+                self.state = TxState::WaitFlowControl {
+                    deadline_ms: now_ms + N_BS_MS,
+                };
+                Ok(())
+            }
+            FLOW_OVERFLOW => {
+                self.state = TxState::Idle;
+                Err(IsoTpError::Overflow)
+            }
+            _ => {
+                self.state = TxState::Idle;
+                Err(IsoTpError::InvalidFrame)
+            }
+        }
+    }
+
+    pub fn poll(&mut self, now_ms: u64) -> TxStep {
+        match self.state {
+            TxState::Idle => TxStep::Done,
+            TxState::WaitFlowControl { deadline_ms } if now_ms > deadline_ms => {
+                self.state = TxState::Idle;
+                TxStep::Failed(IsoTpError::Timeout)
+            }
+            TxState::WaitFlowControl { deadline_ms } => TxStep::WaitUntil(deadline_ms),
+            TxState::Sending { next_ms, .. } if now_ms < next_ms => TxStep::WaitUntil(next_ms),
+            TxState::Sending { block_left, .. } => {
+                let count = (self.len - self.sent).min(FRAME_LEN - 1);
+                let mut frame = padded(&[PCI_CONSECUTIVE << 4 | self.sequence]);
+                frame[1..=count].copy_from_slice(&self.buffer[self.sent..self.sent + count]);
+                self.sent += count;
+                self.sequence = (self.sequence + 1) & 0x0f;
+
+                let block_left = block_left.map(|left| left - 1);
+                self.state = if self.sent == self.len {
+                    TxState::Idle
+                } else if block_left == Some(0) {
+                    TxState::WaitFlowControl {
+                        deadline_ms: now_ms + N_BS_MS,
+                    }
+                } else {
+                    TxState::Sending {
+                        next_ms: now_ms + self.separation_ms,
+                        block_left,
+                    }
+                };
+                TxStep::Send(frame)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTINUE: Frame = [0x30, 0, 0, PADDING, PADDING, PADDING, PADDING, PADDING];
+
+    fn payload(len: usize) -> [u8; MAX_PAYLOAD] {
+        let mut payload = [0; MAX_PAYLOAD];
+        for (i, byte) in payload[..len].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        payload
+    }
+
+    /// Flow control from the tester with block size and separation time.
+    fn flow(status: u8, block_size: u8, separation: u8) -> Frame {
+        padded(&[PCI_FLOW_CONTROL << 4 | status, block_size, separation])
+    }
+
+    fn expect_send(step: TxStep) -> Frame {
+        match step {
+            TxStep::Send(frame) => frame,
+            step => panic!("expected a frame, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn single_frame() {
+        let mut rx = Receiver::new();
+        let frame = padded(&[0x03, 0x22, 0xf1, 0x90]);
+        assert_eq!(rx.on_frame(&frame, 0), RxStatus::Complete);
+        assert_eq!(rx.message(), &[0x22, 0xf1, 0x90]);
+        assert!(is_single_frame(&frame));
+        assert!(!is_flow_control(&frame));
+
+        let mut tx = Transmitter::new();
+        assert_eq!(tx.start(&[0x50, 0x03], 0), Ok(padded(&[0x02, 0x50, 0x03])));
+        assert_eq!(tx.poll(0), TxStep::Done);
+    }
+
+    #[test]
+    fn invalid_single_frame() {
+        let mut rx = Receiver::new();
+        assert_eq!(
+            rx.on_frame(&[], 0),
+            RxStatus::Error(IsoTpError::InvalidFrame)
+        );
+        assert_eq!(
+            rx.on_frame(&padded(&[0x00]), 0),
+            RxStatus::Error(IsoTpError::InvalidFrame)
+        );
+        // longer than the frame
+        assert_eq!(
+            rx.on_frame(&[0x05, 1, 2, 3], 0),
+            RxStatus::Error(IsoTpError::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn first_and_consecutive_frames() {
+        let mut rx = Receiver::new();
+        let first = [0x10, 0x0a, 0x2e, 0xf1, 0x90, 1, 2, 3];
+        assert_eq!(rx.on_frame(&first, 0), RxStatus::FlowControl(CONTINUE));
+        assert_eq!(
+            rx.on_frame(&padded(&[0x21, 4, 5, 6, 7]), 10),
+            RxStatus::Complete
+        );
+        assert_eq!(rx.message(), &[0x2e, 0xf1, 0x90, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let message = payload(MAX_PAYLOAD);
+        let mut tx = Transmitter::new();
+        let mut rx = Receiver::new();
+
+        let first = tx.start(&message, 0).unwrap();
+        let RxStatus::FlowControl(fc) = rx.on_frame(&first, 0) else {
+            panic!("no flow control");
+        };
+        assert_eq!(fc, CONTINUE);
+        tx.on_frame(&fc, 0).unwrap();
+
+        let mut sequences = [0; 18];
+        for (i, sequence) in sequences.iter_mut().enumerate() {
+            let frame = expect_send(tx.poll(0));
+            *sequence = frame[0] & 0x0f;
+            let status = rx.on_frame(&frame, 0);
+            if i < 17 {
+                assert_eq!(status, RxStatus::Pending);
+            } else {
+                assert_eq!(status, RxStatus::Complete);
+            }
+        }
+        assert_eq!(tx.poll(0), TxStep::Done);
+        assert_eq!(
+            sequences,
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2]
+        );
+        assert_eq!(rx.message(), &message[..]);
+    }
+
+    #[test]
+    fn sequence_error_aborts() {
+        let mut rx = Receiver::new();
+        let first = [0x10, 0x14, 0, 1, 2, 3, 4, 5];
+        rx.on_frame(&first, 0);
+        assert_eq!(
+            rx.on_frame(&padded(&[0x22, 6]), 0),
+            RxStatus::Error(IsoTpError::Sequence)
+        );
+        // the reception is gone, the right frame no longer continues it
+        assert_eq!(
+            rx.on_frame(&padded(&[0x21, 6]), 0),
+            RxStatus::Error(IsoTpError::Unexpected)
+        );
+    }
+
+    #[test]
+    fn consecutive_frame_timeout() {
+        let mut rx = Receiver::new();
+        let first = [0x10, 0x14, 0, 1, 2, 3, 4, 5];
+        assert_eq!(
+            rx.on_frame(&padded(&[0x21, 6]), 0),
+            RxStatus::Error(IsoTpError::Unexpected)
+        );
+
+        rx.on_frame(&first, 0);
+        let cf = [0x21, 6, 7, 8, 9, 10, 11, 12];
+        assert_eq!(rx.on_frame(&cf, N_CR_MS), RxStatus::Pending);
+        let cf = [0x22, 13, 14, 15, 16, 17, 18, 19];
+        assert_eq!(
+            rx.on_frame(&cf, 2 * N_CR_MS + 1),
+            RxStatus::Error(IsoTpError::Unexpected)
+        );
+    }
+
+    #[test]
+    fn flow_control_timeout() {
+        let mut tx = Transmitter::new();
+        tx.start(&payload(20)[..20], 0).unwrap();
+        assert_eq!(tx.poll(500), TxStep::WaitUntil(N_BS_MS));
+        assert_eq!(tx.poll(N_BS_MS + 1), TxStep::Failed(IsoTpError::Timeout));
+        assert_eq!(tx.poll(N_BS_MS + 2), TxStep::Done);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut rx = Receiver::new();
+        let first = [0x10, MAX_PAYLOAD as u8 + 1, 0, 1, 2, 3, 4, 5];
+        assert_eq!(
+            rx.on_frame(&first, 0),
+            RxStatus::FlowControl(padded(&[0x32, 0, 0]))
+        );
+        assert_eq!(
+            rx.on_frame(&padded(&[0x21, 6]), 0),
+            RxStatus::Error(IsoTpError::Unexpected)
+        );
+
+        let mut tx = Transmitter::new();
+        assert_eq!(
+            tx.start(&[0; MAX_PAYLOAD + 1], 0),
+            Err(IsoTpError::Overflow)
+        );
+        tx.start(&payload(20)[..20], 0).unwrap();
+        assert_eq!(
+            tx.on_frame(&flow(FLOW_OVERFLOW, 0, 0), 0),
+            Err(IsoTpError::Overflow)
+        );
+        assert_eq!(tx.poll(0), TxStep::Done);
+    }
+
+    #[test]
+    fn block_size_and_separation_time() {
+        let message = payload(40);
+        let mut tx = Transmitter::new();
+        let first = tx.start(&message[..40], 0).unwrap();
+        assert_eq!(first, [0x10, 40, 0, 1, 2, 3, 4, 5]);
+
+        // frames other than flow control are ignored
+        tx.on_frame(&padded(&[0x02, 0x3e, 0x00]), 0).unwrap();
+        assert_eq!(tx.poll(0), TxStep::WaitUntil(N_BS_MS));
+
+        // two frames 10 ms apart, then the next flow control
+        tx.on_frame(&flow(FLOW_CONTINUE, 2, 10), 0).unwrap();
+        assert_eq!(expect_send(tx.poll(0))[0], 0x21);
+        assert_eq!(tx.poll(5), TxStep::WaitUntil(10));
+        assert_eq!(expect_send(tx.poll(10))[0], 0x22);
+        assert_eq!(tx.poll(20), TxStep::WaitUntil(10 + N_BS_MS));
+
+        // wait restarts N_Bs
+        tx.on_frame(&flow(FLOW_WAIT, 0, 0), 30).unwrap();
+        assert_eq!(tx.poll(40), TxStep::WaitUntil(30 + N_BS_MS));
+
+        // the rest in one block, 100 us are rounded up to 1 ms
+        tx.on_frame(&flow(FLOW_CONTINUE, 0, 0xf1), 50).unwrap();
+        assert_eq!(expect_send(tx.poll(50))[0], 0x23);
+        assert_eq!(tx.poll(50), TxStep::WaitUntil(51));
+        assert_eq!(expect_send(tx.poll(51))[0], 0x24);
+        let last = expect_send(tx.poll(52));
+        assert_eq!(last, [0x25, 34, 35, 36, 37, 38, 39, PADDING]);
+        assert_eq!(tx.poll(53), TxStep::Done);
+    }
+
+    #[test]
+    fn reserved_separation_time() {
+        let mut tx = Transmitter::new();
+        tx.start(&payload(20)[..20], 0).unwrap();
+        tx.on_frame(&flow(FLOW_CONTINUE, 0, 0x80), 0).unwrap();
+        expect_send(tx.poll(0));
+        assert_eq!(tx.poll(1), TxStep::WaitUntil(0x7f));
+    }
+
+    #[test]
+    fn invalid_flow_status() {
+        let mut tx = Transmitter::new();
+        tx.start(&payload(20)[..20], 0).unwrap();
+        assert!(is_flow_control(&flow(0x3, 0, 0)));
+        assert_eq!(
+            tx.on_frame(&flow(0x3, 0, 0), 0),
+            Err(IsoTpError::InvalidFrame)
+        );
+        assert_eq!(tx.poll(0), TxStep::Done);
+    }
+}
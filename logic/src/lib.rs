@@ -0,0 +1,29 @@
+// Hardware independent parts of the firmware. Kept free of embassy so they
+// build for the host, run the tests with
+// `cargo test --manifest-path logic/Cargo.toml --target x86_64-unknown-linux-gnu`
+#![cfg_attr(not(test), no_std)]
+#![allow(clippy::new_without_default)]
+
+pub mod can_scheduler;
+pub mod collision;
+pub mod color_transition;
+pub mod config;
+pub mod config_store;
+pub mod failsafe;
+pub mod filter;
+pub mod isotp;
+pub mod lighting;
+pub mod lin;
+pub mod lin_diag;
+pub mod lin_power;
+pub mod lin_slave;
+pub mod math;
+pub mod motor;
+pub mod params;
+pub mod rgb_effects;
+pub mod servo;
+pub mod status_led;
+pub mod steering;
+pub mod steering_cal;
+pub mod uds;
+pub mod ultrasound;
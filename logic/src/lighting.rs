@@ -0,0 +1,443 @@
+use crate::math::abs_f32;
+
+#[derive(Copy, Clone)]
+pub struct LightingConfig {
+    /// photoresistor reading below which it is night
+    pub night_below: u16,
+    /// photoresistor reading above which it is day again
+    pub day_above: u16,
+    /// a new day/night decision has to hold this long, ignores shadows
+    pub daylight_hold_ms: u64,
+    /// deceleration switching the brake light on, m/s^2
+    pub brake_on_decel: f32,
+    pub brake_off_decel: f32,
+    pub brake_min_on_ms: u64,
+    /// speed below which the reverse light is on, km/h
+    pub reverse_below_kmh: f32,
+    /// wheel angle switching an indicator on, positive angles steer left
+    pub indicator_on_deg: f32,
+    pub indicator_off_deg: f32,
+    pub blink_period_ms: u64,
+}
+
+pub const DEFAULT_LIGHTING: LightingConfig = LightingConfig {
+    night_below: 300,
+    day_above: 500,
+    daylight_hold_ms: 2000,
+    brake_on_decel: 1.0,
+    brake_off_decel: 0.3,
+    brake_min_on_ms: 500,
+    reverse_below_kmh: -0.3,
+    indicator_on_deg: 15.0,
+    indicator_off_deg: 8.0,
+    blink_period_ms: 666,
+};
+
+/// Headlight command from CAN, the values are the `Light_Cmd_Headlights` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeadlightMode {
+    Auto = 0,
+    Off = 1,
+    On = 2,
+}
+
+/// Indicator command from CAN, the values are the `Light_Cmd_Indicators` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IndicatorMode {
+    Auto = 0,
+    Left = 1,
+    Right = 2,
+    Hazard = 3,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LightOverride {
+    pub headlights: HeadlightMode,
+    pub indicators: IndicatorMode,
+}
+
+impl LightOverride {
+    pub const AUTO: LightOverride = LightOverride {
+        headlights: HeadlightMode::Auto,
+        indicators: IndicatorMode::Auto,
+    };
+
+    pub fn from_raw(headlights: u8, indicators: u8) -> Self {
+        let headlights = match headlights {
+            1 => HeadlightMode::Off,
+            2 => HeadlightMode::On,
+            _ => HeadlightMode::Auto,
+        };
+        let indicators = match indicators {
+            1 => IndicatorMode::Left,
+            2 => IndicatorMode::Right,
+            3 => IndicatorMode::Hazard,
+            _ => IndicatorMode::Auto,
+        };
+        Self {
+            headlights,
+            indicators,
+        }
+    }
+}
+
+/// Lights that are on, indicators are reported while active, not per flash.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LightState {
+    pub headlights: bool,
+    pub brake: bool,
+    pub reverse: bool,
+    pub indicator_left: bool,
+    pub indicator_right: bool,
+    pub night: bool,
+    pub overridden: bool,
+    /// last photoresistor reading
+    pub brightness: u16,
+}
+
+impl LightState {
+    // bits of the LEDS frame byte
+    const LED_HEADLIGHTS: u8 = 1 << 0;
+    const LED_BRAKE: u8 = 1 << 1;
+    const LED_INDICATOR_LEFT: u8 = 1 << 2;
+    const LED_INDICATOR_RIGHT: u8 = 1 << 3;
+
+    /// LED frame data, `blink` is the current indicator flash phase.
+    pub fn leds(&self, blink: bool) -> u8 {
+        let mut leds = 0;
+        if self.headlights {
+            leds |= Self::LED_HEADLIGHTS;
+        }
+        if self.brake {
+            leds |= Self::LED_BRAKE;
+        }
+        if self.indicator_left && blink {
+            leds |= Self::LED_INDICATOR_LEFT;
+        }
+        if self.indicator_right && blink {
+            leds |= Self::LED_INDICATOR_RIGHT;
+        }
+        leds
+    }
+
+    /// The RGB LED is the rear lamp: reverse, brake or tail light.
+    pub fn rear_lamp(&self) -> (u8, u8, u8) {
+        if self.reverse {
+            (255, 255, 255)
+        } else if self.brake {
+            (255, 0, 0)
+        } else if self.headlights {
+            (40, 0, 0)
+        } else {
+            (0, 0, 0)
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+}
+
+pub struct LightingController {
+    config: LightingConfig,
+    night: bool,
+    /// time the photoresistor started to disagree with `night`
+    daylight_change_ms: Option<u64>,
+    brightness: u16,
+    speed: Option<(f32, u64)>,
+    decel: f32,
+    brake_since_ms: Option<u64>,
+    indicator: Option<Side>,
+    light_override: LightOverride,
+}
+
+impl LightingController {
+    pub fn new(config: LightingConfig) -> Self {
+        Self {
+            config,
+            night: false,
+            daylight_change_ms: None,
+            brightness: 0,
+            speed: None,
+            decel: 0.0,
+            brake_since_ms: None,
+            indicator: None,
+            light_override: LightOverride::AUTO,
+        }
+    }
+
+    pub fn brightness(&mut self, value: u16, now_ms: u64) {
+        self.brightness = value;
+        let disagrees = if self.night {
+            value > self.config.day_above
+        } else {
+            value < self.config.night_below
+        };
+
+        match (disagrees, self.daylight_change_ms) {
+            (false, _) => self.daylight_change_ms = None,
+            (true, None) => self.daylight_change_ms = Some(now_ms),
+            (true, Some(since)) if now_ms - since >= self.config.daylight_hold_ms => {
+                self.night = !self.night;
+                self.daylight_change_ms = None;
+            }
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Signed vehicle speed in km/h, the deceleration is derived from it.
+    pub fn speed(&mut self, kmh: f32, now_ms: u64) {
+        if let Some((last, last_ms)) = self.speed {
+            let dt = (now_ms - last_ms) as f32 / 1000.0;
+            if dt > 0.0 {
+                // slowing down in either direction is positive
+                let decel = (abs_f32(last) - abs_f32(kmh)) / 3.6 / dt;
+                self.decel += 0.3 * (decel - self.decel);
+            }
+        }
+        self.speed = Some((kmh, now_ms));
+
+        let config = &self.config;
+        match self.brake_since_ms {
+            None if self.decel > config.brake_on_decel => self.brake_since_ms = Some(now_ms),
+            Some(since)
+                if self.decel < config.brake_off_decel
+                    && now_ms - since >= config.brake_min_on_ms =>
+            {
+                self.brake_since_ms = None
+            }
+            _ => {}
+        }
+    }
+
+    pub fn steering(&mut self, angle_deg: f32) {
+        let config = &self.config;
+        self.indicator = match self.indicator {
+            Some(Side::Left) if angle_deg > config.indicator_off_deg => Some(Side::Left),
+            Some(Side::Right) if angle_deg < -config.indicator_off_deg => Some(Side::Right),
+            _ if angle_deg >= config.indicator_on_deg => Some(Side::Left),
+            _ if angle_deg <= -config.indicator_on_deg => Some(Side::Right),
+            _ => None,
+        };
+    }
+
+    pub fn set_override(&mut self, light_override: LightOverride) {
+        self.light_override = light_override;
+    }
+
+    pub fn state(&self) -> LightState {
+        let headlights = match self.light_override.headlights {
+            HeadlightMode::Auto => self.night,
+            HeadlightMode::Off => false,
+            HeadlightMode::On => true,
+        };
+        let (indicator_left, indicator_right) = match self.light_override.indicators {
+            IndicatorMode::Auto => (
+                self.indicator == Some(Side::Left),
+                self.indicator == Some(Side::Right),
+            ),
+            IndicatorMode::Left => (true, false),
+            IndicatorMode::Right => (false, true),
+            IndicatorMode::Hazard => (true, true),
+        };
+        let speed = self.speed.map_or(0.0, |(kmh, _)| kmh);
+
+        LightState {
+            headlights,
+            brake: self.brake_since_ms.is_some(),
+            reverse: speed < self.config.reverse_below_kmh,
+            indicator_left,
+            indicator_right,
+            night: self.night,
+            overridden: self.light_override != LightOverride::AUTO,
+            brightness: self.brightness,
+        }
+    }
+
+    pub fn blink(&self, now_ms: u64) -> bool {
+        now_ms % self.config.blink_period_ms < self.config.blink_period_ms / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_hysteresis() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        // between the thresholds the decision holds
+        lighting.brightness(400, 0);
+        lighting.brightness(400, 10_000);
+        assert!(!lighting.state().night);
+
+        lighting.brightness(299, 20_000);
+        lighting.brightness(299, 21_999);
+        assert!(!lighting.state().night);
+        lighting.brightness(299, 22_000);
+        assert!(lighting.state().night);
+        assert!(lighting.state().headlights);
+        assert_eq!(lighting.state().brightness, 299);
+
+        lighting.brightness(500, 30_000);
+        lighting.brightness(500, 40_000);
+        assert!(lighting.state().night);
+        lighting.brightness(501, 50_000);
+        lighting.brightness(501, 52_000);
+        assert!(!lighting.state().night);
+    }
+
+    #[test]
+    fn daylight_shadow_restarts_hold() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        lighting.brightness(100, 0);
+        lighting.brightness(400, 1500);
+        lighting.brightness(100, 1600);
+        lighting.brightness(100, 2000);
+        assert!(!lighting.state().night);
+        lighting.brightness(100, 3599);
+        assert!(!lighting.state().night);
+        lighting.brightness(100, 3600);
+        assert!(lighting.state().night);
+    }
+
+    /// Brakes from 36 to 32.4 km/h within the first 100 ms and keeps the
+    /// speed, returns the times the brake light was on.
+    fn brake_light(config: LightingConfig, sign: f32) -> Vec<u64> {
+        let mut lighting = LightingController::new(config);
+        let mut on = Vec::new();
+        for t in (0..=2000).step_by(100) {
+            let kmh = if t == 0 { 36.0 } else { 32.4 };
+            lighting.speed(sign * kmh, t);
+            if lighting.state().brake {
+                on.push(t);
+            }
+        }
+        on
+    }
+
+    #[test]
+    fn brake_light_on_deceleration() {
+        // the filtered deceleration drops below the off threshold at 800 ms
+        let on: Vec<u64> = (100..800).step_by(100).collect();
+        assert_eq!(brake_light(DEFAULT_LIGHTING, 1.0), on);
+        // slowing down while reversing
+        assert_eq!(brake_light(DEFAULT_LIGHTING, -1.0), on);
+    }
+
+    #[test]
+    fn brake_light_minimum_on_time() {
+        let config = LightingConfig {
+            brake_min_on_ms: 1000,
+            ..DEFAULT_LIGHTING
+        };
+        let on: Vec<u64> = (100..1100).step_by(100).collect();
+        assert_eq!(brake_light(config, 1.0), on);
+    }
+
+    #[test]
+    fn brake_light_off_when_accelerating() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        for (i, t) in (0..=1000).step_by(100).enumerate() {
+            lighting.speed(i as f32 * 3.6, t);
+            assert!(!lighting.state().brake);
+        }
+    }
+
+    #[test]
+    fn reverse_light() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        assert!(!lighting.state().reverse);
+        lighting.speed(-0.3, 0);
+        assert!(!lighting.state().reverse);
+        lighting.speed(-0.5, 100);
+        assert!(lighting.state().reverse);
+        assert_eq!(lighting.state().rear_lamp(), (255, 255, 255));
+    }
+
+    fn indicators(lighting: &LightingController) -> (bool, bool) {
+        let state = lighting.state();
+        (state.indicator_left, state.indicator_right)
+    }
+
+    #[test]
+    fn indicator_hysteresis() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        let steps = [
+            (14.9, (false, false)),
+            (15.0, (true, false)),
+            (8.1, (true, false)),
+            (8.0, (false, false)),
+            (-15.0, (false, true)),
+            (-8.1, (false, true)),
+            (-8.0, (false, false)),
+            (20.0, (true, false)),
+            // straight across to the other side
+            (-20.0, (false, true)),
+        ];
+        for (angle, expected) in steps {
+            lighting.steering(angle);
+            assert_eq!(indicators(&lighting), expected, "{}", angle);
+        }
+    }
+
+    #[test]
+    fn blink_phase() {
+        let lighting = LightingController::new(DEFAULT_LIGHTING);
+        assert!(lighting.blink(0));
+        assert!(lighting.blink(332));
+        assert!(!lighting.blink(333));
+        assert!(!lighting.blink(665));
+        assert!(lighting.blink(666));
+    }
+
+    #[test]
+    fn overrides() {
+        let mut lighting = LightingController::new(DEFAULT_LIGHTING);
+        lighting.steering(20.0);
+        assert!(!lighting.state().overridden);
+
+        lighting.set_override(LightOverride::from_raw(2, 3));
+        let state = lighting.state();
+        assert!(state.overridden && state.headlights && !state.night);
+        assert_eq!(indicators(&lighting), (true, true));
+
+        lighting.set_override(LightOverride::from_raw(1, 2));
+        assert!(!lighting.state().headlights);
+        assert_eq!(indicators(&lighting), (false, true));
+
+        // unknown codes fall back to automatic
+        assert!(LightOverride::from_raw(7, 7) == LightOverride::AUTO);
+        lighting.set_override(LightOverride::from_raw(0, 0));
+        assert!(!lighting.state().overridden);
+        assert_eq!(indicators(&lighting), (true, false));
+    }
+
+    #[test]
+    fn led_frame() {
+        let state = LightState {
+            headlights: true,
+            brake: true,
+            reverse: false,
+            indicator_left: true,
+            indicator_right: false,
+            night: true,
+            overridden: false,
+            brightness: 0,
+        };
+        assert_eq!(state.leds(true), 0b0111);
+        assert_eq!(state.leds(false), 0b0011);
+        assert_eq!(state.rear_lamp(), (255, 0, 0));
+        let tail = LightState {
+            brake: false,
+            ..state
+        };
+        assert_eq!(tail.rear_lamp(), (40, 0, 0));
+    }
+}
@@ -0,0 +1,11 @@
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// response sent by this node
+    Publish,
+    /// response sent by another node
+    Subscribe,
+}
+
+pub const MASTER_REQ_ID: u8 = 0x3c;
+pub const SLAVE_RESP_ID: u8 = 0x3d;
@@ -0,0 +1,497 @@
+/// Wildcard NAD addressing every slave.
+pub const NAD_BROADCAST: u8 = 0x7f;
+const NEGATIVE_RESPONSE: u8 = 0x7f;
+
+const PCI_SINGLE: u8 = 0x00;
+const PCI_FIRST: u8 = 0x10;
+const PCI_CONSECUTIVE: u8 = 0x20;
+const PADDING: u8 = 0xff;
+
+/// Longest transport layer message we send or receive.
+pub const MAX_MESSAGE_LEN: usize = 64;
+
+/// Diagnostic transport layer errors.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub enum TpError {
+    /// PCI type not allowed at this point of the message
+    UnexpectedPci,
+    /// consecutive frame with a wrong sequence number
+    Sequence,
+    /// announced length exceeds `MAX_MESSAGE_LEN` or the frame
+    Length,
+}
+
+/// Splits a message into master request PDUs.
+pub struct Segmenter<'a> {
+    nad: u8,
+    data: &'a [u8],
+    offset: usize,
+    sequence: u8,
+}
+
+impl<'a> Segmenter<'a> {
+    /// `data` starts with the service identifier.
+    pub fn new(nad: u8, data: &'a [u8]) -> Result<Self, TpError> {
+        if data.is_empty() || data.len() > MAX_MESSAGE_LEN {
+            return Err(TpError::Length);
+        }
+        Ok(Self {
+            nad,
+            data,
+            offset: 0,
+            sequence: 0,
+        })
+    }
+}
+
+impl Iterator for Segmenter<'_> {
+    type Item = [u8; 8];
+
+    fn next(&mut self) -> Option<[u8; 8]> {
+        let remaining = &self.data[self.offset..];
+        if remaining.is_empty() {
+            return None;
+        }
+
+        let mut pdu = [PADDING; 8];
+        pdu[0] = self.nad;
+        let payload = if self.offset == 0 && remaining.len() <= 6 {
+            pdu[1] = PCI_SINGLE | remaining.len() as u8;
+            &mut pdu[2..]
+        } else if self.offset == 0 {
+            pdu[1] = PCI_FIRST | (self.data.len() >> 8) as u8;
+            pdu[2] = self.data.len() as u8;
+            &mut pdu[3..]
+        } else {
+            self.sequence = (self.sequence + 1) & 0x0f;
+            pdu[1] = PCI_CONSECUTIVE | self.sequence;
+            &mut pdu[2..]
+        };
+
+        let len = payload.len().min(remaining.len());
+        payload[..len].copy_from_slice(&remaining[..len]);
+        self.offset += len;
+        Some(pdu)
+    }
+}
+
+/// Collects slave response PDUs into a message.
+pub struct Reassembler {
+    buffer: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+    expected: usize,
+    sequence: u8,
+}
+
+impl Reassembler {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_MESSAGE_LEN],
+            len: 0,
+            expected: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Feeds one PDU, returns the NAD and the message once it is complete.
+    pub fn feed(&mut self, pdu: &[u8]) -> Result<Option<(u8, &[u8])>, TpError> {
+        if pdu.len() != 8 {
+            return Err(TpError::Length);
+        }
+        let (nad, pci) = (pdu[0], pdu[1]);
+
+        let payload = match pci & 0xf0 {
+            PCI_SINGLE if self.expected == 0 => {
+                let len = (pci & 0x0f) as usize;
+                if len == 0 || len > 6 {
+                    return Err(TpError::Length);
+                }
+                self.expected = len;
+                &pdu[2..2 + len]
+            }
+            PCI_FIRST if self.expected == 0 => {
+                let len = ((pci & 0x0f) as usize) << 8 | pdu[2] as usize;
+                if len <= 6 || len > MAX_MESSAGE_LEN {
+                    return Err(TpError::Length);
+                }
+                self.expected = len;
+                self.sequence = 0;
+                &pdu[3..]
+            }
+            PCI_CONSECUTIVE if self.expected != 0 => {
+                self.sequence = (self.sequence + 1) & 0x0f;
+                if pci & 0x0f != self.sequence {
+                    self.reset();
+                    return Err(TpError::Sequence);
+                }
+                let len = (self.expected - self.len).min(6);
+                &pdu[2..2 + len]
+            }
+            _ => {
+                self.reset();
+                return Err(TpError::UnexpectedPci);
+            }
+        };
+
+        let len = payload.len().min(self.expected - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&payload[..len]);
+        self.len += len;
+
+        if self.len < self.expected {
+            return Ok(None);
+        }
+        let message_len = self.len;
+        self.reset();
+        Ok(Some((nad, &self.buffer[..message_len])))
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.expected = 0;
+        self.sequence = 0;
+    }
+}
+
+/// LIN 2.x node configuration and identification services.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NodeService {
+    /// addressed by the initial NAD of the node
+    AssignNad {
+        nad: u8,
+        supplier_id: u16,
+        function_id: u16,
+        new_nad: u8,
+    },
+    ReadByIdentifier {
+        nad: u8,
+        identifier: u8,
+        supplier_id: u16,
+        function_id: u16,
+    },
+    /// the node takes `new_nad` when `(id[byte] ^ invert) & mask == 0`
+    ConditionalChangeNad {
+        nad: u8,
+        identifier: u8,
+        byte: u8,
+        mask: u8,
+        invert: u8,
+        new_nad: u8,
+    },
+    SaveConfiguration {
+        nad: u8,
+    },
+}
+
+impl NodeService {
+    pub const ASSIGN_NAD: u8 = 0xb0;
+    pub const READ_BY_IDENTIFIER: u8 = 0xb2;
+    pub const CONDITIONAL_CHANGE_NAD: u8 = 0xb3;
+    pub const SAVE_CONFIGURATION: u8 = 0xb6;
+
+    /// Builds a service from its identifier and the five request parameter
+    /// bytes in the order of the LIN specification.
+    pub fn from_request(nad: u8, sid: u8, params: [u8; 5]) -> Option<Self> {
+        let [p1, p2, p3, p4, p5] = params;
+        let word = |lsb: u8, msb: u8| u16::from_le_bytes([lsb, msb]);
+        let service = match sid {
+            Self::ASSIGN_NAD => NodeService::AssignNad {
+                nad,
+                supplier_id: word(p1, p2),
+                function_id: word(p3, p4),
+                new_nad: p5,
+            },
+            Self::READ_BY_IDENTIFIER => NodeService::ReadByIdentifier {
+                nad,
+                identifier: p1,
+                supplier_id: word(p2, p3),
+                function_id: word(p4, p5),
+            },
+            Self::CONDITIONAL_CHANGE_NAD => NodeService::ConditionalChangeNad {
+                nad,
+                identifier: p1,
+                byte: p2,
+                mask: p3,
+                invert: p4,
+                new_nad: p5,
+            },
+            Self::SAVE_CONFIGURATION => NodeService::SaveConfiguration { nad },
+            _ => return None,
+        };
+
+        match service {
+            // 0 is reserved for sleep, 0x7e and up are functional and broadcast
+            NodeService::AssignNad { new_nad, .. }
+            | NodeService::ConditionalChangeNad { new_nad, .. }
+                if new_nad == 0 || new_nad >= 0x7e =>
+            {
+                None
+            }
+            _ => Some(service),
+        }
+    }
+
+    pub fn nad(&self) -> u8 {
+        match *self {
+            NodeService::AssignNad { nad, .. }
+            | NodeService::ReadByIdentifier { nad, .. }
+            | NodeService::ConditionalChangeNad { nad, .. }
+            | NodeService::SaveConfiguration { nad } => nad,
+        }
+    }
+
+    pub fn sid(&self) -> u8 {
+        match self {
+            NodeService::AssignNad { .. } => Self::ASSIGN_NAD,
+            NodeService::ReadByIdentifier { .. } => Self::READ_BY_IDENTIFIER,
+            NodeService::ConditionalChangeNad { .. } => Self::CONDITIONAL_CHANGE_NAD,
+            NodeService::SaveConfiguration { .. } => Self::SAVE_CONFIGURATION,
+        }
+    }
+
+    /// Request message starting with the service identifier.
+    pub fn encode(&self) -> ([u8; 6], usize) {
+        let sid = self.sid();
+        match *self {
+            NodeService::AssignNad {
+                supplier_id,
+                function_id,
+                new_nad,
+                ..
+            } => {
+                let [s0, s1] = supplier_id.to_le_bytes();
+                let [f0, f1] = function_id.to_le_bytes();
+                ([sid, s0, s1, f0, f1, new_nad], 6)
+            }
+            NodeService::ReadByIdentifier {
+                identifier,
+                supplier_id,
+                function_id,
+                ..
+            } => {
+                let [s0, s1] = supplier_id.to_le_bytes();
+                let [f0, f1] = function_id.to_le_bytes();
+                ([sid, identifier, s0, s1, f0, f1], 6)
+            }
+            NodeService::ConditionalChangeNad {
+                identifier,
+                byte,
+                mask,
+                invert,
+                new_nad,
+                ..
+            } => ([sid, identifier, byte, mask, invert, new_nad], 6),
+            NodeService::SaveConfiguration { .. } => ([sid, 0, 0, 0, 0, 0], 1),
+        }
+    }
+}
+
+/// Outcome of a diagnostic transaction, the values are the
+/// `Lin_Diag_Status` codes of the `LIN_DIAG_RESP` CAN message.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub enum DiagStatus {
+    Ok = 0,
+    /// the node rejected the request, `data[0]` holds the error code
+    Negative = 1,
+    Timeout = 2,
+    Transport = 3,
+    /// response to another service
+    Unexpected = 4,
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DiagResponse {
+    /// NAD of the responding node, the requested one when it did not respond
+    pub nad: u8,
+    pub sid: u8,
+    pub status: DiagStatus,
+    /// response parameters after the response service identifier
+    pub data: [u8; 5],
+}
+
+impl DiagResponse {
+    pub fn status(service: &NodeService, status: DiagStatus) -> Self {
+        Self {
+            nad: service.nad(),
+            sid: service.sid(),
+            status,
+            data: [PADDING; 5],
+        }
+    }
+
+    /// Response to `service` from the reassembled slave `message`.
+    pub fn parse(service: &NodeService, nad: u8, message: &[u8]) -> Self {
+        let mut response = Self::status(service, DiagStatus::Ok);
+        response.nad = nad;
+
+        let params = match message {
+            [rsid, params @ ..] if *rsid == service.sid() + 0x40 => params,
+            [NEGATIVE_RESPONSE, sid, code, ..] if *sid == service.sid() => {
+                response.status = DiagStatus::Negative;
+                core::slice::from_ref(code)
+            }
+            _ => {
+                response.status = DiagStatus::Unexpected;
+                &[]
+            }
+        };
+        let len = params.len().min(response.data.len());
+        response.data[..len].copy_from_slice(&params[..len]);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAD: u8 = 0x0a;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len as u8).map(|i| i.wrapping_mul(7)).collect()
+    }
+
+    #[test]
+    fn single_frame() {
+        let pdus: Vec<_> = Segmenter::new(NAD, &[0xb6]).unwrap().collect();
+        assert_eq!(pdus, [[NAD, 0x01, 0xb6, 0xff, 0xff, 0xff, 0xff, 0xff]]);
+
+        let pdus: Vec<_> = Segmenter::new(NAD, &[1, 2, 3, 4, 5, 6]).unwrap().collect();
+        assert_eq!(pdus, [[NAD, 0x06, 1, 2, 3, 4, 5, 6]]);
+    }
+
+    #[test]
+    fn first_and_consecutive_frames() {
+        let data = message(13);
+        let pdus: Vec<_> = Segmenter::new(NAD, &data).unwrap().collect();
+        assert_eq!(
+            pdus,
+            [
+                [NAD, 0x10, 13, data[0], data[1], data[2], data[3], data[4]],
+                [NAD, 0x21, data[5], data[6], data[7], data[8], data[9], data[10]],
+                [NAD, 0x22, data[11], data[12], 0xff, 0xff, 0xff, 0xff],
+            ]
+        );
+    }
+
+    #[test]
+    fn segmenter_length_limits() {
+        assert_eq!(Segmenter::new(NAD, &[]).err(), Some(TpError::Length));
+        assert_eq!(
+            Segmenter::new(NAD, &message(MAX_MESSAGE_LEN + 1)).err(),
+            Some(TpError::Length)
+        );
+        assert_eq!(
+            Segmenter::new(NAD, &message(MAX_MESSAGE_LEN))
+                .unwrap()
+                .count(),
+            11
+        );
+    }
+
+    #[test]
+    fn round_trip_every_length() {
+        for len in 1..=MAX_MESSAGE_LEN {
+            let data = message(len);
+            let mut reassembler = Reassembler::new();
+            let mut result = None;
+            for pdu in Segmenter::new(NAD, &data).unwrap() {
+                assert!(result.is_none(), "message complete early at {len}");
+                result = reassembler
+                    .feed(&pdu)
+                    .unwrap()
+                    .map(|(nad, message)| (nad, message.to_vec()));
+            }
+            assert_eq!(result, Some((NAD, data)));
+        }
+    }
+
+    #[test]
+    fn wrong_sequence_restarts() {
+        let data = message(20);
+        let pdus: Vec<_> = Segmenter::new(NAD, &data).unwrap().collect();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.feed(&pdus[0]), Ok(None));
+        assert_eq!(reassembler.feed(&pdus[2]), Err(TpError::Sequence));
+        // the rest of the broken message is not taken as a new one
+        assert_eq!(reassembler.feed(&pdus[3]), Err(TpError::UnexpectedPci));
+
+        for pdu in &pdus[..3] {
+            assert!(reassembler.feed(pdu).is_ok());
+        }
+        let complete = reassembler.feed(&pdus[3]).unwrap();
+        assert_eq!(complete, Some((NAD, data.as_slice())));
+    }
+
+    #[test]
+    fn unexpected_pci() {
+        let mut reassembler = Reassembler::new();
+        let consecutive = [NAD, 0x21, 1, 2, 3, 4, 5, 6];
+        assert_eq!(reassembler.feed(&consecutive), Err(TpError::UnexpectedPci));
+
+        let first = [NAD, 0x10, 10, 1, 2, 3, 4, 5];
+        assert_eq!(reassembler.feed(&first), Ok(None));
+        let single = [NAD, 0x01, 0xf6, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(reassembler.feed(&single), Err(TpError::UnexpectedPci));
+        // reset by the error
+        assert_eq!(
+            reassembler.feed(&single),
+            Ok(Some((NAD, [0xf6].as_slice())))
+        );
+    }
+
+    #[test]
+    fn reassembler_length_limits() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.feed(&[NAD, 0x01, 0xf6]), Err(TpError::Length));
+        assert_eq!(
+            reassembler.feed(&[NAD, 0x00, 0, 0, 0, 0, 0, 0]),
+            Err(TpError::Length)
+        );
+        assert_eq!(
+            reassembler.feed(&[NAD, 0x07, 0, 0, 0, 0, 0, 0]),
+            Err(TpError::Length)
+        );
+        // first frames are only used for more than 6 bytes
+        assert_eq!(
+            reassembler.feed(&[NAD, 0x10, 6, 0, 0, 0, 0, 0]),
+            Err(TpError::Length)
+        );
+        assert_eq!(
+            reassembler.feed(&[NAD, 0x10, 65, 0, 0, 0, 0, 0]),
+            Err(TpError::Length)
+        );
+        assert_eq!(
+            reassembler.feed(&[NAD, 0x11, 0, 0, 0, 0, 0, 0]),
+            Err(TpError::Length)
+        );
+    }
+
+    #[test]
+    fn reserved_new_nad_is_rejected() {
+        let assign = |new_nad| NodeService::from_request(NAD, 0xb0, [1, 0, 2, 0, new_nad]);
+        assert!(assign(0x20).is_some());
+        assert!(assign(0).is_none());
+        assert!(assign(0x7e).is_none());
+        assert!(NodeService::from_request(NAD, 0xb1, [0; 5]).is_none());
+    }
+
+    #[test]
+    fn parse_responses() {
+        let service = NodeService::SaveConfiguration { nad: NAD };
+        let response = DiagResponse::parse(&service, NAD, &[0xf6]);
+        assert_eq!(response.status, DiagStatus::Ok);
+
+        let response = DiagResponse::parse(&service, NAD, &[0x7f, 0xb6, 0x12]);
+        assert_eq!(response.status, DiagStatus::Negative);
+        assert_eq!(response.data[0], 0x12);
+
+        let response = DiagResponse::parse(&service, NAD, &[0xf2, 1]);
+        assert_eq!(response.status, DiagStatus::Unexpected);
+    }
+}
@@ -0,0 +1,167 @@
+/// Master request putting all slaves to sleep, NAD 0 followed by 0xff.
+pub const GO_TO_SLEEP: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+/// Ignition has to stay off this long before the cluster is put to sleep,
+/// so that voltage drops while cranking do not cycle the bus.
+pub const SLEEP_DELAY_MS: u64 = 3000;
+
+/// Change of the LIN cluster power state to carry out.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub enum PowerAction {
+    GoToSleep,
+    WakeUp,
+}
+
+/// Keeps the LIN cluster asleep while the ignition is off.
+///
+/// A wake-up request from a slave wakes the cluster for at least the sleep
+/// delay before it is put to sleep again.
+pub struct PowerManager {
+    sleep_delay_ms: u64,
+    ignition: bool,
+    sleeping: bool,
+    /// start of the period the ignition has been off while awake
+    idle_since_ms: u64,
+}
+
+impl PowerManager {
+    /// Starts awake, the ignition is assumed on until KL15 says otherwise.
+    pub const fn new(sleep_delay_ms: u64) -> Self {
+        Self {
+            sleep_delay_ms,
+            ignition: true,
+            sleeping: false,
+            idle_since_ms: 0,
+        }
+    }
+
+    pub fn ignition(&mut self, on: bool, now_ms: u64) {
+        if on != self.ignition && !on {
+            self.idle_since_ms = now_ms;
+        }
+        self.ignition = on;
+    }
+
+    /// `wakeup_request` is set when a wake-up pulse was seen on the bus.
+    pub fn update(&mut self, now_ms: u64, wakeup_request: bool) -> Option<PowerAction> {
+        if self.sleeping {
+            if self.ignition || wakeup_request {
+                self.sleeping = false;
+                self.idle_since_ms = now_ms;
+                return Some(PowerAction::WakeUp);
+            }
+        } else if !self.ignition && now_ms.saturating_sub(self.idle_since_ms) >= self.sleep_delay_ms
+        {
+            self.sleeping = true;
+            return Some(PowerAction::GoToSleep);
+        }
+        None
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ignition switched off at `now_ms` and the cluster asleep after the
+    /// sleep delay.
+    fn asleep(now_ms: u64) -> PowerManager {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        power.ignition(false, now_ms);
+        assert_eq!(
+            power.update(now_ms + SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+        power
+    }
+
+    #[test]
+    fn starts_awake() {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        assert!(!power.is_sleeping());
+        assert_eq!(power.update(100_000, false), None);
+        assert_eq!(power.update(100_000, true), None);
+    }
+
+    #[test]
+    fn sleeps_after_delay() {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        power.ignition(false, 1000);
+        assert_eq!(power.update(1000, false), None);
+        assert_eq!(power.update(1000 + SLEEP_DELAY_MS - 1, false), None);
+        assert_eq!(
+            power.update(1000 + SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+        assert!(power.is_sleeping());
+        // sent once
+        assert_eq!(power.update(1000 + SLEEP_DELAY_MS + 10, false), None);
+    }
+
+    #[test]
+    fn ignition_off_again_keeps_delay() {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        power.ignition(false, 0);
+        power.ignition(false, 2000);
+        assert_eq!(
+            power.update(SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+    }
+
+    #[test]
+    fn cranking_drop_keeps_awake() {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        power.ignition(false, 0);
+        power.ignition(true, SLEEP_DELAY_MS - 1);
+        assert_eq!(power.update(SLEEP_DELAY_MS, false), None);
+        assert_eq!(power.update(10 * SLEEP_DELAY_MS, false), None);
+
+        // the next drop starts a new delay
+        power.ignition(false, 20_000);
+        assert_eq!(power.update(20_000 + SLEEP_DELAY_MS - 1, false), None);
+        assert_eq!(
+            power.update(20_000 + SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+    }
+
+    #[test]
+    fn ignition_wakes() {
+        let mut power = asleep(0);
+        assert_eq!(power.update(10_000, false), None);
+        power.ignition(true, 10_000);
+        assert_eq!(power.update(10_000, false), Some(PowerAction::WakeUp));
+        assert!(!power.is_sleeping());
+        assert_eq!(power.update(20_000, false), None);
+    }
+
+    #[test]
+    fn wakeup_request_wakes_for_delay() {
+        let mut power = asleep(0);
+        assert_eq!(power.update(10_000, true), Some(PowerAction::WakeUp));
+        assert_eq!(power.update(10_000 + SLEEP_DELAY_MS - 1, false), None);
+        assert_eq!(
+            power.update(10_000 + SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+    }
+
+    #[test]
+    fn wakeup_request_ignored_while_awake() {
+        let mut power = PowerManager::new(SLEEP_DELAY_MS);
+        power.ignition(false, 0);
+        // does not restart the delay
+        assert_eq!(power.update(2000, true), None);
+        assert_eq!(
+            power.update(SLEEP_DELAY_MS, false),
+            Some(PowerAction::GoToSleep)
+        );
+    }
+}
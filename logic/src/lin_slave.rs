@@ -0,0 +1,353 @@
+use lin_bus::{Frame, PID};
+
+use crate::lin::Direction;
+
+/// Frame handled by this node, `Publish` frames are answered by us.
+pub struct SlaveFrame {
+    pub id: u8,
+    pub len: usize,
+    pub direction: Direction,
+}
+
+/// Input of the responder decoded from the UART.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// break field, received as a framing error
+    Break,
+    Byte(u8),
+    /// the bus was idle longer than the inter byte space
+    Idle,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Idle,
+    Sync,
+    Pid,
+    /// collecting the response of a subscribed frame
+    Receive {
+        frame: usize,
+        received: usize,
+    },
+    /// reading back our own response
+    Echo {
+        sent: usize,
+    },
+}
+
+/// Header decoding and response handling of a LIN slave, fed byte by byte.
+///
+/// Independent of the UART so the frame logic can be driven by a byte stream
+/// simulator on the host.
+pub struct SlaveResponder<const N: usize> {
+    frames: &'static [SlaveFrame; N],
+    data: [[u8; 8]; N],
+    valid: [bool; N],
+    updated: [bool; N],
+    state: State,
+    pid: u8,
+    buffer: [u8; 9],
+    response_len: usize,
+    errors: u16,
+}
+
+impl<const N: usize> SlaveResponder<N> {
+    pub fn new(frames: &'static [SlaveFrame; N]) -> Self {
+        Self {
+            frames,
+            data: [[0; 8]; N],
+            valid: [false; N],
+            updated: [false; N],
+            state: State::Idle,
+            pid: 0,
+            buffer: [0; 9],
+            response_len: 0,
+            errors: 0,
+        }
+    }
+
+    /// Sets the response of a published frame, sent from its next header on.
+    pub fn publish(&mut self, frame: usize, data: &[u8]) {
+        self.data[frame][..data.len()].copy_from_slice(data);
+        self.valid[frame] = true;
+    }
+
+    /// Data received in a subscribed frame since the last call.
+    pub fn take_received(&mut self, frame: usize) -> Option<&[u8]> {
+        let updated = self.updated[frame];
+        self.updated[frame] = false;
+        updated.then_some(&self.data[frame][..self.frames[frame].len])
+    }
+
+    /// Checksum, parity and readback errors since startup.
+    pub fn errors(&self) -> u16 {
+        self.errors
+    }
+
+    /// Processes one event, returns the bytes to transmit when a header of a
+    /// published frame was completed.
+    pub fn feed(&mut self, event: Event) -> Option<&[u8]> {
+        let byte = match event {
+            Event::Break => {
+                self.state = State::Sync;
+                return None;
+            }
+            Event::Idle => {
+                self.state = State::Idle;
+                return None;
+            }
+            Event::Byte(byte) => byte,
+        };
+
+        match self.state {
+            // without LIN mode break detection a break reads as a zero byte
+            State::Idle if byte == 0 => self.state = State::Sync,
+            State::Idle => {}
+            State::Sync if byte == 0x55 => self.state = State::Pid,
+            State::Sync => self.state = State::Idle,
+            State::Pid => return self.header(byte),
+            State::Receive { frame, received } => {
+                self.buffer[received] = byte;
+                let len = self.frames[frame].len;
+                if received < len {
+                    self.state = State::Receive {
+                        frame,
+                        received: received + 1,
+                    };
+                } else {
+                    self.state = State::Idle;
+                    self.complete(frame);
+                }
+            }
+            State::Echo { sent } => {
+                if byte != self.buffer[sent] {
+                    // another node overwrote our response, stop comparing
+                    self.error();
+                    self.state = State::Idle;
+                } else if sent + 1 == self.response_len {
+                    self.state = State::Idle;
+                } else {
+                    self.state = State::Echo { sent: sent + 1 };
+                }
+            }
+        }
+        None
+    }
+
+    fn header(&mut self, pid: u8) -> Option<&[u8]> {
+        self.state = State::Idle;
+        if PID::from_id(pid & 0x3f).get() != pid {
+            self.error();
+            return None;
+        }
+        self.pid = pid;
+
+        let frame = self.frames.iter().position(|f| f.id == pid & 0x3f)?;
+        match self.frames[frame].direction {
+            Direction::Publish if self.valid[frame] => {
+                let len = self.frames[frame].len;
+                let response = Frame::from_data(PID::from_id(pid & 0x3f), &self.data[frame][..len]);
+                let response = response.get_data_with_checksum();
+                self.buffer[..response.len()].copy_from_slice(response);
+                self.response_len = response.len();
+                self.state = State::Echo { sent: 0 };
+                Some(&self.buffer[..self.response_len])
+            }
+            // no data yet, stay silent
+            Direction::Publish => None,
+            Direction::Subscribe => {
+                self.state = State::Receive { frame, received: 0 };
+                None
+            }
+        }
+    }
+
+    fn complete(&mut self, frame: usize) {
+        let len = self.frames[frame].len;
+        let received = Frame::from_data(PID::from_id(self.pid & 0x3f), &self.buffer[..len]);
+        if received.get_checksum() != self.buffer[len] {
+            self.error();
+            return;
+        }
+        self.data[frame][..len].copy_from_slice(&self.buffer[..len]);
+        self.valid[frame] = true;
+        self.updated[frame] = true;
+    }
+
+    fn error(&mut self) {
+        self.errors = self.errors.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lin::MASTER_REQ_ID;
+
+    const PUBLISHED: usize = 0;
+    const SUBSCRIBED: usize = 1;
+    static FRAMES: [SlaveFrame; 2] = [
+        SlaveFrame {
+            id: 0x10,
+            len: 3,
+            direction: Direction::Publish,
+        },
+        SlaveFrame {
+            id: MASTER_REQ_ID,
+            len: 8,
+            direction: Direction::Subscribe,
+        },
+    ];
+
+    /// Feeds a byte stream as the UART would deliver it, returns the
+    /// responses the slave sent.
+    fn simulate(slave: &mut SlaveResponder<2>, events: &[Event]) -> Vec<Vec<u8>> {
+        events
+            .iter()
+            .filter_map(|&event| slave.feed(event).map(<[u8]>::to_vec))
+            .collect()
+    }
+
+    fn header(id: u8) -> [Event; 3] {
+        [
+            Event::Break,
+            Event::Byte(0x55),
+            Event::Byte(PID::from_id(id).get()),
+        ]
+    }
+
+    fn bytes(data: &[u8]) -> Vec<Event> {
+        data.iter().map(|&byte| Event::Byte(byte)).collect()
+    }
+
+    #[test]
+    fn header_with_parity_error_is_ignored() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let pid = PID::from_id(0x10).get() ^ 0x80;
+        let responses = simulate(
+            &mut slave,
+            &[Event::Break, Event::Byte(0x55), Event::Byte(pid)],
+        );
+        assert!(responses.is_empty());
+        assert_eq!(slave.errors(), 1);
+    }
+
+    #[test]
+    fn publish_answers_with_checksum() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let responses = simulate(&mut slave, &header(0x10));
+
+        let expected = Frame::from_data(PID::from_id(0x10), &[1, 2, 3]);
+        assert_eq!(responses, [expected.get_data_with_checksum().to_vec()]);
+
+        // the transceiver echoes the response
+        simulate(&mut slave, &bytes(expected.get_data_with_checksum()));
+        assert_eq!(slave.errors(), 0);
+    }
+
+    #[test]
+    fn publish_detects_overwritten_echo() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let response = simulate(&mut slave, &header(0x10)).remove(0);
+
+        let mut echo = response.clone();
+        echo[1] ^= 0x01;
+        simulate(&mut slave, &bytes(&echo));
+        assert_eq!(slave.errors(), 1);
+
+        // the next header is answered again
+        assert_eq!(simulate(&mut slave, &header(0x10)), [response]);
+    }
+
+    #[test]
+    fn publish_without_data_stays_silent() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        assert!(simulate(&mut slave, &header(0x10)).is_empty());
+        // headers of other nodes are ignored
+        assert!(simulate(&mut slave, &header(0x20)).is_empty());
+        assert_eq!(slave.errors(), 0);
+    }
+
+    #[test]
+    fn subscribe_receives_data() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        let data = [0x0a, 0x01, 0xb6, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let frame = Frame::from_data(PID::from_id(MASTER_REQ_ID), &data);
+        let mut events = header(MASTER_REQ_ID).to_vec();
+        events.extend(bytes(frame.get_data_with_checksum()));
+
+        assert!(simulate(&mut slave, &events).is_empty());
+        assert_eq!(slave.take_received(SUBSCRIBED), Some(data.as_slice()));
+        assert_eq!(slave.take_received(SUBSCRIBED), None);
+        assert_eq!(slave.errors(), 0);
+    }
+
+    #[test]
+    fn subscribe_with_bad_checksum_is_dropped() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        let data = [0x0a, 0x01, 0xb6, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let frame = Frame::from_data(PID::from_id(MASTER_REQ_ID), &data);
+        let mut events = header(MASTER_REQ_ID).to_vec();
+        events.extend(bytes(&data));
+        events.push(Event::Byte(frame.get_checksum() ^ 0xff));
+
+        simulate(&mut slave, &events);
+        assert_eq!(slave.take_received(SUBSCRIBED), None);
+        assert_eq!(slave.errors(), 1);
+    }
+
+    #[test]
+    fn idle_aborts_a_frame() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let pid = PID::from_id(0x10).get();
+        let events = [
+            Event::Break,
+            Event::Byte(0x55),
+            Event::Idle,
+            Event::Byte(pid),
+        ];
+        assert!(simulate(&mut slave, &events).is_empty());
+
+        // a truncated subscribed frame is not taken
+        let mut events = header(MASTER_REQ_ID).to_vec();
+        events.extend(bytes(&[1, 2, 3]));
+        events.push(Event::Idle);
+        events.extend(bytes(&[4, 5, 6, 7, 8, 9]));
+        simulate(&mut slave, &events);
+        assert_eq!(slave.take_received(SUBSCRIBED), None);
+        assert_eq!(slave.errors(), 0);
+    }
+
+    #[test]
+    fn break_resynchronizes() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+
+        // a break in the middle of a subscribed frame starts a new header
+        let mut events = header(MASTER_REQ_ID).to_vec();
+        events.extend(bytes(&[1, 2, 3]));
+        events.extend(header(0x10));
+        assert_eq!(simulate(&mut slave, &events).len(), 1);
+    }
+
+    #[test]
+    fn zero_byte_is_taken_as_break() {
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let pid = PID::from_id(0x10).get();
+        let events = [Event::Byte(0), Event::Byte(0x55), Event::Byte(pid)];
+        assert_eq!(simulate(&mut slave, &events).len(), 1);
+
+        // without the sync byte the header is dropped
+        let mut slave = SlaveResponder::new(&FRAMES);
+        slave.publish(PUBLISHED, &[1, 2, 3]);
+        let events = [Event::Break, Event::Byte(0x54), Event::Byte(pid)];
+        assert!(simulate(&mut slave, &events).is_empty());
+    }
+}
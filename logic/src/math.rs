@@ -0,0 +1,68 @@
+// f32::abs and f32::round are not in core on our toolchain, everything
+// outside of tests goes through these instead
+
+// from here on every f32 is an integer
+const EXACT_LIMIT: f32 = 8_388_608.0;
+
+pub fn abs_f32(x: f32) -> f32 {
+    if x < 0.0 {
+        -x
+    } else {
+        x
+    }
+}
+
+/// Nearest integer, halfway cases away from zero like `f32::round`.
+pub fn round_f32(x: f32) -> f32 {
+    if x.is_nan() || abs_f32(x) >= EXACT_LIMIT {
+        // already integral, infinite or NaN
+        return x;
+    }
+    let truncated = x as i32 as f32;
+    let fraction = x - truncated;
+    if fraction >= 0.5 {
+        truncated + 1.0
+    } else if fraction <= -0.5 {
+        truncated - 1.0
+    } else {
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abs() {
+        for x in [0.0, -0.0, 1.5, -1.5, f32::MAX, f32::MIN, f32::INFINITY] {
+            assert_eq!(abs_f32(x), x.abs());
+        }
+        assert!(abs_f32(f32::NAN).is_nan());
+    }
+
+    #[test]
+    fn round() {
+        let values = [
+            0.0,
+            0.4,
+            0.5,
+            0.49999997,
+            1.5,
+            2.5,
+            -0.5,
+            -1.5,
+            -2.4999,
+            1e7 + 0.5,
+            -7.5e6 - 0.5,
+            8_388_609.0,
+            1e20,
+            -1e20,
+        ];
+        for x in values {
+            assert_eq!(round_f32(x), x.round(), "{}", x);
+        }
+        assert_eq!(round_f32(f32::INFINITY), f32::INFINITY);
+        assert!(round_f32(f32::NAN).is_nan());
+    }
+}
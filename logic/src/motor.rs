@@ -0,0 +1,223 @@
+use crate::{
+    collision::{BrakeAssistConfig, DEFAULT_BRAKE_ASSIST},
+    math::abs_f32,
+};
+
+// below this speed with zero target the controller is considered stopped
+pub const STANDSTILL_KMH: f32 = 0.05;
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+/// Tunables of the motor task.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MotorConfig {
+    pub speed_pid: PidGains,
+    /// km/h per second
+    pub max_acceleration: f32,
+    pub brake_assist: BrakeAssistConfig,
+}
+
+pub const DEFAULT_MOTOR: MotorConfig = MotorConfig {
+    speed_pid: PidGains {
+        kp: 0.08,
+        ki: 0.15,
+        kd: 0.0,
+    },
+    max_acceleration: 4.0,
+    brake_assist: DEFAULT_BRAKE_ASSIST,
+};
+
+/// Hardware independent speed regulator.
+///
+/// The requested speed is first ramped by `max_acceleration` and then tracked
+/// by a PID controller whose output is a normalized throttle in -1.0..=1.0.
+pub struct SpeedController {
+    gains: PidGains,
+    max_acceleration: f32,
+    setpoint: f32,
+    integral: f32,
+    prev_error: Option<f32>,
+}
+
+impl SpeedController {
+    pub fn new(gains: PidGains, max_acceleration: f32) -> Self {
+        Self {
+            gains,
+            max_acceleration,
+            setpoint: 0.0,
+            integral: 0.0,
+            prev_error: None,
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: PidGains) {
+        self.gains = gains;
+    }
+
+    pub fn set_max_acceleration(&mut self, max_acceleration: f32) {
+        self.max_acceleration = max_acceleration;
+    }
+
+    pub fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    /// Clamps the ramped setpoint immediately, bypassing the acceleration limit.
+    pub fn limit_setpoint(&mut self, min: f32, max: f32) {
+        self.setpoint = self.setpoint.clamp(min, max);
+    }
+
+    pub fn reset(&mut self) {
+        self.setpoint = 0.0;
+        self.integral = 0.0;
+        self.prev_error = None;
+    }
+
+    /// Computes the throttle for one control period of `dt` seconds.
+    pub fn update(&mut self, target: f32, measured: f32, dt: f32) -> f32 {
+        let max_step = self.max_acceleration * dt;
+        self.setpoint += (target - self.setpoint).clamp(-max_step, max_step);
+
+        if self.setpoint == 0.0 && target == 0.0 && abs_f32(measured) < STANDSTILL_KMH {
+            self.integral = 0.0;
+            self.prev_error = None;
+            return 0.0;
+        }
+
+        let error = self.setpoint - measured;
+        let derivative = match self.prev_error {
+            Some(prev) if dt > 0.0 => (error - prev) / dt,
+            _ => 0.0,
+        };
+        self.prev_error = Some(error);
+
+        let integral = self.integral + error * dt;
+        let output = self.gains.kp * error + self.gains.ki * integral + self.gains.kd * derivative;
+
+        // anti-windup: keep integrating only while not saturated or when unwinding
+        if abs_f32(output) <= 1.0 || abs_f32(integral) < abs_f32(self.integral) {
+            self.integral = integral;
+        }
+
+        output.clamp(-1.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.02;
+    const P_ONLY: PidGains = PidGains {
+        kp: 0.1,
+        ki: 0.0,
+        kd: 0.0,
+    };
+
+    #[test]
+    fn setpoint_is_ramped_by_max_acceleration() {
+        let mut controller = SpeedController::new(P_ONLY, 4.0);
+        controller.update(10.0, 0.0, DT);
+        assert!((controller.setpoint() - 0.08).abs() < 1e-6);
+
+        for _ in 1..50 {
+            controller.update(10.0, 0.0, DT);
+        }
+        assert!((controller.setpoint() - 4.0).abs() < 1e-3);
+
+        for _ in 0..200 {
+            controller.update(10.0, 0.0, DT);
+        }
+        assert_eq!(controller.setpoint(), 10.0);
+
+        // decelerating is limited the same way
+        controller.update(0.0, 10.0, DT);
+        assert!((controller.setpoint() - 9.92).abs() < 1e-4);
+    }
+
+    #[test]
+    fn limit_setpoint_bypasses_the_ramp() {
+        let mut controller = SpeedController::new(P_ONLY, 4.0);
+        for _ in 0..250 {
+            controller.update(10.0, 10.0, DT);
+        }
+        controller.limit_setpoint(-1.0, 2.0);
+        assert_eq!(controller.setpoint(), 2.0);
+    }
+
+    #[test]
+    fn output_saturates() {
+        let gains = PidGains {
+            kp: 1.0,
+            ki: 0.0,
+            kd: 0.0,
+        };
+        let mut controller = SpeedController::new(gains, 1000.0);
+        assert_eq!(controller.update(20.0, 0.0, DT), 1.0);
+        assert_eq!(controller.update(-20.0, 20.0, DT), -1.0);
+    }
+
+    #[test]
+    fn integral_holds_while_saturated() {
+        let gains = PidGains {
+            kp: 0.08,
+            ki: 0.15,
+            kd: 0.0,
+        };
+        let after = |cycles| {
+            let mut controller = SpeedController::new(gains, 1000.0);
+            for _ in 0..cycles {
+                assert_eq!(controller.update(20.0, 0.0, DT), 1.0);
+            }
+            // only the integral term is left once the speed is reached
+            controller.update(20.0, 20.0, DT)
+        };
+        assert_eq!(after(10), after(1000));
+        assert!(after(1000).abs() < 1e-6);
+    }
+
+    #[test]
+    fn integral_unwinds_while_saturated() {
+        let gains = PidGains {
+            kp: 0.08,
+            ki: 0.15,
+            kd: 0.0,
+        };
+        let mut controller = SpeedController::new(gains, 1000.0);
+        // build up a positive integral without saturating
+        for _ in 0..30 {
+            assert!(controller.update(5.0, 0.0, DT) < 1.0);
+        }
+        let wound = controller.update(5.0, 5.0, DT);
+        assert!(wound > 0.0);
+
+        // a large negative error saturates but still reduces the integral
+        for _ in 0..10 {
+            assert_eq!(controller.update(5.0, 30.0, DT), -1.0);
+        }
+        assert!(controller.update(5.0, 5.0, DT) < wound);
+    }
+
+    #[test]
+    fn stopped_at_zero_target() {
+        let gains = PidGains {
+            kp: 0.08,
+            ki: 0.15,
+            kd: 0.0,
+        };
+        let mut controller = SpeedController::new(gains, 1000.0);
+        for _ in 0..50 {
+            controller.update(5.0, 0.0, DT);
+        }
+        assert_eq!(controller.update(0.0, 0.01, DT), 0.0);
+        // the integral was cleared
+        assert!(controller.update(1.0, 1.0, DT).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,425 @@
+use crate::{
+    config::{Config, DEFAULT_CONFIG},
+    filter::FilterConfig,
+    math::round_f32,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Access {
+    /// reported only, changed by other means
+    ReadOnly,
+    ReadWrite,
+    /// safety related, only written while the car stands still
+    Standstill,
+}
+
+/// The values are the `Param_Resp_Status` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParamError {
+    UnknownCommand = 1,
+    UnknownParam = 2,
+    ReadOnly = 3,
+    OutOfRange = 4,
+    /// in range, but contradicting another parameter
+    Inconsistent = 5,
+    Moving = 6,
+    Flash = 7,
+}
+
+/// Tuning parameter exchanged as a raw integer, the physical value is the
+/// raw value times `scale`.
+#[derive(Copy, Clone)]
+pub struct Param {
+    pub name: &'static str,
+    pub access: Access,
+    pub scale: f32,
+    /// raw limits, inclusive
+    pub min: i32,
+    pub max: i32,
+    get: fn(&Config) -> f32,
+    set: fn(&mut Config, f32),
+}
+
+impl Param {
+    pub fn read(&self, config: &Config) -> i32 {
+        round_f32((self.get)(config) / self.scale) as i32
+    }
+
+    /// Range checked write, the access rights are up to the caller.
+    pub fn write(&self, config: &mut Config, raw: i32) -> Result<(), ParamError> {
+        if !(self.min..=self.max).contains(&raw) {
+            return Err(ParamError::OutOfRange);
+        }
+        (self.set)(config, raw as f32 * self.scale);
+        Ok(())
+    }
+}
+
+fn read_only(_: &mut Config, _: f32) {}
+
+fn flag(value: bool) -> f32 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Parameter table, the index is the `Param_Index` on CAN and selects the
+/// flash key, so entries are only ever appended.
+#[rustfmt::skip]
+pub const PARAMS: [Param; 22] = [
+    Param { name: "speed_kp", access: Access::ReadWrite, scale: 0.001, min: 0, max: 2000,
+        get: |c| c.motor.speed_pid.kp, set: |c, v| c.motor.speed_pid.kp = v },
+    Param { name: "speed_ki", access: Access::ReadWrite, scale: 0.001, min: 0, max: 2000,
+        get: |c| c.motor.speed_pid.ki, set: |c, v| c.motor.speed_pid.ki = v },
+    Param { name: "speed_kd", access: Access::ReadWrite, scale: 0.001, min: 0, max: 2000,
+        get: |c| c.motor.speed_pid.kd, set: |c, v| c.motor.speed_pid.kd = v },
+    // km/h per second
+    Param { name: "max_acceleration", access: Access::ReadWrite, scale: 0.1, min: 1, max: 200,
+        get: |c| c.motor.max_acceleration, set: |c, v| c.motor.max_acceleration = v },
+    Param { name: "brake_stop_mm", access: Access::Standstill, scale: 1.0, min: 50, max: 2000,
+        get: |c| c.motor.brake_assist.stop_distance_mm as f32,
+        set: |c, v| c.motor.brake_assist.stop_distance_mm = v as u64 },
+    Param { name: "brake_release_mm", access: Access::Standstill, scale: 1.0, min: 50, max: 3000,
+        get: |c| c.motor.brake_assist.release_distance_mm as f32,
+        set: |c, v| c.motor.brake_assist.release_distance_mm = v as u64 },
+    Param { name: "brake_ttc", access: Access::Standstill, scale: 0.001, min: 100, max: 5000,
+        get: |c| c.motor.brake_assist.brake_ttc_s, set: |c, v| c.motor.brake_assist.brake_ttc_s = v },
+    Param { name: "release_ttc", access: Access::Standstill, scale: 0.001, min: 100, max: 10000,
+        get: |c| c.motor.brake_assist.release_ttc_s,
+        set: |c, v| c.motor.brake_assist.release_ttc_s = v },
+    Param { name: "unknown_limit_kmh", access: Access::Standstill, scale: 0.1, min: 0, max: 100,
+        get: |c| c.motor.brake_assist.unknown_limit_kmh,
+        set: |c, v| c.motor.brake_assist.unknown_limit_kmh = v },
+    // deg/s
+    Param { name: "steer_max_velocity", access: Access::ReadWrite, scale: 1.0, min: 10, max: 1000,
+        get: |c| c.steering_limits.max_velocity, set: |c, v| c.steering_limits.max_velocity = v },
+    // deg/s^2
+    Param { name: "steer_max_acceleration", access: Access::ReadWrite, scale: 1.0, min: 10, max: 10000,
+        get: |c| c.steering_limits.max_acceleration,
+        set: |c, v| c.steering_limits.max_acceleration = v },
+    Param { name: "steer_full_angle_kmh", access: Access::ReadWrite, scale: 0.1, min: 0, max: 300,
+        get: |c| c.steering_limits.full_angle_below_kmh,
+        set: |c, v| c.steering_limits.full_angle_below_kmh = v },
+    Param { name: "steer_high_speed_kmh", access: Access::ReadWrite, scale: 0.1, min: 1, max: 300,
+        get: |c| c.steering_limits.high_speed_kmh, set: |c, v| c.steering_limits.high_speed_kmh = v },
+    Param { name: "steer_high_speed_deg", access: Access::ReadWrite, scale: 0.1, min: 0, max: 450,
+        get: |c| c.steering_limits.high_speed_angle_deg,
+        set: |c, v| c.steering_limits.high_speed_angle_deg = v },
+    Param { name: "us_median", access: Access::ReadWrite, scale: 1.0, min: 0, max: 1,
+        get: |c| flag(c.filter.median), set: |c, v| c.filter.median = v != 0.0 },
+    Param { name: "us_outlier", access: Access::ReadWrite, scale: 1.0, min: 0, max: 1,
+        get: |c| flag(c.filter.outlier_rejection), set: |c, v| c.filter.outlier_rejection = v != 0.0 },
+    Param { name: "us_ema", access: Access::ReadWrite, scale: 1.0, min: 0, max: 1,
+        get: |c| flag(c.filter.exponential), set: |c, v| c.filter.exponential = v != 0.0 },
+    Param { name: "us_moving_average", access: Access::ReadWrite, scale: 1.0, min: 0, max: 1,
+        get: |c| flag(c.filter.moving_average), set: |c, v| c.filter.moving_average = v != 0.0 },
+    Param { name: "us_ema_alpha", access: Access::ReadWrite, scale: 0.01, min: 1, max: 100,
+        get: |c| c.filter.alpha, set: |c, v| c.filter.alpha = v },
+    // mm/s
+    Param { name: "us_max_rate", access: Access::ReadWrite, scale: 1.0, min: 100, max: 20000,
+        get: |c| c.filter.max_rate_mm_per_s as f32, set: |c, v| c.filter.max_rate_mm_per_s = v as u64 },
+    Param { name: "ticks_per_cm", access: Access::ReadOnly, scale: 0.01, min: 0, max: i32::MAX,
+        get: |c| c.ticks_per_cm, set: read_only },
+    Param { name: "can_bitrate", access: Access::ReadOnly, scale: 1.0, min: 0, max: i32::MAX,
+        get: |c| c.can_bitrate as f32, set: read_only },
+];
+
+/// Request as received, decoded by the parameter task so that invalid
+/// commands are answered as well.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ParamRequest {
+    pub command: u8,
+    pub index: u8,
+    pub value: i32,
+}
+
+/// The values are the `Param_Cmd` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParamCommand {
+    Read(u8),
+    Write(u8, i32),
+    Save,
+    /// restores the compiled-in values of every writable parameter
+    Defaults,
+}
+
+impl ParamCommand {
+    pub fn from_request(request: &ParamRequest) -> Option<Self> {
+        match request.command {
+            0 => Some(ParamCommand::Read(request.index)),
+            1 => Some(ParamCommand::Write(request.index, request.value)),
+            2 => Some(ParamCommand::Save),
+            3 => Some(ParamCommand::Defaults),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ParamResponse {
+    pub command: u8,
+    pub index: u8,
+    pub error: Option<ParamError>,
+    pub value: i32,
+}
+
+/// Applies a command to `config`, returning the raw value to report. A
+/// rejected command leaves `config` unchanged, an accepted save is up to the
+/// caller.
+pub fn apply(config: &mut Config, command: ParamCommand, moving: bool) -> Result<i32, ParamError> {
+    match command {
+        ParamCommand::Read(index) => {
+            let param = PARAMS.get(index as usize).ok_or(ParamError::UnknownParam)?;
+            Ok(param.read(config))
+        }
+        ParamCommand::Write(index, raw) => {
+            let param = PARAMS.get(index as usize).ok_or(ParamError::UnknownParam)?;
+            match param.access {
+                Access::ReadOnly => return Err(ParamError::ReadOnly),
+                Access::Standstill if moving => return Err(ParamError::Moving),
+                Access::ReadWrite | Access::Standstill => {}
+            }
+            let mut changed = *config;
+            param.write(&mut changed, raw)?;
+            if !changed.is_consistent() {
+                return Err(ParamError::Inconsistent);
+            }
+            *config = changed;
+            Ok(param.read(config))
+        }
+        // writing the flash stalls the control tasks
+        ParamCommand::Defaults | ParamCommand::Save if moving => Err(ParamError::Moving),
+        ParamCommand::Defaults => {
+            config.motor = DEFAULT_CONFIG.motor;
+            config.steering_limits = DEFAULT_CONFIG.steering_limits;
+            config.filter = DEFAULT_CONFIG.filter;
+            Ok(0)
+        }
+        ParamCommand::Save => Ok(0),
+    }
+}
+
+/// Writes the ultrasound filter parameters of `filter`, either all or none
+/// of them take effect.
+pub fn apply_filter(
+    config: &mut Config,
+    filter: FilterConfig,
+    moving: bool,
+) -> Result<(), ParamError> {
+    let mut requested = *config;
+    requested.filter = filter;
+    let mut changed = *config;
+    for (index, param) in PARAMS.iter().enumerate() {
+        if param.name.starts_with("us_") {
+            let raw = param.read(&requested);
+            apply(&mut changed, ParamCommand::Write(index as u8, raw), moving)?;
+        }
+    }
+    *config = changed;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(name: &str) -> u8 {
+        PARAMS.iter().position(|param| param.name == name).unwrap() as u8
+    }
+
+    fn raw_values(config: &Config) -> Vec<i32> {
+        PARAMS.iter().map(|param| param.read(config)).collect()
+    }
+
+    #[test]
+    fn defaults_in_range() {
+        for param in &PARAMS {
+            let raw = param.read(&DEFAULT_CONFIG);
+            assert!((param.min..=param.max).contains(&raw), "{}", param.name);
+        }
+        for (i, param) in PARAMS.iter().enumerate() {
+            assert!(PARAMS[..i].iter().all(|p| p.name != param.name));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for param in PARAMS.iter().filter(|p| p.access != Access::ReadOnly) {
+            for raw in [param.min, (param.min + param.max) / 2, param.max] {
+                let mut config = DEFAULT_CONFIG;
+                param.write(&mut config, raw).unwrap();
+                assert_eq!(param.read(&config), raw, "{}", param.name);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        for param in PARAMS.iter().filter(|p| p.access != Access::ReadOnly) {
+            for raw in [param.min - 1, param.max + 1] {
+                let mut config = DEFAULT_CONFIG;
+                assert_eq!(param.write(&mut config, raw), Err(ParamError::OutOfRange));
+                assert_eq!(raw_values(&config), raw_values(&DEFAULT_CONFIG));
+            }
+        }
+    }
+
+    #[test]
+    fn read_rounds_to_nearest() {
+        let param = &PARAMS[index("speed_kp") as usize];
+        let mut config = DEFAULT_CONFIG;
+        for (kp, raw) in [(0.0024, 2), (0.0026, 3), (0.0696, 70), (1.9996, 2000)] {
+            config.motor.speed_pid.kp = kp;
+            assert_eq!(param.read(&config), raw, "{}", kp);
+        }
+        // steps of 0.1 are not exact in f32
+        let param = &PARAMS[index("max_acceleration") as usize];
+        for raw in 1..=200 {
+            param.write(&mut config, raw).unwrap();
+            assert_eq!(param.read(&config), raw);
+        }
+    }
+
+    #[test]
+    fn command_codes() {
+        let request = |command| ParamRequest {
+            command,
+            index: 3,
+            value: -7,
+        };
+        assert_eq!(
+            ParamCommand::from_request(&request(0)),
+            Some(ParamCommand::Read(3))
+        );
+        assert_eq!(
+            ParamCommand::from_request(&request(1)),
+            Some(ParamCommand::Write(3, -7))
+        );
+        assert_eq!(
+            ParamCommand::from_request(&request(2)),
+            Some(ParamCommand::Save)
+        );
+        assert_eq!(
+            ParamCommand::from_request(&request(3)),
+            Some(ParamCommand::Defaults)
+        );
+        assert_eq!(ParamCommand::from_request(&request(4)), None);
+    }
+
+    #[test]
+    fn access_rights() {
+        let mut config = DEFAULT_CONFIG;
+        let unknown = PARAMS.len() as u8;
+        assert_eq!(
+            apply(&mut config, ParamCommand::Read(unknown), false),
+            Err(ParamError::UnknownParam)
+        );
+        assert_eq!(
+            apply(&mut config, ParamCommand::Write(unknown, 0), false),
+            Err(ParamError::UnknownParam)
+        );
+        assert_eq!(
+            apply(
+                &mut config,
+                ParamCommand::Write(index("ticks_per_cm"), 1),
+                false
+            ),
+            Err(ParamError::ReadOnly)
+        );
+
+        let stop = index("brake_stop_mm");
+        assert_eq!(
+            apply(&mut config, ParamCommand::Write(stop, 300), true),
+            Err(ParamError::Moving)
+        );
+        assert_eq!(
+            apply(&mut config, ParamCommand::Write(stop, 300), false),
+            Ok(300)
+        );
+        assert_eq!(config.motor.brake_assist.stop_distance_mm, 300);
+
+        // tuning while driving is allowed
+        let kp = index("speed_kp");
+        assert_eq!(
+            apply(&mut config, ParamCommand::Write(kp, 150), true),
+            Ok(150)
+        );
+        assert_eq!(apply(&mut config, ParamCommand::Read(kp), true), Ok(150));
+    }
+
+    #[test]
+    fn inconsistent_write_rejected() {
+        let mut config = DEFAULT_CONFIG;
+        assert_eq!(
+            apply(
+                &mut config,
+                ParamCommand::Write(index("brake_release_mm"), 50),
+                false
+            ),
+            Err(ParamError::Inconsistent)
+        );
+        assert_eq!(raw_values(&config), raw_values(&DEFAULT_CONFIG));
+    }
+
+    #[test]
+    fn defaults_and_save() {
+        let mut config = DEFAULT_CONFIG;
+        apply(
+            &mut config,
+            ParamCommand::Write(index("speed_kp"), 7),
+            false,
+        )
+        .unwrap();
+        apply(
+            &mut config,
+            ParamCommand::Write(index("us_median"), 0),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            apply(&mut config, ParamCommand::Defaults, true),
+            Err(ParamError::Moving)
+        );
+        assert_eq!(
+            apply(&mut config, ParamCommand::Save, true),
+            Err(ParamError::Moving)
+        );
+        assert_eq!(apply(&mut config, ParamCommand::Save, false), Ok(0));
+        assert_eq!(apply(&mut config, ParamCommand::Defaults, false), Ok(0));
+        assert_eq!(raw_values(&config), raw_values(&DEFAULT_CONFIG));
+    }
+
+    #[test]
+    fn filter_all_or_nothing() {
+        let mut config = DEFAULT_CONFIG;
+        let filter = FilterConfig {
+            median: false,
+            moving_average: true,
+            alpha: 0.25,
+            ..DEFAULT_CONFIG.filter
+        };
+        assert_eq!(apply_filter(&mut config, filter, true), Ok(()));
+        assert!(config.filter == filter);
+
+        let invalid = FilterConfig {
+            median: true,
+            alpha: 0.0,
+            ..filter
+        };
+        assert_eq!(
+            apply_filter(&mut config, invalid, false),
+            Err(ParamError::OutOfRange)
+        );
+        assert!(config.filter == filter);
+    }
+}
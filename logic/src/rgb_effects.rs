@@ -0,0 +1,218 @@
+use crate::color_transition::{dim, gamma, Color, ColorTransition, Interpolation};
+
+const RAINBOW: [Color; 3] = [(255, 0, 0), (0, 255, 0), (0, 0, 255)];
+const AMBER: Color = (255, 120, 0);
+
+const DEFAULT_BREATHE_MS: u32 = 3000;
+const DEFAULT_BLINK_MS: u32 = 1000;
+const DEFAULT_RAINBOW_MS: u32 = 6000;
+const DEFAULT_HAZARD_MS: u32 = 666;
+
+// status codes flash `code` times, then pause
+const CODE_FLASH_MS: u64 = 250;
+const CODE_PAUSE_MS: u64 = 1000;
+
+/// Effect rendered on the RGB LED, colors are given in perceived brightness
+/// and gamma corrected on output.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(Debug))]
+pub enum Effect {
+    Solid(Color),
+    /// fades in and out once per period
+    Breathe {
+        color: Color,
+        period_ms: u32,
+    },
+    Blink {
+        color: Color,
+        period_ms: u32,
+    },
+    /// one turn around the color wheel per period
+    Rainbow {
+        period_ms: u32,
+    },
+    Hazard {
+        period_ms: u32,
+    },
+    StatusCode {
+        color: Color,
+        code: u8,
+    },
+}
+
+impl Effect {
+    /// Decodes `RGB_EFFECT`, `None` hands the LED back to the lighting
+    /// controller. A period of 0 selects the default of the effect.
+    pub fn from_request(effect: u8, color: Color, period_ms: u16, param: u8) -> Option<Self> {
+        let period = |default| match period_ms {
+            0 => default,
+            period => period as u32,
+        };
+        match effect {
+            1 => Some(Effect::Solid(color)),
+            2 => Some(Effect::Breathe {
+                color,
+                period_ms: period(DEFAULT_BREATHE_MS),
+            }),
+            3 => Some(Effect::Blink {
+                color,
+                period_ms: period(DEFAULT_BLINK_MS),
+            }),
+            4 => Some(Effect::Rainbow {
+                period_ms: period(DEFAULT_RAINBOW_MS),
+            }),
+            5 => Some(Effect::Hazard {
+                period_ms: period(DEFAULT_HAZARD_MS),
+            }),
+            6 => Some(Effect::StatusCode {
+                color,
+                code: param.max(1),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Color to output `elapsed_ms` after the effect was started.
+    pub fn render(&self, elapsed_ms: u64) -> Color {
+        let color = match *self {
+            Effect::Solid(color) => color,
+            Effect::Breathe { color, period_ms } => dim(color, breathe(elapsed_ms, period_ms)),
+            Effect::Blink { color, period_ms } => flash(color, elapsed_ms, period_ms),
+            Effect::Rainbow { period_ms } => {
+                ColorTransition::new(&RAINBOW, period_ms / 3, Interpolation::Hsv).at(elapsed_ms)
+            }
+            Effect::Hazard { period_ms } => flash(AMBER, elapsed_ms, period_ms),
+            Effect::StatusCode { color, code } => {
+                let cycle = code as u64 * 2 * CODE_FLASH_MS + CODE_PAUSE_MS;
+                let position = elapsed_ms % cycle;
+                let on = position < code as u64 * 2 * CODE_FLASH_MS
+                    && (position / CODE_FLASH_MS) % 2 == 0;
+                if on {
+                    color
+                } else {
+                    (0, 0, 0)
+                }
+            }
+        };
+        gamma(color)
+    }
+}
+
+fn flash(color: Color, elapsed_ms: u64, period_ms: u32) -> Color {
+    let period_ms = period_ms.max(2) as u64;
+    if elapsed_ms % period_ms < period_ms / 2 {
+        color
+    } else {
+        (0, 0, 0)
+    }
+}
+
+/// Brightness of a breathing cycle, a smoothstep up and down so the LED
+/// lingers at the ends instead of bouncing.
+fn breathe(elapsed_ms: u64, period_ms: u32) -> u8 {
+    let half = (period_ms / 2).max(1) as u64;
+    let position = elapsed_ms % (2 * half);
+    let ramp = if position < half {
+        position
+    } else {
+        2 * half - position
+    };
+    // 3t^2 - 2t^3 in fixed point, t = ramp / half
+    let t = (ramp * 255 / half) as u32;
+    ((3 * t * t * 255 - 2 * t * t * t) / (255 * 255)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Color = (255, 255, 255);
+    const OFF: Color = (0, 0, 0);
+
+    #[test]
+    fn breathe_rises_and_falls() {
+        assert_eq!(breathe(0, 2000), 0);
+        assert_eq!(breathe(1000, 2000), 255);
+        assert_eq!(breathe(2000, 2000), 0);
+        assert_eq!(breathe(500, 2000), breathe(1500, 2000));
+
+        let rising: Vec<_> = (0..=1000).step_by(50).map(|t| breathe(t, 2000)).collect();
+        assert!(rising.windows(2).all(|pair| pair[0] <= pair[1]));
+        // smoothstep lingers at the ends
+        assert!(breathe(100, 2000) < 20);
+        assert!(breathe(900, 2000) > 235);
+    }
+
+    #[test]
+    fn breathe_with_zero_period() {
+        assert_eq!(breathe(1234, 0), 0);
+    }
+
+    #[test]
+    fn blink_is_on_for_the_first_half() {
+        let effect = Effect::Blink {
+            color: WHITE,
+            period_ms: 1000,
+        };
+        assert_eq!(effect.render(0), WHITE);
+        assert_eq!(effect.render(499), WHITE);
+        assert_eq!(effect.render(500), OFF);
+        assert_eq!(effect.render(1000), WHITE);
+    }
+
+    #[test]
+    fn status_code_timing() {
+        let effect = Effect::StatusCode {
+            color: WHITE,
+            code: 2,
+        };
+        let expected = [
+            (0, WHITE),
+            (249, WHITE),
+            (250, OFF),
+            (500, WHITE),
+            (749, WHITE),
+            (750, OFF),
+            (1000, OFF),
+            (1999, OFF),
+            // next cycle
+            (2000, WHITE),
+            (2250, OFF),
+        ];
+        for (elapsed_ms, color) in expected {
+            assert_eq!(effect.render(elapsed_ms), color, "at {elapsed_ms} ms");
+        }
+    }
+
+    #[test]
+    fn output_is_gamma_corrected() {
+        let effect = Effect::Solid((128, 0, 255));
+        assert_eq!(effect.render(0), gamma((128, 0, 255)));
+        assert!(effect.render(0).0 < 128);
+    }
+
+    #[test]
+    fn request_defaults() {
+        assert_eq!(
+            Effect::from_request(2, WHITE, 0, 0),
+            Some(Effect::Breathe {
+                color: WHITE,
+                period_ms: DEFAULT_BREATHE_MS
+            })
+        );
+        assert_eq!(
+            Effect::from_request(5, WHITE, 400, 0),
+            Some(Effect::Hazard { period_ms: 400 })
+        );
+        assert_eq!(
+            Effect::from_request(6, WHITE, 0, 0),
+            Some(Effect::StatusCode {
+                color: WHITE,
+                code: 1
+            })
+        );
+        assert_eq!(Effect::from_request(0, WHITE, 0, 0), None);
+        assert_eq!(Effect::from_request(7, WHITE, 0, 0), None);
+    }
+}
@@ -0,0 +1,206 @@
+pub const MAX_CALIBRATION_POINTS: usize = 8;
+
+/// Pulse width measured for a wheel angle, positive angles steer left.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalibrationPoint {
+    pub angle_deg: f32,
+    pub pulse_us: f32,
+}
+
+/// Maps wheel angles to pulse widths.
+///
+/// The steering linkage is not linear, so the pulse width is interpolated
+/// between measured points. The limits may differ left and right since the
+/// end stops are rarely symmetric, the trim moves the center.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ServoCalibration {
+    points: [CalibrationPoint; MAX_CALIBRATION_POINTS],
+    len: usize,
+    /// added to every requested angle
+    pub trim_deg: f32,
+    /// right limit, negative
+    pub min_angle_deg: f32,
+    /// left limit, positive
+    pub max_angle_deg: f32,
+}
+
+impl ServoCalibration {
+    /// `points` must be sorted by angle, the limits default to the outer
+    /// points. `None` for less than two or more than `MAX_CALIBRATION_POINTS`
+    /// points or when they are not sorted.
+    pub fn new(points: &[CalibrationPoint]) -> Option<Self> {
+        if points.len() < 2 || points.len() > MAX_CALIBRATION_POINTS {
+            return None;
+        }
+        if !points.windows(2).all(|w| w[0].angle_deg < w[1].angle_deg) {
+            return None;
+        }
+
+        let mut table = [CalibrationPoint {
+            angle_deg: 0.0,
+            pulse_us: 0.0,
+        }; MAX_CALIBRATION_POINTS];
+        table[..points.len()].copy_from_slice(points);
+        Some(Self {
+            points: table,
+            len: points.len(),
+            trim_deg: 0.0,
+            min_angle_deg: points[0].angle_deg,
+            max_angle_deg: points[points.len() - 1].angle_deg,
+        })
+    }
+
+    pub fn points(&self) -> &[CalibrationPoint] {
+        &self.points[..self.len]
+    }
+
+    /// Angle actually steered for a request, after trim and limits.
+    pub fn limit(&self, angle_deg: f32) -> f32 {
+        (angle_deg + self.trim_deg).clamp(self.min_angle_deg, self.max_angle_deg)
+    }
+
+    pub fn pulse_us(&self, angle_deg: f32) -> f32 {
+        let points = self.points();
+        let angle_deg = self
+            .limit(angle_deg)
+            .clamp(points[0].angle_deg, points[self.len - 1].angle_deg);
+
+        let upper = points[1..self.len - 1]
+            .iter()
+            .position(|p| angle_deg < p.angle_deg)
+            .map_or(self.len - 1, |i| i + 1);
+        let (a, b) = (points[upper - 1], points[upper]);
+        let fraction = (angle_deg - a.angle_deg) / (b.angle_deg - a.angle_deg);
+        a.pulse_us + fraction * (b.pulse_us - a.pulse_us)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(angle_deg: f32, pulse_us: f32) -> CalibrationPoint {
+        CalibrationPoint {
+            angle_deg,
+            pulse_us,
+        }
+    }
+
+    // non-linear linkage with more travel to the left
+    fn calibration() -> ServoCalibration {
+        ServoCalibration::new(&[
+            point(-30.0, 1100.0),
+            point(-10.0, 1350.0),
+            point(0.0, 1500.0),
+            point(20.0, 1700.0),
+            point(40.0, 1950.0),
+        ])
+        .unwrap()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_points() {
+        assert_eq!(ServoCalibration::new(&[]), None);
+        assert_eq!(ServoCalibration::new(&[point(0.0, 1500.0)]), None);
+        assert_eq!(ServoCalibration::new(&[point(0.0, 1500.0); 9]), None);
+        // unsorted and duplicate angles
+        assert_eq!(
+            ServoCalibration::new(&[point(10.0, 1600.0), point(-10.0, 1400.0)]),
+            None
+        );
+        assert_eq!(
+            ServoCalibration::new(&[point(0.0, 1500.0), point(0.0, 1600.0)]),
+            None
+        );
+        assert_eq!(
+            ServoCalibration::new(&[point(f32::NAN, 1500.0), point(10.0, 1600.0)]),
+            None
+        );
+    }
+
+    #[test]
+    fn limits_default_to_outer_points() {
+        let calibration = calibration();
+        assert_eq!(calibration.points().len(), 5);
+        assert_close(calibration.min_angle_deg, -30.0);
+        assert_close(calibration.max_angle_deg, 40.0);
+    }
+
+    #[test]
+    fn hits_calibration_points() {
+        let calibration = calibration();
+        for p in calibration.points() {
+            assert_close(calibration.pulse_us(p.angle_deg), p.pulse_us);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = calibration();
+        assert_close(calibration.pulse_us(-20.0), 1225.0);
+        assert_close(calibration.pulse_us(-5.0), 1425.0);
+        assert_close(calibration.pulse_us(10.0), 1600.0);
+        assert_close(calibration.pulse_us(0.5), 1505.0);
+        assert_close(calibration.pulse_us(30.0), 1825.0);
+    }
+
+    #[test]
+    fn monotonic() {
+        let calibration = calibration();
+        let mut last = 0.0;
+        for angle in -40..=50 {
+            let pulse = calibration.pulse_us(angle as f32);
+            assert!(pulse >= last);
+            last = pulse;
+        }
+    }
+
+    #[test]
+    fn asymmetric_limits() {
+        let calibration = calibration();
+        assert_close(calibration.limit(45.0), 40.0);
+        assert_close(calibration.limit(-45.0), -30.0);
+        assert_close(calibration.pulse_us(90.0), 1950.0);
+        assert_close(calibration.pulse_us(-90.0), 1100.0);
+
+        // limits inside the measured range
+        let mut calibration = calibration;
+        calibration.min_angle_deg = -20.0;
+        calibration.max_angle_deg = 25.0;
+        assert_close(calibration.pulse_us(-45.0), 1225.0);
+        assert_close(calibration.pulse_us(45.0), 1762.5);
+    }
+
+    #[test]
+    fn trim_moves_center() {
+        let mut calibration = calibration();
+        calibration.trim_deg = 2.0;
+        assert_close(calibration.limit(0.0), 2.0);
+        assert_close(calibration.pulse_us(0.0), 1520.0);
+        // applied before the limits
+        assert_close(calibration.limit(39.0), 40.0);
+        assert_close(calibration.pulse_us(-32.0), 1100.0);
+        assert_close(calibration.pulse_us(-31.0), 1112.5);
+    }
+
+    #[test]
+    fn two_points_linear() {
+        let calibration =
+            ServoCalibration::new(&[point(-45.0, 1000.0), point(45.0, 2000.0)]).unwrap();
+        assert_close(calibration.pulse_us(0.0), 1500.0);
+        assert_close(calibration.pulse_us(22.5), 1750.0);
+        assert_close(calibration.pulse_us(-45.0), 1000.0);
+    }
+}
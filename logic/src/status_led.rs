@@ -0,0 +1,214 @@
+/// Condition shown on the status LED, lower values take priority.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Status {
+    CanBusOff = 0,
+    FailsafeActive = 1,
+    Kl15Low = 2,
+    LinFault = 3,
+    /// the last reset was caused by the watchdog, latched until power cycle
+    WatchdogReset = 4,
+}
+
+impl Status {
+    pub const ALL: [Status; 5] = [
+        Status::CanBusOff,
+        Status::FailsafeActive,
+        Status::Kl15Low,
+        Status::LinFault,
+        Status::WatchdogReset,
+    ];
+
+    pub const fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Blink code, the number of pulses tells the conditions apart.
+    pub const fn pattern(self) -> Pattern {
+        Pattern::code(self as u8 + 2)
+    }
+}
+
+/// `pulses` flashes followed by a pause, repeated.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pattern {
+    pub pulses: u8,
+    pub on_ms: u64,
+    pub off_ms: u64,
+    pub pause_ms: u64,
+}
+
+impl Pattern {
+    /// short flash once a second while nothing is wrong
+    pub const HEARTBEAT: Pattern = Pattern {
+        pulses: 1,
+        on_ms: 50,
+        off_ms: 0,
+        pause_ms: 950,
+    };
+
+    pub const fn code(pulses: u8) -> Self {
+        Self {
+            pulses,
+            on_ms: 200,
+            off_ms: 300,
+            pause_ms: 1500,
+        }
+    }
+
+    pub const fn duration_ms(&self) -> u64 {
+        self.pulses as u64 * (self.on_ms + self.off_ms) + self.pause_ms
+    }
+
+    pub fn level(&self, elapsed_ms: u64) -> bool {
+        let pulse_ms = self.on_ms + self.off_ms;
+        elapsed_ms < self.pulses as u64 * pulse_ms && elapsed_ms % pulse_ms < self.on_ms
+    }
+}
+
+/// Plays the pattern of the highest priority condition.
+///
+/// A new pattern only starts when the current one has been shown completely,
+/// so codes are never cut short and stay countable.
+pub struct Sequencer {
+    pattern: Pattern,
+    started_ms: u64,
+}
+
+impl Sequencer {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            pattern: Pattern::HEARTBEAT,
+            started_ms: now_ms,
+        }
+    }
+
+    /// Pattern for a set of condition bits.
+    pub fn select(conditions: u8) -> Pattern {
+        Status::ALL
+            .iter()
+            .find(|status| conditions & status.mask() != 0)
+            .map_or(Pattern::HEARTBEAT, |status| status.pattern())
+    }
+
+    /// LED level at `now_ms`.
+    pub fn update(&mut self, now_ms: u64, conditions: u8) -> bool {
+        let mut elapsed = now_ms - self.started_ms;
+        if elapsed >= self.pattern.duration_ms() {
+            self.pattern = Self::select(conditions);
+            self.started_ms = now_ms;
+            elapsed = 0;
+        }
+        self.pattern.level(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the sequencer from `from_ms` up to `to_ms` in 10 ms steps and
+    /// counts the rising edges.
+    fn count_pulses(sequencer: &mut Sequencer, from_ms: u64, to_ms: u64, conditions: u8) -> u32 {
+        let mut pulses = 0;
+        let mut last = false;
+        for now in (from_ms..to_ms).step_by(10) {
+            let level = sequencer.update(now, conditions);
+            if level && !last {
+                pulses += 1;
+            }
+            last = level;
+        }
+        pulses
+    }
+
+    #[test]
+    fn heartbeat_without_conditions() {
+        assert!(Sequencer::select(0) == Pattern::HEARTBEAT);
+    }
+
+    #[test]
+    fn lowest_status_takes_priority() {
+        let all = Status::ALL
+            .iter()
+            .fold(0, |mask, status| mask | status.mask());
+        assert!(Sequencer::select(all) == Status::CanBusOff.pattern());
+
+        let conditions = Status::LinFault.mask() | Status::FailsafeActive.mask();
+        assert!(Sequencer::select(conditions) == Status::FailsafeActive.pattern());
+
+        let conditions = Status::WatchdogReset.mask() | Status::Kl15Low.mask();
+        assert!(Sequencer::select(conditions) == Status::Kl15Low.pattern());
+    }
+
+    #[test]
+    fn codes_are_distinct() {
+        for (i, a) in Status::ALL.iter().enumerate() {
+            for b in &Status::ALL[i + 1..] {
+                assert!(a.pattern().pulses != b.pattern().pulses);
+            }
+        }
+    }
+
+    #[test]
+    fn code_pulse_count() {
+        let pattern = Status::Kl15Low.pattern();
+        let mut sequencer = Sequencer::new(0);
+        // heartbeat first, the condition is picked up once it completed
+        let heartbeat = Pattern::HEARTBEAT.duration_ms();
+        assert_eq!(
+            count_pulses(&mut sequencer, 0, heartbeat, Status::Kl15Low.mask()),
+            1
+        );
+        let end = heartbeat + pattern.duration_ms();
+        assert_eq!(
+            count_pulses(&mut sequencer, heartbeat, end, Status::Kl15Low.mask()),
+            pattern.pulses as u32
+        );
+    }
+
+    #[test]
+    fn pattern_not_cut_short() {
+        let low = Status::LinFault.pattern();
+        let mut sequencer = Sequencer::new(0);
+        sequencer.update(0, 0);
+        // enter the lin fault code
+        let heartbeat = Pattern::HEARTBEAT.duration_ms();
+        sequencer.update(heartbeat, Status::LinFault.mask());
+
+        // a higher priority condition is raised in the middle of the code
+        let raised = heartbeat + low.on_ms + 10;
+        let end = heartbeat + low.duration_ms();
+        let conditions = Status::LinFault.mask() | Status::CanBusOff.mask();
+        let pulses = count_pulses(&mut sequencer, heartbeat, raised, conditions)
+            + count_pulses(&mut sequencer, raised, end, conditions);
+        assert_eq!(pulses, low.pulses as u32);
+
+        // and shown after the lin fault code completed
+        let high = Status::CanBusOff.pattern();
+        assert_eq!(
+            count_pulses(&mut sequencer, end, end + high.duration_ms(), conditions),
+            high.pulses as u32
+        );
+    }
+
+    #[test]
+    fn cleared_condition_finishes_code() {
+        let pattern = Status::FailsafeActive.pattern();
+        let heartbeat = Pattern::HEARTBEAT.duration_ms();
+        let mut sequencer = Sequencer::new(0);
+        sequencer.update(heartbeat, Status::FailsafeActive.mask());
+
+        // the condition clears as soon as the code started
+        let end = heartbeat + pattern.duration_ms();
+        assert_eq!(
+            count_pulses(&mut sequencer, heartbeat, end, 0),
+            pattern.pulses as u32
+        );
+        assert!(sequencer.update(end, 0));
+        assert!(!sequencer.update(end + Pattern::HEARTBEAT.on_ms, 0));
+        let next = end + Pattern::HEARTBEAT.on_ms;
+        assert_eq!(count_pulses(&mut sequencer, next, end + heartbeat, 0), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::math::abs_f32;
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SteeringLimits {
+    /// deg/s
+    pub max_velocity: f32,
+    /// deg/s^2
+    pub max_acceleration: f32,
+    /// the full steering angle is allowed up to this speed
+    pub full_angle_below_kmh: f32,
+    /// from this speed on the angle is limited to `high_speed_angle_deg`
+    pub high_speed_kmh: f32,
+    pub high_speed_angle_deg: f32,
+}
+
+pub const DEFAULT_STEERING: SteeringLimits = SteeringLimits {
+    max_velocity: 180.0,
+    max_acceleration: 1500.0,
+    full_angle_below_kmh: 5.0,
+    high_speed_kmh: 15.0,
+    high_speed_angle_deg: 15.0,
+};
+
+/// Hardware independent steering trajectory.
+///
+/// Moves the wheel angle towards the requested angle with bounded angular
+/// velocity and acceleration, braking in time to stop at the target without
+/// overshoot. Steps in the request therefore never reach the linkage as
+/// steps.
+pub struct SteeringProfile {
+    limits: SteeringLimits,
+    angle: f32,
+    velocity: f32,
+}
+
+impl SteeringProfile {
+    pub fn new(limits: SteeringLimits) -> Self {
+        Self {
+            limits,
+            angle: 0.0,
+            velocity: 0.0,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: SteeringLimits) {
+        self.limits = limits;
+    }
+
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    /// Largest angle in either direction allowed at `speed_kmh`, interpolated
+    /// between the low and high speed limit.
+    pub fn max_angle(&self, speed_kmh: f32, full_angle_deg: f32) -> f32 {
+        let limits = &self.limits;
+        let speed = abs_f32(speed_kmh);
+        if speed <= limits.full_angle_below_kmh {
+            return full_angle_deg;
+        }
+        if speed >= limits.high_speed_kmh {
+            return limits.high_speed_angle_deg.min(full_angle_deg);
+        }
+
+        let fraction = (speed - limits.full_angle_below_kmh)
+            / (limits.high_speed_kmh - limits.full_angle_below_kmh);
+        let angle = full_angle_deg + fraction * (limits.high_speed_angle_deg - full_angle_deg);
+        angle.min(full_angle_deg)
+    }
+
+    /// Computes the angle setpoint for one period of `dt` seconds.
+    pub fn update(&mut self, target: f32, max_angle: f32, dt: f32) -> f32 {
+        let target = target.clamp(-max_angle, max_angle);
+        let limits = &self.limits;
+
+        // work along the direction towards the target
+        let direction = if target < self.angle { -1.0 } else { 1.0 };
+        let distance = (target - self.angle) * direction;
+        let velocity = self.velocity * direction;
+        let max_step = limits.max_acceleration * dt;
+
+        let accelerate = (velocity + max_step).min(limits.max_velocity);
+        if distance <= accelerate.min(max_step) * dt {
+            // arrives within this period slow enough to stop in the next
+            self.angle = target;
+            self.velocity = 0.0;
+            return self.angle;
+        }
+
+        // speed up, hold or slow down, whichever is fastest and still stops
+        // at the target
+        let fits = |v: f32| v * dt + braking_distance(v, max_step, dt) <= distance;
+        let hold = velocity.min(limits.max_velocity);
+        let next = if fits(accelerate) {
+            accelerate
+        } else if fits(hold) {
+            hold
+        } else {
+            velocity - max_step
+        };
+
+        self.velocity = next.max(velocity - max_step) * direction;
+        self.angle += self.velocity * dt;
+        self.angle
+    }
+}
+
+/// Distance covered while braking from `velocity` to a stop, slowing down by
+/// `max_step` every period.
+fn braking_distance(velocity: f32, max_step: f32, dt: f32) -> f32 {
+    if velocity <= 0.0 {
+        return 0.0;
+    }
+    let steps = (velocity / max_step) as u32 as f32;
+    dt * (steps * velocity - max_step * steps * (steps + 1.0) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.02;
+    const FULL_ANGLE_DEG: f32 = 45.0;
+
+    /// One period, checks the velocity and acceleration bounds against the
+    /// `velocity` of the previous period.
+    fn step(profile: &mut SteeringProfile, target: f32, velocity: &mut f32) -> f32 {
+        let limits = profile.limits;
+        let last = profile.angle();
+        let angle = profile.update(target, FULL_ANGLE_DEG, DT);
+        let v = (angle - last) / DT;
+
+        assert!(v.abs() <= limits.max_velocity + 1e-3, "velocity {}", v);
+        assert!(
+            (v - *velocity).abs() <= limits.max_acceleration * DT + 1e-3,
+            "acceleration from {} to {}",
+            velocity,
+            v
+        );
+        *velocity = v;
+        angle
+    }
+
+    /// Runs the profile from standstill towards `target`, returns the number
+    /// of periods until it stopped there.
+    fn run_to(profile: &mut SteeringProfile, target: f32) -> usize {
+        let start = profile.angle();
+        let mut velocity = 0.0;
+        for period in 0..1000 {
+            let angle = step(profile, target, &mut velocity);
+            // never beyond the target, never back towards the start
+            assert!(
+                (angle - start) * (target - angle) >= 0.0,
+                "overshoot {}",
+                angle
+            );
+            if angle == target {
+                // the next period stops
+                assert_eq!(step(profile, target, &mut velocity), target);
+                assert_eq!(velocity, 0.0);
+                return period;
+            }
+        }
+        panic!("target {} not reached", target);
+    }
+
+    #[test]
+    fn step_respects_velocity_and_acceleration() {
+        let mut profile = SteeringProfile::new(DEFAULT_STEERING);
+        run_to(&mut profile, 40.0);
+        assert_eq!(profile.angle(), 40.0);
+        run_to(&mut profile, -30.0);
+        assert_eq!(profile.angle(), -30.0);
+    }
+
+    #[test]
+    fn slow_limits() {
+        let mut profile = SteeringProfile::new(SteeringLimits {
+            max_velocity: 30.0,
+            max_acceleration: 60.0,
+            ..DEFAULT_STEERING
+        });
+        let periods = run_to(&mut profile, 30.0);
+        // 0.5 s accelerating and braking each, 0.5 s at full speed
+        assert!((70..=80).contains(&periods), "{} periods", periods);
+    }
+
+    #[test]
+    fn reversal_respects_acceleration() {
+        let mut profile = SteeringProfile::new(DEFAULT_STEERING);
+        let mut velocity = 0.0;
+        for _ in 0..8 {
+            step(&mut profile, 40.0, &mut velocity);
+        }
+        assert!(velocity > 100.0);
+        // the request jumps behind the wheels while moving
+        for _ in 0..100 {
+            step(&mut profile, -20.0, &mut velocity);
+        }
+        assert_eq!(profile.angle(), -20.0);
+        assert_eq!(velocity, 0.0);
+    }
+
+    #[test]
+    fn small_step() {
+        let mut profile = SteeringProfile::new(DEFAULT_STEERING);
+        run_to(&mut profile, 0.5);
+        assert_eq!(profile.angle(), 0.5);
+    }
+
+    #[test]
+    fn holds_target() {
+        let mut profile = SteeringProfile::new(DEFAULT_STEERING);
+        run_to(&mut profile, 10.0);
+        for _ in 0..10 {
+            assert_eq!(profile.update(10.0, FULL_ANGLE_DEG, DT), 10.0);
+        }
+    }
+
+    #[test]
+    fn target_limited_to_max_angle() {
+        let mut profile = SteeringProfile::new(DEFAULT_STEERING);
+        for _ in 0..200 {
+            profile.update(45.0, 20.0, DT);
+        }
+        assert_eq!(profile.angle(), 20.0);
+        for _ in 0..200 {
+            profile.update(-45.0, 20.0, DT);
+        }
+        assert_eq!(profile.angle(), -20.0);
+    }
+
+    #[test]
+    fn max_angle_interpolation() {
+        let profile = SteeringProfile::new(DEFAULT_STEERING);
+        assert_eq!(profile.max_angle(0.0, 45.0), 45.0);
+        assert_eq!(profile.max_angle(5.0, 45.0), 45.0);
+        assert_eq!(profile.max_angle(10.0, 45.0), 30.0);
+        assert_eq!(profile.max_angle(12.5, 45.0), 22.5);
+        assert_eq!(profile.max_angle(15.0, 45.0), 15.0);
+        assert_eq!(profile.max_angle(40.0, 45.0), 15.0);
+        // reversing is limited the same way
+        assert_eq!(profile.max_angle(-10.0, 45.0), 30.0);
+        assert_eq!(profile.max_angle(-40.0, 45.0), 15.0);
+    }
+
+    #[test]
+    fn max_angle_never_above_full_angle() {
+        let profile = SteeringProfile::new(SteeringLimits {
+            high_speed_angle_deg: 60.0,
+            ..DEFAULT_STEERING
+        });
+        assert_eq!(profile.max_angle(10.0, 45.0), 45.0);
+        assert_eq!(profile.max_angle(20.0, 45.0), 45.0);
+        // a narrower full angle from the calibration
+        let profile = SteeringProfile::new(DEFAULT_STEERING);
+        assert_eq!(profile.max_angle(0.0, 10.0), 10.0);
+        assert_eq!(profile.max_angle(20.0, 10.0), 10.0);
+    }
+}
@@ -0,0 +1,475 @@
+use crate::servo::{CalibrationPoint, ServoCalibration};
+
+// pulse widths outside of this range are never sent to the servo
+pub const PULSE_MIN_US: u16 = 800;
+pub const PULSE_MAX_US: u16 = 2200;
+// smallest travel from the center to either end stop
+const MIN_TRAVEL_US: u16 = 100;
+
+/// Calibration step requested over CAN, the values are the `Steer_Cal_Cmd`
+/// codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalCommand {
+    Enter,
+    /// moves the servo to an absolute pulse width in us
+    Jog(u16),
+    RecordLeft,
+    RecordCenter,
+    RecordRight,
+    Save,
+    Abort,
+}
+
+impl CalCommand {
+    pub fn from_request(command: u8, pulse_us: u16) -> Option<Self> {
+        match command {
+            0 => Some(CalCommand::Enter),
+            1 => Some(CalCommand::Jog(pulse_us)),
+            2 => Some(CalCommand::RecordLeft),
+            3 => Some(CalCommand::RecordCenter),
+            4 => Some(CalCommand::RecordRight),
+            5 => Some(CalCommand::Save),
+            6 => Some(CalCommand::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// The values are the `Steer_Cal_State` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalState {
+    Idle = 0,
+    Active = 1,
+    Saved = 2,
+}
+
+/// The values are the `Steer_Cal_Error` codes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalError {
+    NotActive = 1,
+    /// calibration is only entered at standstill
+    Moving = 2,
+    PulseRange = 3,
+    /// saving before all three positions were recorded
+    Incomplete = 4,
+    /// the recorded positions are not plausible
+    Invalid = 5,
+    Flash = 6,
+}
+
+/// What the servo task has to do after a command.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum CalAction {
+    None,
+    Pulse(u16),
+    Save(CalibrationRecord),
+    Exit,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalStatus {
+    pub state: CalState,
+    pub error: Option<CalError>,
+    pub left_us: u16,
+    pub center_us: u16,
+    pub right_us: u16,
+}
+
+/// Operator driven calibration of the steering end stops.
+///
+/// The servo is jogged by pulse width to the left stop, the center and the
+/// right stop, each position is recorded and the result is validated before
+/// it is handed out for saving.
+pub struct CalibrationProcedure {
+    state: CalState,
+    error: Option<CalError>,
+    pulse_us: u16,
+    left_us: Option<u16>,
+    center_us: Option<u16>,
+    right_us: Option<u16>,
+}
+
+impl CalibrationProcedure {
+    pub const fn new() -> Self {
+        Self {
+            state: CalState::Idle,
+            error: None,
+            pulse_us: 0,
+            left_us: None,
+            center_us: None,
+            right_us: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == CalState::Active
+    }
+
+    /// `pulse_us` is the pulse currently output, jogging starts from there.
+    pub fn handle(&mut self, command: CalCommand, pulse_us: u16, moving: bool) -> CalAction {
+        let result = self.step(command, pulse_us, moving);
+        self.error = result.err();
+        result.unwrap_or(CalAction::None)
+    }
+
+    fn step(
+        &mut self,
+        command: CalCommand,
+        pulse_us: u16,
+        moving: bool,
+    ) -> Result<CalAction, CalError> {
+        match command {
+            CalCommand::Enter if moving => Err(CalError::Moving),
+            CalCommand::Enter => {
+                *self = Self::new();
+                self.state = CalState::Active;
+                self.pulse_us = pulse_us;
+                Ok(CalAction::Pulse(pulse_us))
+            }
+            _ if !self.is_active() => Err(CalError::NotActive),
+            CalCommand::Jog(pulse_us) => {
+                if !(PULSE_MIN_US..=PULSE_MAX_US).contains(&pulse_us) {
+                    return Err(CalError::PulseRange);
+                }
+                self.pulse_us = pulse_us;
+                Ok(CalAction::Pulse(pulse_us))
+            }
+            CalCommand::RecordLeft => {
+                self.left_us = Some(self.pulse_us);
+                Ok(CalAction::None)
+            }
+            CalCommand::RecordCenter => {
+                self.center_us = Some(self.pulse_us);
+                Ok(CalAction::None)
+            }
+            CalCommand::RecordRight => {
+                self.right_us = Some(self.pulse_us);
+                Ok(CalAction::None)
+            }
+            CalCommand::Save => {
+                let (Some(left_us), Some(center_us), Some(right_us)) =
+                    (self.left_us, self.center_us, self.right_us)
+                else {
+                    return Err(CalError::Incomplete);
+                };
+                let record = CalibrationRecord {
+                    left_us,
+                    center_us,
+                    right_us,
+                };
+                if !record.is_valid() {
+                    return Err(CalError::Invalid);
+                }
+                Ok(CalAction::Save(record))
+            }
+            CalCommand::Abort => {
+                self.state = CalState::Idle;
+                Ok(CalAction::Exit)
+            }
+        }
+    }
+
+    /// Result of writing the record handed out by `Save`.
+    pub fn saved(&mut self, ok: bool) {
+        if ok {
+            self.state = CalState::Saved;
+            self.error = None;
+        } else {
+            self.error = Some(CalError::Flash);
+        }
+    }
+
+    pub fn status(&self) -> CalStatus {
+        CalStatus {
+            state: self.state,
+            error: self.error,
+            left_us: self.left_us.unwrap_or(0),
+            center_us: self.center_us.unwrap_or(0),
+            right_us: self.right_us.unwrap_or(0),
+        }
+    }
+}
+
+/// Pulse widths of the steering end stops, saved in the configuration store
+/// with a key of their own.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalibrationRecord {
+    pub left_us: u16,
+    pub center_us: u16,
+    pub right_us: u16,
+}
+
+/// End stops measured on the first car, used until a calibration was saved.
+pub const DEFAULT_CALIBRATION: CalibrationRecord = CalibrationRecord {
+    left_us: 1896,
+    center_us: 1486,
+    right_us: 1075,
+};
+
+impl CalibrationRecord {
+    /// The center has to lie between the stops with some travel to either
+    /// side, the servo may be mounted either way round.
+    pub fn is_valid(&self) -> bool {
+        let pulses = [self.left_us, self.center_us, self.right_us];
+        if !pulses
+            .iter()
+            .all(|pulse| (PULSE_MIN_US..=PULSE_MAX_US).contains(pulse))
+        {
+            return false;
+        }
+        let (low, high) = if self.left_us < self.right_us {
+            (self.left_us, self.right_us)
+        } else {
+            (self.right_us, self.left_us)
+        };
+        self.center_us >= low + MIN_TRAVEL_US && self.center_us + MIN_TRAVEL_US <= high
+    }
+
+    /// Calibration with the end stops at +-`full_angle_deg`, `None` unless
+    /// the angle is positive.
+    pub fn calibration(&self, full_angle_deg: f32) -> Option<ServoCalibration> {
+        ServoCalibration::new(&[
+            CalibrationPoint {
+                angle_deg: -full_angle_deg,
+                pulse_us: self.right_us as f32,
+            },
+            CalibrationPoint {
+                angle_deg: 0.0,
+                pulse_us: self.center_us as f32,
+            },
+            CalibrationPoint {
+                angle_deg: full_angle_deg,
+                pulse_us: self.left_us as f32,
+            },
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD: CalibrationRecord = CalibrationRecord {
+        left_us: 1900,
+        center_us: 1500,
+        right_us: 1100,
+    };
+
+    /// Enters calibration and records `RECORD`.
+    fn recorded() -> CalibrationProcedure {
+        let mut procedure = CalibrationProcedure::new();
+        procedure.handle(CalCommand::Enter, 1500, false);
+        for (pulse_us, record) in [
+            (RECORD.left_us, CalCommand::RecordLeft),
+            (RECORD.center_us, CalCommand::RecordCenter),
+            (RECORD.right_us, CalCommand::RecordRight),
+        ] {
+            assert_eq!(
+                procedure.handle(CalCommand::Jog(pulse_us), 0, false),
+                CalAction::Pulse(pulse_us)
+            );
+            assert_eq!(procedure.handle(record, 0, false), CalAction::None);
+        }
+        procedure
+    }
+
+    #[test]
+    fn request_codes() {
+        assert_eq!(CalCommand::from_request(0, 0), Some(CalCommand::Enter));
+        assert_eq!(
+            CalCommand::from_request(1, 1234),
+            Some(CalCommand::Jog(1234))
+        );
+        assert_eq!(CalCommand::from_request(5, 0), Some(CalCommand::Save));
+        assert_eq!(CalCommand::from_request(6, 0), Some(CalCommand::Abort));
+        assert_eq!(CalCommand::from_request(7, 0), None);
+    }
+
+    #[test]
+    fn enter_only_at_standstill() {
+        let mut procedure = CalibrationProcedure::new();
+        assert_eq!(
+            procedure.handle(CalCommand::Enter, 1500, true),
+            CalAction::None
+        );
+        assert!(!procedure.is_active());
+        assert_eq!(procedure.status().error, Some(CalError::Moving));
+
+        assert_eq!(
+            procedure.handle(CalCommand::Enter, 1500, false),
+            CalAction::Pulse(1500)
+        );
+        assert!(procedure.is_active());
+        assert_eq!(procedure.status().state, CalState::Active);
+        assert_eq!(procedure.status().error, None);
+    }
+
+    #[test]
+    fn commands_need_active_calibration() {
+        let mut procedure = CalibrationProcedure::new();
+        for command in [
+            CalCommand::Jog(1500),
+            CalCommand::RecordLeft,
+            CalCommand::Save,
+            CalCommand::Abort,
+        ] {
+            assert_eq!(procedure.handle(command, 1500, false), CalAction::None);
+            assert_eq!(procedure.status().error, Some(CalError::NotActive));
+        }
+        assert_eq!(procedure.status().state, CalState::Idle);
+    }
+
+    #[test]
+    fn jog_range() {
+        let mut procedure = CalibrationProcedure::new();
+        procedure.handle(CalCommand::Enter, 1500, false);
+        assert_eq!(
+            procedure.handle(CalCommand::Jog(PULSE_MIN_US - 1), 0, false),
+            CalAction::None
+        );
+        assert_eq!(procedure.status().error, Some(CalError::PulseRange));
+        assert_eq!(
+            procedure.handle(CalCommand::Jog(PULSE_MAX_US + 1), 0, false),
+            CalAction::None
+        );
+        // the rejected jog does not move the recorded position
+        procedure.handle(CalCommand::RecordLeft, 0, false);
+        assert_eq!(procedure.status().left_us, 1500);
+        assert_eq!(procedure.status().error, None);
+
+        assert_eq!(
+            procedure.handle(CalCommand::Jog(PULSE_MAX_US), 0, false),
+            CalAction::Pulse(PULSE_MAX_US)
+        );
+    }
+
+    #[test]
+    fn save_recorded_positions() {
+        let mut procedure = recorded();
+        let status = procedure.status();
+        assert_eq!(
+            (status.left_us, status.center_us, status.right_us),
+            (1900, 1500, 1100)
+        );
+        assert_eq!(
+            procedure.handle(CalCommand::Save, 0, false),
+            CalAction::Save(RECORD)
+        );
+        // active until the record is in flash
+        assert!(procedure.is_active());
+
+        procedure.saved(true);
+        assert!(!procedure.is_active());
+        assert_eq!(procedure.status().state, CalState::Saved);
+        assert_eq!(procedure.status().error, None);
+    }
+
+    #[test]
+    fn save_failed() {
+        let mut procedure = recorded();
+        procedure.handle(CalCommand::Save, 0, false);
+        procedure.saved(false);
+        assert!(procedure.is_active());
+        assert_eq!(procedure.status().error, Some(CalError::Flash));
+        // may be retried
+        assert_eq!(
+            procedure.handle(CalCommand::Save, 0, false),
+            CalAction::Save(RECORD)
+        );
+    }
+
+    #[test]
+    fn save_incomplete() {
+        let mut procedure = CalibrationProcedure::new();
+        procedure.handle(CalCommand::Enter, 1500, false);
+        procedure.handle(CalCommand::RecordCenter, 0, false);
+        procedure.handle(CalCommand::Jog(1900), 0, false);
+        procedure.handle(CalCommand::RecordLeft, 0, false);
+        assert_eq!(
+            procedure.handle(CalCommand::Save, 0, false),
+            CalAction::None
+        );
+        assert_eq!(procedure.status().error, Some(CalError::Incomplete));
+    }
+
+    #[test]
+    fn save_implausible() {
+        let mut procedure = recorded();
+        // center too close to the left stop
+        procedure.handle(CalCommand::Jog(1850), 0, false);
+        procedure.handle(CalCommand::RecordCenter, 0, false);
+        assert_eq!(
+            procedure.handle(CalCommand::Save, 0, false),
+            CalAction::None
+        );
+        assert_eq!(procedure.status().error, Some(CalError::Invalid));
+    }
+
+    #[test]
+    fn abort_and_reenter() {
+        let mut procedure = recorded();
+        assert_eq!(
+            procedure.handle(CalCommand::Abort, 0, false),
+            CalAction::Exit
+        );
+        assert_eq!(procedure.status().state, CalState::Idle);
+
+        // entering again starts over
+        procedure.handle(CalCommand::Enter, 1400, false);
+        assert_eq!(procedure.status().left_us, 0);
+        assert_eq!(
+            procedure.handle(CalCommand::Save, 0, false),
+            CalAction::None
+        );
+        assert_eq!(procedure.status().error, Some(CalError::Incomplete));
+    }
+
+    #[test]
+    fn plausibility() {
+        assert!(RECORD.is_valid());
+        assert!(DEFAULT_CALIBRATION.is_valid());
+        // servo mounted the other way round
+        let mirrored = CalibrationRecord {
+            left_us: 1100,
+            center_us: 1500,
+            right_us: 1900,
+        };
+        assert!(mirrored.is_valid());
+        // center outside of the stops, too little travel, out of range
+        for (left_us, center_us, right_us) in [
+            (1900, 2000, 1100),
+            (1900, 1850, 1100),
+            (1900, 1150, 1100),
+            (2300, 1500, 1100),
+            (1900, 1500, 700),
+        ] {
+            let record = CalibrationRecord {
+                left_us,
+                center_us,
+                right_us,
+            };
+            assert!(!record.is_valid());
+        }
+    }
+
+    #[test]
+    fn servo_calibration() {
+        let calibration = RECORD.calibration(45.0).unwrap();
+        assert_eq!(calibration.pulse_us(45.0), 1900.0);
+        assert_eq!(calibration.pulse_us(0.0), 1500.0);
+        assert_eq!(calibration.pulse_us(-45.0), 1100.0);
+        assert_eq!(calibration.pulse_us(22.5), 1700.0);
+        assert_eq!(RECORD.calibration(0.0), None);
+    }
+}
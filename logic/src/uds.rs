@@ -0,0 +1,763 @@
+use crate::{
+    config::{self, Config},
+    math::{abs_f32, round_f32},
+    status_led::Status,
+    ultrasound::{FiringSchedule, UltrasoundResult, ULTRASOUND_CHANNELS},
+};
+
+// S3: a non-default session falls back after this time without requests
+const S3_SERVER_MS: u64 = 5000;
+// reported by the session control response
+const P2_SERVER_MS: u16 = 50;
+const P2_EXTENDED_SERVER_MS: u16 = 5000;
+// ECU reset is refused above this speed
+const STANDSTILL_KMH: f32 = 0.1;
+
+const SID_SESSION_CONTROL: u8 = 0x10;
+const SID_ECU_RESET: u8 = 0x11;
+const SID_READ_DTC: u8 = 0x19;
+const SID_READ_DATA: u8 = 0x22;
+pub const SID_WRITE_DATA: u8 = 0x2e;
+const SID_TESTER_PRESENT: u8 = 0x3e;
+const SID_NEGATIVE_RESPONSE: u8 = 0x7f;
+const POSITIVE_RESPONSE: u8 = 0x40;
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+const DID_ACTIVE_SESSION: u16 = 0xf186;
+const DID_SOFTWARE_VERSION: u16 = 0xf195;
+/// u16 mV
+const DID_KL15_VOLTAGE: u16 = 0xfd00;
+/// i16 0.01 km/h
+const DID_SPEED: u16 = 0xfd01;
+/// u16 per ultrasound position, encoded as on `FRONT_DIST`
+const DID_DISTANCES: u16 = 0xfd02;
+/// u16 0.01 ticks, writable
+const DID_TICKS_PER_CM: u16 = 0xfd10;
+/// r1, r2 u32 ohm, writable
+const DID_KL15_DIVIDER: u16 = 0xfd11;
+/// left, center, right u16 us, written by the calibration procedure only
+const DID_STEERING_CALIBRATION: u16 = 0xfd12;
+/// sensor channel per position, writable
+const DID_ULTRASOUND_MAP: u16 = 0xfd13;
+/// nominal, data u32 bit/s, writable
+const DID_CAN_BITRATE: u16 = 0xfd14;
+/// `FiringSchedule` of the ultrasound sensors u8, writable
+const DID_ULTRASOUND_SCHEDULE: u16 = 0xfd15;
+
+/// DTC of every status LED condition.
+const DTCS: [(Status, u32); 5] = [
+    // U0073 communication bus off
+    (Status::CanBusOff, 0xc0_7300),
+    // U0100 lost communication, here with the drive computer
+    (Status::FailsafeActive, 0xc1_0000),
+    // P0562 system voltage low
+    (Status::Kl15Low, 0x05_6200),
+    // U1001 LIN slave not responding
+    (Status::LinFault, 0xd0_0100),
+    // P0606 control module processor fault
+    (Status::WatchdogReset, 0x06_0600),
+];
+const DTC_TEST_FAILED: u8 = 0x01;
+/// latched until the next power cycle
+const DTC_CONFIRMED: u8 = 0x08;
+const DTC_AVAILABILITY_MASK: u8 = DTC_TEST_FAILED | DTC_CONFIRMED;
+const DTC_FORMAT_ISO_14229_1: u8 = 0x01;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Addressing {
+    Physical,
+    Functional,
+}
+
+/// The values are the negative response codes of ISO 14229-1.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Nrc {
+    ServiceNotSupported = 0x11,
+    SubFunctionNotSupported = 0x12,
+    IncorrectLength = 0x13,
+    ResponseTooLong = 0x14,
+    ConditionsNotCorrect = 0x22,
+    RequestOutOfRange = 0x31,
+    GeneralProgrammingFailure = 0x72,
+    ResponsePending = 0x78,
+    ServiceNotSupportedInSession = 0x7f,
+}
+
+impl Nrc {
+    /// Functional requests are not answered with these.
+    fn suppressed_on_functional(self) -> bool {
+        matches!(
+            self,
+            Nrc::ServiceNotSupported
+                | Nrc::SubFunctionNotSupported
+                | Nrc::RequestOutOfRange
+                | Nrc::ServiceNotSupportedInSession
+        )
+    }
+}
+
+pub fn negative_response(sid: u8, nrc: Nrc) -> [u8; 3] {
+    [SID_NEGATIVE_RESPONSE, sid, nrc as u8]
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Session {
+    Default = 0x01,
+    Extended = 0x03,
+}
+
+/// Values read by ReadDataByIdentifier and ReadDTCInformation.
+#[derive(Copy, Clone)]
+pub struct DiagData {
+    pub kl15_mv: u16,
+    pub speed_kmh: f32,
+    pub distances: [UltrasoundResult; ULTRASOUND_CHANNELS],
+    /// `status_led` conditions raised now and since startup
+    pub conditions: u8,
+    pub history: u8,
+}
+
+/// What the task has to do with the response in the buffer.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    None,
+    Respond(usize),
+    /// respond, then reset the controller
+    Reset(usize),
+    /// save the configuration, respond once it is in flash
+    Save(usize),
+}
+
+struct Response<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Response<'_> {
+    fn push(&mut self, data: &[u8]) -> Result<(), Nrc> {
+        let end = self.len + data.len();
+        self.buffer
+            .get_mut(self.len..end)
+            .ok_or(Nrc::ResponseTooLong)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Positive response with a `len` of 0 when suppressed by the request.
+    fn positive(&self, suppress: bool) -> usize {
+        if suppress {
+            0
+        } else {
+            self.len
+        }
+    }
+}
+
+/// Sub-function and whether the positive response is suppressed, the
+/// request holds nothing else.
+fn sub_function(params: &[u8]) -> Result<(u8, bool), Nrc> {
+    match params {
+        [sub] => Ok((
+            sub & !SUPPRESS_POSITIVE_RESPONSE,
+            sub & SUPPRESS_POSITIVE_RESPONSE != 0,
+        )),
+        _ => Err(Nrc::IncorrectLength),
+    }
+}
+
+fn u32_at(value: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]])
+}
+
+/// Hardware independent UDS service dispatcher.
+///
+/// Reads and writes go to the `Config` handed in, the caller persists it
+/// when the result asks for a save.
+pub struct Server {
+    software_version: &'static str,
+    session: Session,
+    last_request_ms: u64,
+}
+
+impl Server {
+    /// `software_version` is reported as the software version identifier.
+    pub const fn new(software_version: &'static str) -> Self {
+        Self {
+            software_version,
+            session: Session::Default,
+            last_request_ms: 0,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        request: &[u8],
+        addressing: Addressing,
+        now_ms: u64,
+        data: &DiagData,
+        config: &mut Config,
+        response: &mut [u8],
+    ) -> Action {
+        if self.session != Session::Default && now_ms - self.last_request_ms > S3_SERVER_MS {
+            self.session = Session::Default;
+        }
+        self.last_request_ms = now_ms;
+
+        let Some((&sid, params)) = request.split_first() else {
+            return Action::None;
+        };
+        let mut writer = Response {
+            buffer: response,
+            len: 0,
+        };
+        let result = match sid {
+            SID_SESSION_CONTROL => self.session_control(params, &mut writer),
+            SID_ECU_RESET => Self::ecu_reset(params, data, &mut writer),
+            SID_READ_DTC => Self::read_dtc(params, data, &mut writer),
+            SID_READ_DATA => self.read_data(params, data, config, &mut writer),
+            SID_WRITE_DATA => self.write_data(params, config, &mut writer),
+            SID_TESTER_PRESENT => Self::tester_present(params, &mut writer),
+            _ => Err(Nrc::ServiceNotSupported),
+        };
+
+        match result {
+            Ok(Action::Respond(0)) => Action::None,
+            Ok(action) => action,
+            Err(nrc) if addressing == Addressing::Functional && nrc.suppressed_on_functional() => {
+                Action::None
+            }
+            Err(nrc) => {
+                response[..3].copy_from_slice(&negative_response(sid, nrc));
+                Action::Respond(3)
+            }
+        }
+    }
+
+    fn session_control(&mut self, params: &[u8], w: &mut Response) -> Result<Action, Nrc> {
+        let (sub, suppress) = sub_function(params)?;
+        self.session = match sub {
+            0x01 => Session::Default,
+            0x03 => Session::Extended,
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+        w.push(&[SID_SESSION_CONTROL + POSITIVE_RESPONSE, sub])?;
+        w.push(&P2_SERVER_MS.to_be_bytes())?;
+        // P2* is sent in 10 ms steps
+        w.push(&(P2_EXTENDED_SERVER_MS / 10).to_be_bytes())?;
+        Ok(Action::Respond(w.positive(suppress)))
+    }
+
+    fn ecu_reset(params: &[u8], data: &DiagData, w: &mut Response) -> Result<Action, Nrc> {
+        let (sub, suppress) = sub_function(params)?;
+        // hard and soft reset both restart the controller
+        if sub != 0x01 && sub != 0x03 {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+        if abs_f32(data.speed_kmh) > STANDSTILL_KMH {
+            return Err(Nrc::ConditionsNotCorrect);
+        }
+        w.push(&[SID_ECU_RESET + POSITIVE_RESPONSE, sub])?;
+        Ok(Action::Reset(w.positive(suppress)))
+    }
+
+    fn tester_present(params: &[u8], w: &mut Response) -> Result<Action, Nrc> {
+        let (sub, suppress) = sub_function(params)?;
+        if sub != 0x00 {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+        w.push(&[SID_TESTER_PRESENT + POSITIVE_RESPONSE, sub])?;
+        Ok(Action::Respond(w.positive(suppress)))
+    }
+
+    fn read_dtc(params: &[u8], data: &DiagData, w: &mut Response) -> Result<Action, Nrc> {
+        let status = |condition: Status| {
+            let mut status = 0;
+            if data.conditions & condition.mask() != 0 {
+                status |= DTC_TEST_FAILED;
+            }
+            if data.history & condition.mask() != 0 {
+                status |= DTC_CONFIRMED;
+            }
+            status
+        };
+
+        let (&sub, params) = params.split_first().ok_or(Nrc::IncorrectLength)?;
+        let mask = match (sub, params) {
+            // reportNumberOfDTCByStatusMask, reportDTCByStatusMask
+            (0x01 | 0x02, [mask]) => *mask,
+            // reportSupportedDTC
+            (0x0a, []) => 0xff,
+            (0x01 | 0x02 | 0x0a, _) => return Err(Nrc::IncorrectLength),
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+
+        w.push(&[SID_READ_DTC + POSITIVE_RESPONSE, sub, DTC_AVAILABILITY_MASK])?;
+        let matching = DTCS.iter().filter(|(condition, _)| {
+            // supported DTCs are reported with any status
+            sub == 0x0a || status(*condition) & mask != 0
+        });
+        if sub == 0x01 {
+            w.push(&[DTC_FORMAT_ISO_14229_1])?;
+            w.push(&(matching.count() as u16).to_be_bytes())?;
+        } else {
+            for &(condition, dtc) in matching {
+                w.push(&dtc.to_be_bytes()[1..])?;
+                w.push(&[status(condition)])?;
+            }
+        }
+        Ok(Action::Respond(w.len))
+    }
+
+    fn read_data(
+        &self,
+        params: &[u8],
+        data: &DiagData,
+        config: &Config,
+        w: &mut Response,
+    ) -> Result<Action, Nrc> {
+        if params.is_empty() || params.len() % 2 != 0 {
+            return Err(Nrc::IncorrectLength);
+        }
+        w.push(&[SID_READ_DATA + POSITIVE_RESPONSE])?;
+        let mut supported = false;
+        for did in params.chunks(2) {
+            let did = u16::from_be_bytes([did[0], did[1]]);
+            supported |= self.read_did(did, data, config, w)?;
+        }
+        if !supported {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        Ok(Action::Respond(w.len))
+    }
+
+    /// Appends the identifier and its record, unknown identifiers are skipped.
+    fn read_did(
+        &self,
+        did: u16,
+        data: &DiagData,
+        config: &Config,
+        w: &mut Response,
+    ) -> Result<bool, Nrc> {
+        let start = w.len;
+        w.push(&did.to_be_bytes())?;
+        match did {
+            DID_ACTIVE_SESSION => w.push(&[self.session as u8])?,
+            DID_SOFTWARE_VERSION => w.push(self.software_version.as_bytes())?,
+            DID_KL15_VOLTAGE => w.push(&data.kl15_mv.to_be_bytes())?,
+            DID_SPEED => w.push(&((data.speed_kmh * 100.0) as i16).to_be_bytes())?,
+            DID_DISTANCES => {
+                for distance in data.distances {
+                    w.push(&distance.encode().to_be_bytes())?;
+                }
+            }
+            DID_TICKS_PER_CM => {
+                w.push(&(round_f32(config.ticks_per_cm * 100.0) as u16).to_be_bytes())?
+            }
+            DID_KL15_DIVIDER => {
+                w.push(&config.kl15_r1_ohm.to_be_bytes())?;
+                w.push(&config.kl15_r2_ohm.to_be_bytes())?;
+            }
+            DID_STEERING_CALIBRATION => {
+                let steering = &config.steering;
+                w.push(&steering.left_us.to_be_bytes())?;
+                w.push(&steering.center_us.to_be_bytes())?;
+                w.push(&steering.right_us.to_be_bytes())?;
+            }
+            DID_ULTRASOUND_MAP => w.push(&config.ultrasound_map)?,
+            DID_CAN_BITRATE => {
+                w.push(&config.can_bitrate.to_be_bytes())?;
+                w.push(&config.can_data_bitrate.to_be_bytes())?;
+            }
+            DID_ULTRASOUND_SCHEDULE => w.push(&[config.ultrasound_schedule as u8])?,
+            _ => {
+                w.len = start;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes take effect after the next reset.
+    fn write_data(
+        &self,
+        params: &[u8],
+        config: &mut Config,
+        w: &mut Response,
+    ) -> Result<Action, Nrc> {
+        if params.len() < 3 {
+            return Err(Nrc::IncorrectLength);
+        }
+        if self.session != Session::Extended {
+            return Err(Nrc::ServiceNotSupportedInSession);
+        }
+        let did = u16::from_be_bytes([params[0], params[1]]);
+        let value = &params[2..];
+        let expected_len = match did {
+            DID_ULTRASOUND_SCHEDULE => 1,
+            DID_TICKS_PER_CM => 2,
+            DID_KL15_DIVIDER | DID_CAN_BITRATE => 8,
+            DID_ULTRASOUND_MAP => ULTRASOUND_CHANNELS,
+            _ => return Err(Nrc::RequestOutOfRange),
+        };
+        if value.len() != expected_len {
+            return Err(Nrc::IncorrectLength);
+        }
+
+        match did {
+            DID_TICKS_PER_CM => {
+                let ticks = u16::from_be_bytes([value[0], value[1]]);
+                if ticks == 0 {
+                    return Err(Nrc::RequestOutOfRange);
+                }
+                config.ticks_per_cm = ticks as f32 / 100.0;
+            }
+            DID_KL15_DIVIDER => {
+                let (r1, r2) = (u32_at(value, 0), u32_at(value, 4));
+                if r2 == 0 {
+                    return Err(Nrc::RequestOutOfRange);
+                }
+                (config.kl15_r1_ohm, config.kl15_r2_ohm) = (r1, r2);
+            }
+            DID_ULTRASOUND_MAP => {
+                if !config::valid_ultrasound_map(value) {
+                    return Err(Nrc::RequestOutOfRange);
+                }
+                config.ultrasound_map.copy_from_slice(value);
+            }
+            DID_ULTRASOUND_SCHEDULE => {
+                config.ultrasound_schedule =
+                    FiringSchedule::from_u8(value[0]).ok_or(Nrc::RequestOutOfRange)?;
+            }
+            _ => {
+                let (bitrate, data_bitrate) = (u32_at(value, 0), u32_at(value, 4));
+                if !config::valid_bitrates(bitrate, data_bitrate) {
+                    return Err(Nrc::RequestOutOfRange);
+                }
+                (config.can_bitrate, config.can_data_bitrate) = (bitrate, data_bitrate);
+            }
+        }
+        w.push(&[SID_WRITE_DATA + POSITIVE_RESPONSE])?;
+        w.push(&did.to_be_bytes())?;
+        Ok(Action::Save(w.len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::DEFAULT_CONFIG, isotp::MAX_PAYLOAD};
+
+    const VERSION: &str = "1.2.3";
+
+    struct Tester {
+        server: Server,
+        data: DiagData,
+        config: Config,
+    }
+
+    impl Tester {
+        fn new() -> Self {
+            Self {
+                server: Server::new(VERSION),
+                data: DiagData {
+                    kl15_mv: 12_000,
+                    speed_kmh: 0.0,
+                    distances: [UltrasoundResult::NotFitted; ULTRASOUND_CHANNELS],
+                    conditions: 0,
+                    history: 0,
+                },
+                config: DEFAULT_CONFIG,
+            }
+        }
+
+        /// The action and the response it sends.
+        fn request(
+            &mut self,
+            request: &[u8],
+            addressing: Addressing,
+            now_ms: u64,
+        ) -> (Action, Vec<u8>) {
+            let mut response = [0; MAX_PAYLOAD];
+            let action = self.server.handle(
+                request,
+                addressing,
+                now_ms,
+                &self.data,
+                &mut self.config,
+                &mut response,
+            );
+            let len = match action {
+                Action::None => 0,
+                Action::Respond(len) | Action::Reset(len) | Action::Save(len) => len,
+            };
+            (action, response[..len].to_vec())
+        }
+
+        fn physical(&mut self, request: &[u8]) -> Vec<u8> {
+            self.request(request, Addressing::Physical, 0).1
+        }
+
+        fn functional(&mut self, request: &[u8]) -> Action {
+            self.request(request, Addressing::Functional, 0).0
+        }
+
+        fn extended_session(&mut self, now_ms: u64) {
+            let (action, _) = self.request(&[0x10, 0x83], Addressing::Physical, now_ms);
+            assert_eq!(action, Action::None);
+        }
+    }
+
+    #[test]
+    fn empty_request() {
+        assert_eq!(Tester::new().functional(&[]), Action::None);
+        assert_eq!(Tester::new().physical(&[]), []);
+    }
+
+    #[test]
+    fn service_not_supported() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.physical(&[0x31, 0x01]), [0x7f, 0x31, 0x11]);
+        assert_eq!(tester.functional(&[0x31, 0x01]), Action::None);
+    }
+
+    #[test]
+    fn sub_function_not_supported() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.physical(&[0x10, 0x02]), [0x7f, 0x10, 0x12]);
+        assert_eq!(tester.physical(&[0x11, 0x02]), [0x7f, 0x11, 0x12]);
+        assert_eq!(tester.physical(&[0x19, 0x05]), [0x7f, 0x19, 0x12]);
+        assert_eq!(tester.functional(&[0x3e, 0x01]), Action::None);
+    }
+
+    #[test]
+    fn incorrect_length() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.physical(&[0x3e]), [0x7f, 0x3e, 0x13]);
+        assert_eq!(tester.physical(&[0x22, 0xf1]), [0x7f, 0x22, 0x13]);
+        assert_eq!(tester.physical(&[0x19, 0x01]), [0x7f, 0x19, 0x13]);
+        assert_eq!(tester.physical(&[0x2e, 0xfd, 0x10]), [0x7f, 0x2e, 0x13]);
+        // answered on functional requests as well
+        assert_eq!(tester.functional(&[0x3e, 0x00, 0x00]), Action::Respond(3));
+    }
+
+    #[test]
+    fn request_out_of_range() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.physical(&[0x22, 0x12, 0x34]), [0x7f, 0x22, 0x31]);
+        assert_eq!(tester.functional(&[0x22, 0x12, 0x34]), Action::None);
+    }
+
+    #[test]
+    fn write_needs_extended_session() {
+        let mut tester = Tester::new();
+        let write = [0x2e, 0xfd, 0x10, 0x18, 0x02];
+        assert_eq!(tester.physical(&write), [0x7f, 0x2e, 0x7f]);
+        assert_eq!(tester.functional(&write), Action::None);
+        assert!(tester.config.ticks_per_cm == DEFAULT_CONFIG.ticks_per_cm);
+    }
+
+    #[test]
+    fn reset_only_at_standstill() {
+        let mut tester = Tester::new();
+        for kmh in [1.0, -1.0] {
+            tester.data.speed_kmh = kmh;
+            assert_eq!(tester.physical(&[0x11, 0x01]), [0x7f, 0x11, 0x22]);
+            // conditions not correct is not suppressed
+            assert_eq!(tester.functional(&[0x11, 0x01]), Action::Respond(3));
+        }
+        tester.data.speed_kmh = -0.05;
+        assert_eq!(
+            tester.request(&[0x11, 0x03], Addressing::Physical, 0),
+            (Action::Reset(2), vec![0x51, 0x03])
+        );
+    }
+
+    #[test]
+    fn suppress_positive_response() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.functional(&[0x3e, 0x80]), Action::None);
+        assert_eq!(tester.functional(&[0x11, 0x81]), Action::Reset(0));
+        // the request is still carried out
+        tester.extended_session(0);
+        assert_eq!(
+            tester.physical(&[0x22, 0xf1, 0x86]),
+            [0x62, 0xf1, 0x86, 0x03]
+        );
+        // negative responses are not suppressed
+        assert_eq!(tester.physical(&[0x10, 0x82]), [0x7f, 0x10, 0x12]);
+    }
+
+    #[test]
+    fn session_control() {
+        let mut tester = Tester::new();
+        assert_eq!(
+            tester.physical(&[0x10, 0x03]),
+            [0x50, 0x03, 0x00, 0x32, 0x01, 0xf4]
+        );
+        assert_eq!(
+            tester.physical(&[0x22, 0xf1, 0x86]),
+            [0x62, 0xf1, 0x86, 0x03]
+        );
+        tester.physical(&[0x10, 0x01]);
+        assert_eq!(
+            tester.physical(&[0x22, 0xf1, 0x86]),
+            [0x62, 0xf1, 0x86, 0x01]
+        );
+    }
+
+    #[test]
+    fn session_timeout() {
+        let mut tester = Tester::new();
+        tester.extended_session(0);
+        // tester present keeps the session
+        tester.request(&[0x3e, 0x80], Addressing::Functional, S3_SERVER_MS);
+        let (_, response) =
+            tester.request(&[0x22, 0xf1, 0x86], Addressing::Physical, 2 * S3_SERVER_MS);
+        assert_eq!(response, [0x62, 0xf1, 0x86, 0x03]);
+
+        let write = [0x2e, 0xfd, 0x10, 0x18, 0x02];
+        let (_, response) = tester.request(&write, Addressing::Physical, 3 * S3_SERVER_MS + 1);
+        assert_eq!(response, [0x7f, 0x2e, 0x7f]);
+    }
+
+    #[test]
+    fn write_saves() {
+        let mut tester = Tester::new();
+        tester.extended_session(0);
+        assert_eq!(
+            tester.request(&[0x2e, 0xfd, 0x10, 0x18, 0x02], Addressing::Physical, 0),
+            (Action::Save(3), vec![0x6e, 0xfd, 0x10])
+        );
+        assert!((tester.config.ticks_per_cm - 61.46).abs() < 1e-4);
+
+        assert_eq!(
+            tester.physical(&[0x2e, 0xfd, 0x10, 0, 0]),
+            [0x7f, 0x2e, 0x31]
+        );
+        assert_eq!(
+            tester.physical(&[0x2e, 0xfd, 0x10, 0x18]),
+            [0x7f, 0x2e, 0x13]
+        );
+        // written by the calibration procedure only
+        assert_eq!(
+            tester.physical(&[0x2e, 0xfd, 0x12, 0, 0, 0, 0, 0, 0]),
+            [0x7f, 0x2e, 0x31]
+        );
+        assert!((tester.config.ticks_per_cm - 61.46).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ultrasound_schedule() {
+        let mut tester = Tester::new();
+        assert_eq!(tester.physical(&[0x22, 0xfd, 0x15]), [0x62, 0xfd, 0x15, 2]);
+
+        tester.extended_session(0);
+        assert_eq!(
+            tester.request(&[0x2e, 0xfd, 0x15, 0], Addressing::Physical, 0),
+            (Action::Save(3), vec![0x6e, 0xfd, 0x15])
+        );
+        assert!(tester.config.ultrasound_schedule == FiringSchedule::RoundRobin);
+        assert_eq!(tester.physical(&[0x2e, 0xfd, 0x15, 3]), [0x7f, 0x2e, 0x31]);
+        assert!(tester.config.ultrasound_schedule == FiringSchedule::RoundRobin);
+    }
+
+    #[test]
+    fn write_bitrate() {
+        let mut tester = Tester::new();
+        tester.extended_session(0);
+        let write = |bitrate: u32, data_bitrate: u32| {
+            let mut request = vec![0x2e, 0xfd, 0x14];
+            request.extend_from_slice(&bitrate.to_be_bytes());
+            request.extend_from_slice(&data_bitrate.to_be_bytes());
+            request
+        };
+
+        let (action, _) = tester.request(&write(250_000, 2_000_000), Addressing::Physical, 0);
+        assert_eq!(action, Action::Save(3));
+        assert_eq!(
+            (tester.config.can_bitrate, tester.config.can_data_bitrate),
+            (250_000, 2_000_000)
+        );
+
+        for (bitrate, data_bitrate) in [(500_000, 4_000_000), (333_333, 1_000_000), (500_000, 0)] {
+            assert_eq!(
+                tester.physical(&write(bitrate, data_bitrate)),
+                [0x7f, 0x2e, 0x31]
+            );
+        }
+        assert_eq!(
+            (tester.config.can_bitrate, tester.config.can_data_bitrate),
+            (250_000, 2_000_000)
+        );
+    }
+
+    #[test]
+    fn read_dtc() {
+        let mut tester = Tester::new();
+        tester.data.conditions = Status::Kl15Low.mask();
+        tester.data.history = Status::Kl15Low.mask() | Status::CanBusOff.mask();
+
+        // confirmed or failed
+        assert_eq!(
+            tester.physical(&[0x19, 0x01, 0x09]),
+            [0x59, 0x01, 0x09, 0x01, 0x00, 0x02]
+        );
+        // failed now
+        assert_eq!(
+            tester.physical(&[0x19, 0x01, 0x01]),
+            [0x59, 0x01, 0x09, 0x01, 0x00, 0x01]
+        );
+        assert_eq!(
+            tester.physical(&[0x19, 0x02, 0x01]),
+            [0x59, 0x02, 0x09, 0x05, 0x62, 0x00, 0x09]
+        );
+        let supported = tester.physical(&[0x19, 0x0a]);
+        assert_eq!(supported.len(), 3 + 4 * DTCS.len());
+        assert_eq!(supported[3..7], [0xc0, 0x73, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn read_several_identifiers() {
+        let mut tester = Tester::new();
+        tester.data.speed_kmh = 1.5;
+        // unknown identifiers in between are skipped
+        assert_eq!(
+            tester.physical(&[0x22, 0xfd, 0x00, 0x12, 0x34, 0xfd, 0x01]),
+            [0x62, 0xfd, 0x00, 0x2e, 0xe0, 0xfd, 0x01, 0x00, 0x96]
+        );
+        assert_eq!(
+            tester.physical(&[0x22, 0xf1, 0x95]),
+            [0x62, 0xf1, 0x95, b'1', b'.', b'2', b'.', b'3']
+        );
+    }
+
+    #[test]
+    fn response_too_long() {
+        let mut tester = Tester::new();
+        // 14 bytes per record, 10 of them do not fit
+        let mut request = vec![0x22];
+        for _ in 0..10 {
+            request.extend_from_slice(&[0xfd, 0x02]);
+        }
+        assert_eq!(tester.physical(&request), [0x7f, 0x22, 0x14]);
+        assert_eq!(tester.physical(&request[..19]), {
+            let mut response = vec![0x62];
+            for _ in 0..9 {
+                response.extend_from_slice(&[0xfd, 0x02]);
+                for _ in 0..ULTRASOUND_CHANNELS {
+                    response.extend_from_slice(&UltrasoundResult::NotFitted.encode().to_be_bytes());
+                }
+            }
+            response
+        });
+    }
+}
@@ -0,0 +1,354 @@
+// largest distance reported as a valid measurement
+pub const DISTANCE_MAX_MM: u16 = 10000;
+// status codes from the distance value tables in STM_BUS.dbc
+pub const DISTANCE_NO_ECHO: u16 = 65533;
+pub const DISTANCE_NOT_FITTED: u16 = 65534;
+pub const DISTANCE_TIMEOUT: u16 = 65535;
+
+// how long the last good measurement is reported after failures
+const MAX_MEASUREMENT_AGE_MS: u64 = 300;
+
+// used until the ambient temperature is received over CAN
+pub const DEFAULT_AMBIENT_TEMPERATURE_C: f32 = 20.0;
+
+/// Speed of sound in dry air in m/s.
+///
+/// Linear fit of `331.3 * sqrt(1 + T / 273.15)`, the error stays below 0.1 %
+/// between -20 and +50 °C which is below the sensor resolution.
+pub fn speed_of_sound(temperature_c: f32) -> f32 {
+    331.07 + 0.5915 * temperature_c
+}
+
+/// Distance to the obstacle in mm for the echo round trip time.
+pub fn echo_to_mm(echo_us: u64, temperature_c: f32) -> u64 {
+    (echo_us as f32 * speed_of_sound(temperature_c) / 2000.0) as u64
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UltrasoundResult {
+    /// echo pulse never started, the sensor is not responding
+    Timeout,
+    /// echo pulse did not end in time, no obstacle in range
+    NoEcho,
+    /// no sensor connected to the channel
+    NotFitted,
+    Measurement(u64),
+}
+
+impl UltrasoundResult {
+    /// Raw value of the distance CAN signal.
+    pub fn encode(&self) -> u16 {
+        match *self {
+            UltrasoundResult::Timeout => DISTANCE_TIMEOUT,
+            UltrasoundResult::NoEcho => DISTANCE_NO_ECHO,
+            UltrasoundResult::NotFitted => DISTANCE_NOT_FITTED,
+            UltrasoundResult::Measurement(mm) => mm.min(DISTANCE_MAX_MM as u64) as u16,
+        }
+    }
+}
+
+/// Per-sensor age and quality tracking.
+///
+/// A single missed echo is bridged by the last good measurement until it gets
+/// older than `MAX_MEASUREMENT_AGE_MS`, then the failure itself is reported.
+#[derive(Copy, Clone)]
+pub struct SensorTracker {
+    last_measurement: Option<(u64, u64)>,
+    // running ratio of successful measurements in per mille
+    quality: u16,
+}
+
+impl SensorTracker {
+    pub const fn new() -> Self {
+        Self {
+            last_measurement: None,
+            quality: 0,
+        }
+    }
+
+    pub fn update(&mut self, result: UltrasoundResult, now_ms: u64) -> UltrasoundResult {
+        let valid = matches!(result, UltrasoundResult::Measurement(_)) as u16;
+        self.quality = (self.quality * 7 + valid * 1000) / 8;
+
+        match result {
+            UltrasoundResult::Measurement(mm) => {
+                self.last_measurement = Some((mm, now_ms));
+                result
+            }
+            UltrasoundResult::NotFitted => result,
+            failure => match self.last_measurement {
+                Some((mm, ts)) if now_ms - ts <= MAX_MEASUREMENT_AGE_MS => {
+                    UltrasoundResult::Measurement(mm)
+                }
+                _ => {
+                    self.last_measurement = None;
+                    failure
+                }
+            },
+        }
+    }
+
+    /// Percentage of recent measurements that succeeded.
+    pub fn quality(&self) -> u8 {
+        (self.quality / 10) as u8
+    }
+}
+
+/// Order in which the sensors are fired.
+///
+/// Channels in the first half face forward and the second half backward,
+/// matching the `FRONT_DIST` and `REAR_DIST` CAN messages.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FiringSchedule {
+    /// one sensor at a time
+    RoundRobin = 0,
+    /// all front sensors together, then all rear sensors
+    FrontRearGroups = 1,
+    /// a front and a rear sensor together, they can not hear each other
+    CrosstalkAvoidance = 2,
+}
+
+impl FiringSchedule {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(FiringSchedule::RoundRobin),
+            1 => Some(FiringSchedule::FrontRearGroups),
+            2 => Some(FiringSchedule::CrosstalkAvoidance),
+            _ => None,
+        }
+    }
+
+    /// Channels fired together as bit masks, empty groups are zero.
+    pub fn groups<const N: usize>(self, fitted: u32) -> [u32; N] {
+        let front = N / 2;
+        let mut groups = [0; N];
+        match self {
+            FiringSchedule::RoundRobin => {
+                for (ch, group) in groups.iter_mut().enumerate() {
+                    *group = 1 << ch;
+                }
+            }
+            FiringSchedule::FrontRearGroups => {
+                // a single sensor counts as rear
+                let front_mask = (1 << front) - 1;
+                let rear_mask = ((1 << N) - 1) & !front_mask;
+                let mut masks = [front_mask, rear_mask]
+                    .into_iter()
+                    .filter(|&mask| mask != 0);
+                for group in groups.iter_mut() {
+                    *group = masks.next().unwrap_or(0);
+                }
+            }
+            FiringSchedule::CrosstalkAvoidance => {
+                for (ch, group) in groups.iter_mut().enumerate().take(N - front) {
+                    *group = 1 << (front + ch);
+                    if ch < front {
+                        *group |= 1 << ch;
+                    }
+                }
+            }
+        }
+
+        for group in groups.iter_mut() {
+            *group &= fitted;
+        }
+        groups
+    }
+}
+
+pub const ULTRASOUND_CHANNELS: usize = 6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_FITTED: u32 = u32::MAX;
+
+    // 331.3 * sqrt(1 + T / 273.15) in m/s
+    const SPEED_OF_SOUND: [(f32, f32); 8] = [
+        (-20.0, 318.94),
+        (-10.0, 325.18),
+        (0.0, 331.30),
+        (10.0, 337.31),
+        (20.0, 343.21),
+        (30.0, 349.02),
+        (40.0, 354.73),
+        (50.0, 360.35),
+    ];
+
+    #[test]
+    fn speed_of_sound_matches_reference() {
+        for (temperature_c, reference) in SPEED_OF_SOUND {
+            let error = (speed_of_sound(temperature_c) - reference).abs() / reference;
+            assert!(error < 0.001, "{temperature_c} °C off by {error}");
+        }
+    }
+
+    #[test]
+    fn echo_to_mm_follows_temperature() {
+        for (temperature_c, reference) in SPEED_OF_SOUND {
+            // round trip of an obstacle at 1 m
+            let echo_us = (2_000_000.0 / reference) as u64;
+            let mm = echo_to_mm(echo_us, temperature_c);
+            assert!(mm.abs_diff(1000) <= 2, "{temperature_c} °C gives {mm} mm");
+        }
+        assert_eq!(echo_to_mm(0, DEFAULT_AMBIENT_TEMPERATURE_C), 0);
+    }
+
+    // every fitted channel has to be fired exactly once per cycle
+    fn assert_covers<const N: usize>(groups: &[u32; N], fitted: u32) {
+        let mut seen = 0;
+        for &group in groups {
+            assert_eq!(seen & group, 0, "channel fired twice in {groups:?}");
+            seen |= group;
+        }
+        assert_eq!(seen, fitted & ((1 << N) - 1));
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(UltrasoundResult::Measurement(1234).encode(), 1234);
+        assert_eq!(
+            UltrasoundResult::Measurement(60_000).encode(),
+            DISTANCE_MAX_MM
+        );
+        assert_eq!(UltrasoundResult::Timeout.encode(), DISTANCE_TIMEOUT);
+        assert_eq!(UltrasoundResult::NoEcho.encode(), DISTANCE_NO_ECHO);
+        assert_eq!(UltrasoundResult::NotFitted.encode(), DISTANCE_NOT_FITTED);
+    }
+
+    #[test]
+    fn tracker_bridges_short_outage() {
+        let mut tracker = SensorTracker::new();
+        let mut update = |result, now_ms| tracker.update(result, now_ms).encode();
+        assert_eq!(update(UltrasoundResult::Measurement(500), 0), 500);
+        assert_eq!(update(UltrasoundResult::NoEcho, 100), 500);
+        assert_eq!(
+            update(UltrasoundResult::Timeout, MAX_MEASUREMENT_AGE_MS),
+            500
+        );
+
+        // too old, the failure is reported from now on
+        let stale = MAX_MEASUREMENT_AGE_MS + 1;
+        assert_eq!(update(UltrasoundResult::NoEcho, stale), DISTANCE_NO_ECHO);
+        assert_eq!(
+            update(UltrasoundResult::Timeout, stale + 10),
+            DISTANCE_TIMEOUT
+        );
+        assert_eq!(update(UltrasoundResult::Measurement(800), stale + 20), 800);
+    }
+
+    #[test]
+    fn tracker_reports_failure_without_measurement() {
+        let mut tracker = SensorTracker::new();
+        let result = tracker.update(UltrasoundResult::Timeout, 0);
+        assert_eq!(result.encode(), DISTANCE_TIMEOUT);
+
+        // not fitted is never bridged
+        tracker.update(UltrasoundResult::Measurement(500), 10);
+        let result = tracker.update(UltrasoundResult::NotFitted, 20);
+        assert_eq!(result.encode(), DISTANCE_NOT_FITTED);
+    }
+
+    #[test]
+    fn tracker_quality() {
+        let mut tracker = SensorTracker::new();
+        assert_eq!(tracker.quality(), 0);
+        for i in 0..50 {
+            tracker.update(UltrasoundResult::Measurement(500), i);
+        }
+        assert_eq!(tracker.quality(), 99);
+
+        // bridged failures still count as failures
+        for i in 50..52 {
+            tracker.update(UltrasoundResult::NoEcho, i);
+        }
+        assert!(tracker.quality() < 80);
+        for i in 52..100 {
+            tracker.update(UltrasoundResult::NoEcho, i);
+        }
+        assert_eq!(tracker.quality(), 0);
+    }
+
+    #[test]
+    fn round_robin_fires_one_sensor_at_a_time() {
+        let groups = FiringSchedule::RoundRobin.groups::<6>(ALL_FITTED);
+        assert_eq!(groups, [0b1, 0b10, 0b100, 0b1000, 0b1_0000, 0b10_0000]);
+    }
+
+    #[test]
+    fn front_rear_groups() {
+        let groups = FiringSchedule::FrontRearGroups.groups::<6>(ALL_FITTED);
+        assert_eq!(groups, [0b000_111, 0b111_000, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn single_sensor() {
+        for schedule in [
+            FiringSchedule::RoundRobin,
+            FiringSchedule::FrontRearGroups,
+            FiringSchedule::CrosstalkAvoidance,
+        ] {
+            assert_eq!(schedule.groups::<1>(ALL_FITTED), [1]);
+            assert_eq!(schedule.groups::<1>(0), [0]);
+            assert_eq!(schedule.groups::<0>(ALL_FITTED), []);
+            assert!(FiringSchedule::from_u8(schedule as u8) == Some(schedule));
+        }
+        assert!(FiringSchedule::from_u8(3).is_none());
+    }
+
+    #[test]
+    fn crosstalk_avoidance_pairs_front_and_rear() {
+        let groups = FiringSchedule::CrosstalkAvoidance.groups::<6>(ALL_FITTED);
+        assert_eq!(groups, [0b001_001, 0b010_010, 0b100_100, 0, 0, 0]);
+    }
+
+    #[test]
+    fn crosstalk_avoidance_never_fires_neighbours_together() {
+        fn check<const N: usize>() {
+            let front_mask = (1u32 << (N / 2)) - 1;
+            let groups = FiringSchedule::CrosstalkAvoidance.groups::<N>(ALL_FITTED);
+            assert_covers(&groups, ALL_FITTED);
+            for group in groups {
+                assert!((group & front_mask).count_ones() <= 1, "{groups:?}");
+                assert!((group & !front_mask).count_ones() <= 1, "{groups:?}");
+            }
+        }
+        check::<2>();
+        check::<4>();
+        check::<5>();
+        check::<6>();
+        check::<8>();
+    }
+
+    #[test]
+    fn all_schedules_cover_every_channel() {
+        for schedule in [
+            FiringSchedule::RoundRobin,
+            FiringSchedule::FrontRearGroups,
+            FiringSchedule::CrosstalkAvoidance,
+        ] {
+            assert_covers(&schedule.groups::<6>(ALL_FITTED), ALL_FITTED);
+            assert_covers(&schedule.groups::<3>(ALL_FITTED), ALL_FITTED);
+        }
+    }
+
+    #[test]
+    fn missing_sensors_are_not_fired() {
+        let fitted = 0b101_011;
+        for schedule in [
+            FiringSchedule::RoundRobin,
+            FiringSchedule::FrontRearGroups,
+            FiringSchedule::CrosstalkAvoidance,
+        ] {
+            let groups = schedule.groups::<6>(fitted);
+            assert_covers(&groups, fitted);
+        }
+        assert_eq!(
+            FiringSchedule::CrosstalkAvoidance.groups::<6>(fitted),
+            [0b001_001, 0b000_010, 0b100_000, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,6 @@
+MEMORY
+{
+  /* the last 8K hold the configuration store, see src/config_store.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 504K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 96K
+}
@@ -1,15 +1,34 @@
 use defmt::{error, info};
 use embassy_executor::task;
+use embassy_futures::select::{select, Either};
 use embassy_stm32::can::{
     frame::{FdFrame, Header},
-    CanRx, CanTx,
+    BusError, CanRx, CanTx,
 };
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_can::{Frame, Id, StandardId};
+use stm_board_logic::can_scheduler::{TxScheduler, TxTiming};
 
 use crate::{
-    messages::{self, Messages},
+    failsafe::Command,
+    filter::FilterConfig,
+    isotp,
+    kl15::KL15_ON_MV,
+    lighting::LightOverride,
+    lin_diag::NodeService,
+    lin_master,
+    messages::{self, MessageTiming, Messages, SendType},
+    params::ParamRequest,
+    rgb_effects::Effect,
+    status_led::{self, Status},
+    steering_cal::CalCommand,
+    uds::{self, Addressing, UdsFrame},
     ultrasound::UltrasoundResult,
-    KL15, SERVO_DEGREE, SPEED, ULTRASOUNDS,
+    AMBIENT_TEMPERATURE, BRAKE_INTERVENTION, COMMAND_MONITOR, FAILSAFE_STATUS, KL15,
+    LIGHTING_STEERING, LIGHT_OVERRIDE, LIGHT_STATUS, LIN_DIAG_REQUEST, LIN_DIAG_RESPONSE,
+    LIN_HEALTH, LIN_STATS_RESPONSE, MOTOR_TARGET_SPEED, PARAM_FILTER, PARAM_REQUEST,
+    PARAM_RESPONSE, RGB_EFFECT, SERVO_DEGREE, SPEED, STEERING_CAL_CMD, STEERING_CAL_STATUS, UDS_RX,
+    UDS_TX, ULTRASOUNDS,
 };
 
 fn to_embassy_frame<F: embedded_can::Frame>(frame: F) -> FdFrame {
@@ -17,13 +36,24 @@ fn to_embassy_frame<F: embedded_can::Frame>(frame: F) -> FdFrame {
     FdFrame::new(hdr, frame.data()).unwrap()
 }
 
+fn uds_response_frame(frame: &isotp::Frame) -> FdFrame {
+    let id = Id::Standard(StandardId::new(uds::RESPONSE_ID).unwrap());
+    FdFrame::new(Header::new(id, frame.len() as u8, false), frame).unwrap()
+}
+
 #[task]
 pub async fn can_rx(mut can_rx: CanRx<'static>) {
     let mut last_read_ts = embassy_time::Instant::now();
 
+    let received = |command| {
+        let now = embassy_time::Instant::now().as_millis();
+        COMMAND_MONITOR.lock(|monitor| monitor.borrow_mut().received(command, now));
+    };
+
     loop {
         match can_rx.read().await {
             Ok(envelope) => {
+                status_led::post(Status::CanBusOff, false);
                 let (ts, rx_frame) = (envelope.ts, envelope.frame);
                 let delta = (ts - last_read_ts).as_millis();
                 last_read_ts = ts;
@@ -36,65 +66,369 @@ pub async fn can_rx(mut can_rx: CanRx<'static>) {
                     delta,
                 );
 
+                if let Some(addressing) = uds::addressing(rx_frame.id()) {
+                    if UDS_RX.try_send(UdsFrame::new(addressing, payload)).is_err() {
+                        error!("UDS frame dropped");
+                    }
+                    continue;
+                }
+
                 let msg = messages::Messages::from_can_message(*rx_frame.id(), payload);
                 match msg {
                     Err(err) => info!("CAN RX err"),
                     Ok(frame) => match frame {
                         Messages::WheelAngle(frame) => {
+                            received(Command::WheelAngle);
                             SERVO_DEGREE.signal(frame.wheel_angle());
+                            LIGHTING_STEERING.signal(frame.wheel_angle());
                             info!("RX wheel angle: {}", frame.wheel_angle());
                         }
+                        Messages::DriveCmd(frame) => {
+                            received(Command::Drive);
+                            MOTOR_TARGET_SPEED.signal(frame.drive_target_speed());
+                            info!("RX drive speed: {}", frame.drive_target_speed());
+                        }
+                        Messages::UsFilterCfg(frame) => {
+                            let filter = FilterConfig {
+                                median: frame.filter_median(),
+                                outlier_rejection: frame.filter_outlier(),
+                                exponential: frame.filter_ema(),
+                                moving_average: frame.filter_mov_avg(),
+                                max_rate_mm_per_s: frame.filter_max_rate() as u64,
+                                alpha: frame.filter_ema_alpha(),
+                            };
+                            // range checked and applied by the parameter task
+                            PARAM_FILTER.signal(filter);
+                        }
+                        Messages::LinStatsReq(frame) => {
+                            let id = frame.lin_stats_id();
+                            match lin_master::frame_stats(id) {
+                                Some(stats) => LIN_STATS_RESPONSE.signal((id, stats)),
+                                None => info!("RX stats request for unknown LIN frame {}", id),
+                            }
+                        }
+                        Messages::LinDiagReq(frame) => {
+                            let params = [
+                                frame.lin_diag_param_1(),
+                                frame.lin_diag_param_2(),
+                                frame.lin_diag_param_3(),
+                                frame.lin_diag_param_4(),
+                                frame.lin_diag_param_5(),
+                            ];
+                            let sid = frame.lin_diag_sid_raw();
+                            match NodeService::from_request(frame.lin_diag_nad(), sid, params) {
+                                Some(service) => LIN_DIAG_REQUEST.signal(service),
+                                None => info!("RX invalid LIN diag request {:02x}", sid),
+                            }
+                        }
+                        Messages::LightCmd(frame) => {
+                            LIGHT_OVERRIDE.signal(LightOverride::from_raw(
+                                frame.light_cmd_headlights_raw(),
+                                frame.light_cmd_indicators_raw(),
+                            ));
+                        }
+                        Messages::RgbEffect(frame) => {
+                            RGB_EFFECT.signal(Effect::from_request(
+                                frame.rgb_effect_raw(),
+                                (frame.rgb_red(), frame.rgb_green(), frame.rgb_blue()),
+                                frame.rgb_period(),
+                                frame.rgb_param(),
+                            ));
+                        }
+                        Messages::SteerCalCmd(frame) => {
+                            let command = frame.steer_cal_cmd_raw();
+                            match CalCommand::from_request(command, frame.steer_cal_pulse()) {
+                                Some(command) => STEERING_CAL_CMD.signal(command),
+                                None => {
+                                    info!("RX invalid steering calibration command {}", command)
+                                }
+                            }
+                        }
+                        Messages::ParamReq(frame) => {
+                            let request = ParamRequest {
+                                command: frame.param_cmd_raw(),
+                                index: frame.param_index(),
+                                value: frame.param_value(),
+                            };
+                            if PARAM_REQUEST.try_send(request).is_err() {
+                                error!("Parameter request {} dropped", request);
+                            }
+                        }
+                        Messages::BmcAcceleration(frame) => {
+                            AMBIENT_TEMPERATURE.signal(frame.temperature());
+                        }
                         _ => info!("RX unneeded message"),
                     },
                 };
             }
+            Err(BusError::BusOff) => {
+                error!("CAN bus off");
+                status_led::post(Status::CanBusOff, true);
+            }
             Err(_err) => error!("Error in frame, {:?}", _err),
         }
     }
 }
 
+const TX_TICK_MS: u64 = 10;
+
+const TX_SPEED: usize = 0;
+const TX_FRONT_DIST: usize = 1;
+const TX_REAR_DIST: usize = 2;
+const TX_KL15: usize = 3;
+const TX_STATUS: usize = 4;
+const TX_LIN_STATUS: usize = 5;
+const TX_LIN_STATS: usize = 6;
+const TX_LIN_DIAG: usize = 7;
+const TX_LIGHT_STATUS: usize = 8;
+const TX_STEER_CAL: usize = 9;
+const TX_PARAM: usize = 10;
+
+// highest code of `Param_Resp_Cmd`
+const PARAM_RESP_CMD_MAX: u8 = 3;
+
+// LIN_STATS carries latencies in 0.25 ms steps up to 63.75 ms
+fn encode_latency(latency: Duration) -> f32 {
+    (latency.as_micros() as f32 / 1000.0).min(63.75)
+}
+
+fn tx_timing<M: MessageTiming>() -> TxTiming {
+    TxTiming {
+        cycle_ms: M::CYCLE_TIME_MS,
+        delay_ms: M::DELAY_TIME_MS,
+        cyclic: M::SEND_TYPE != SendType::OnChange,
+        on_change: M::SEND_TYPE != SendType::Cyclic,
+    }
+}
+
 #[task]
 pub async fn can_tx(mut can_tx: CanTx<'static>) {
-    let mut i = 0u8;
-
-    let mut msg_rear = messages::RearDist::new(3, 2, 1).unwrap();
-    let mut msg_front = messages::FrontDist::new(3, 2, 1).unwrap();
-    let mut msg_speed = messages::SpeedKmh::new(i as f32).unwrap();
+    let not_fitted = UltrasoundResult::NotFitted.encode();
+    let mut msg_rear = messages::RearDist::new(not_fitted, not_fitted, not_fitted).unwrap();
+    let mut msg_front = messages::FrontDist::new(not_fitted, not_fitted, not_fitted).unwrap();
+    let mut msg_speed = messages::SpeedKmh::new(0.0).unwrap();
     let mut msg_kl15 = messages::Kl15::new(false, 0).unwrap();
+    let mut msg_status = messages::StmStatus::new(false, false, 0).unwrap();
+    let mut msg_lin_status = messages::LinStatus::new(0, 0, 0, false).unwrap();
+    let mut msg_lin_stats = messages::LinStats::new(0, 0, 0, 0, 0, 0.0, 0.0).unwrap();
+    let mut msg_lin_diag = messages::LinDiagResp::new(0, 0, 0, 0, 0, 0, 0, 0).unwrap();
+    let mut msg_light_status =
+        messages::LightStatus::new(false, false, false, false, false, false, false, 0).unwrap();
+    let mut msg_steer_cal = messages::SteerCalStatus::new(0, 0, 0, 0, 0).unwrap();
+    let mut msg_param = messages::ParamResp::new(0, 0, 0, 0).unwrap();
+
+    let mut scheduler = TxScheduler::new(
+        [
+            tx_timing::<messages::SpeedKmh>(),
+            tx_timing::<messages::FrontDist>(),
+            tx_timing::<messages::RearDist>(),
+            tx_timing::<messages::Kl15>(),
+            tx_timing::<messages::StmStatus>(),
+            tx_timing::<messages::LinStatus>(),
+            tx_timing::<messages::LinStats>(),
+            tx_timing::<messages::LinDiagResp>(),
+            tx_timing::<messages::LightStatus>(),
+            tx_timing::<messages::SteerCalStatus>(),
+            tx_timing::<messages::ParamResp>(),
+        ],
+        Instant::now().as_millis(),
+        TX_TICK_MS,
+    );
 
+    let mut ticker = Ticker::every(Duration::from_millis(TX_TICK_MS));
     loop {
         if let Some(val) = SPEED.try_take() {
+            let prev = msg_speed;
             msg_speed.set_speed_kmh(val).unwrap();
+            if prev.data() != msg_speed.data() {
+                scheduler.changed(TX_SPEED);
+            }
         }
 
         if let Some(results) = ULTRASOUNDS.try_take() {
-            let map = |val| match val {
-                UltrasoundResult::Fail => 0x0u16,
-                UltrasoundResult::Measurement(val) => val as u16,
-            };
-            if let UltrasoundResult::Measurement(val) = results[0] {
-                msg_front.set_front_dist_1(val as u16).unwrap();
+            let (prev_front, prev_rear) = (msg_front, msg_rear);
+            msg_front.set_front_dist_1(results[0].encode()).unwrap();
+            msg_front.set_front_dist_2(results[1].encode()).unwrap();
+            msg_front.set_front_dist_3(results[2].encode()).unwrap();
+            msg_rear.set_rear_dist_1(results[3].encode()).unwrap();
+            msg_rear.set_rear_dist_2(results[4].encode()).unwrap();
+            msg_rear.set_rear_dist_3(results[5].encode()).unwrap();
+            if prev_front.data() != msg_front.data() {
+                scheduler.changed(TX_FRONT_DIST);
             }
-            if let UltrasoundResult::Measurement(val) = results[1] {
-                msg_front.set_front_dist_2(val as u16).unwrap();
+            if prev_rear.data() != msg_rear.data() {
+                scheduler.changed(TX_REAR_DIST);
             }
-            msg_front.set_front_dist_3(map(results[2])).unwrap();
-            msg_rear.set_rear_dist_1(map(results[3])).unwrap();
-            msg_rear.set_rear_dist_2(map(results[4])).unwrap();
-            msg_rear.set_rear_dist_3(map(results[5])).unwrap();
         }
 
         if let Some(millivolts) = KL15.try_take() {
+            let prev = msg_kl15;
             msg_kl15.set_kl15_voltage(millivolts).unwrap();
-            msg_kl15.set_kl15_on(millivolts > 11000).unwrap();
+            msg_kl15.set_kl15_on(millivolts > KL15_ON_MV).unwrap();
+            if prev.data() != msg_kl15.data() {
+                scheduler.changed(TX_KL15);
+            }
+        }
+
+        if let Some(timed_out) = FAILSAFE_STATUS.try_take() {
+            let prev = msg_status;
+            msg_status.set_failsafe_active(timed_out != 0).unwrap();
+            msg_status.set_cmd_timeout_mask(timed_out).unwrap();
+            if prev.data() != msg_status.data() {
+                scheduler.changed(TX_STATUS);
+            }
         }
 
-        can_tx.write_fd(&to_embassy_frame(msg_rear)).await;
-        can_tx.write_fd(&to_embassy_frame(msg_front)).await;
-        can_tx.write_fd(&to_embassy_frame(msg_speed)).await;
-        can_tx.write_fd(&to_embassy_frame(msg_kl15)).await;
+        if let Some(intervention) = BRAKE_INTERVENTION.try_take() {
+            let prev = msg_status;
+            msg_status.set_brake_intervention(intervention).unwrap();
+            if prev.data() != msg_status.data() {
+                scheduler.changed(TX_STATUS);
+            }
+        }
+
+        if let Some(health) = LIN_HEALTH.try_take() {
+            let prev = msg_lin_status;
+            msg_lin_status
+                .set_lin_fault_mask(health.fault_mask)
+                .unwrap();
+            msg_lin_status
+                .set_lin_last_error(health.last_error.map_or(0, |err| err as u8))
+                .unwrap();
+            msg_lin_status
+                .set_lin_error_count(health.error_count)
+                .unwrap();
+            msg_lin_status.set_lin_sleeping(health.sleeping).unwrap();
+            if prev.data() != msg_lin_status.data() {
+                scheduler.changed(TX_LIN_STATUS);
+            }
+        }
+
+        if let Some((id, stats)) = LIN_STATS_RESPONSE.try_take() {
+            let counter = |value: u32| value.min(u8::MAX as u32) as u8;
+            msg_lin_stats.set_lin_stats_id(id).unwrap();
+            msg_lin_stats.set_lin_stats_sent(stats.sent as u16).unwrap();
+            msg_lin_stats
+                .set_lin_stats_checksum_err(counter(stats.checksum_errors))
+                .unwrap();
+            msg_lin_stats
+                .set_lin_stats_no_response(counter(stats.no_response))
+                .unwrap();
+            msg_lin_stats
+                .set_lin_stats_bus_err(counter(stats.bus_errors))
+                .unwrap();
+            msg_lin_stats
+                .set_lin_stats_latency(encode_latency(stats.last_latency))
+                .unwrap();
+            msg_lin_stats
+                .set_lin_stats_max_latency(encode_latency(stats.max_latency))
+                .unwrap();
+            // answered on every request, even when nothing changed
+            scheduler.changed(TX_LIN_STATS);
+        }
 
-        Timer::after_millis(250).await;
-        i = i.wrapping_add(1);
+        if let Some(response) = LIN_DIAG_RESPONSE.try_take() {
+            let [d1, d2, d3, d4, d5] = response.data;
+            msg_lin_diag = messages::LinDiagResp::new(
+                response.nad,
+                response.sid,
+                response.status as u8,
+                d1,
+                d2,
+                d3,
+                d4,
+                d5,
+            )
+            .unwrap();
+            scheduler.changed(TX_LIN_DIAG);
+        }
+
+        if let Some(state) = LIGHT_STATUS.try_take() {
+            let prev = msg_light_status;
+            msg_light_status = messages::LightStatus::new(
+                state.headlights,
+                state.brake,
+                state.reverse,
+                state.indicator_left,
+                state.indicator_right,
+                state.night,
+                state.overridden,
+                state.brightness,
+            )
+            .unwrap();
+            if prev.data() != msg_light_status.data() {
+                scheduler.changed(TX_LIGHT_STATUS);
+            }
+        }
+
+        if let Some(status) = STEERING_CAL_STATUS.try_take() {
+            msg_steer_cal = messages::SteerCalStatus::new(
+                status.state as u8,
+                status.error.map_or(0, |err| err as u8),
+                status.left_us,
+                status.center_us,
+                status.right_us,
+            )
+            .unwrap();
+            // every command is answered, also rejected ones
+            scheduler.changed(TX_STEER_CAL);
+        }
+
+        if let Ok(response) = PARAM_RESPONSE.try_receive() {
+            // unknown commands are reported by the status, `Param_Resp_Cmd`
+            // only holds the known ones
+            match messages::ParamResp::new(
+                response.command.min(PARAM_RESP_CMD_MAX),
+                response.index,
+                response.error.map_or(0, |err| err as u8),
+                response.value,
+            ) {
+                Ok(msg) => {
+                    msg_param = msg;
+                    scheduler.changed(TX_PARAM);
+                }
+                Err(_) => error!("Parameter response {} out of range", response),
+            }
+        }
+
+        let due = scheduler.poll(Instant::now().as_millis());
+        if due & (1 << TX_SPEED) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_speed)).await;
+        }
+        if due & (1 << TX_FRONT_DIST) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_front)).await;
+        }
+        if due & (1 << TX_REAR_DIST) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_rear)).await;
+        }
+        if due & (1 << TX_KL15) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_kl15)).await;
+        }
+        if due & (1 << TX_STATUS) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_status)).await;
+        }
+        if due & (1 << TX_LIN_STATUS) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_lin_status)).await;
+        }
+        if due & (1 << TX_LIN_STATS) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_lin_stats)).await;
+        }
+        if due & (1 << TX_LIN_DIAG) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_lin_diag)).await;
+        }
+        if due & (1 << TX_LIGHT_STATUS) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_light_status)).await;
+        }
+        if due & (1 << TX_STEER_CAL) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_steer_cal)).await;
+        }
+        if due & (1 << TX_PARAM) != 0 {
+            can_tx.write_fd(&to_embassy_frame(msg_param)).await;
+        }
+        // ISO-TP frames leave as soon as the UDS task queues them, its
+        // transmitter paces them, not the schedule
+        while let Either::Second(frame) = select(ticker.next(), UDS_TX.receive()).await {
+            can_tx.write_fd(&uds_response_frame(&frame)).await;
+        }
     }
 }
@@ -0,0 +1,213 @@
+use defmt::{info, warn};
+use embassy_executor::task;
+
+pub use stm_board_logic::config::{Config, DEFAULT_CONFIG};
+
+use crate::{
+    config_store::{ConfigStore, FlashStorage, Storage, StoreError},
+    filter::DEFAULT_FILTER,
+    motor::DEFAULT_MOTOR,
+    params::{Access, PARAMS},
+    steering::DEFAULT_STEERING,
+    steering_cal::CalibrationRecord,
+    ultrasound::{FiringSchedule, ULTRASOUND_CHANNELS},
+    CONFIG, CONFIG_SAVE, PARAMS_SAVED, STEERING_CAL_SAVED, UDS_CONFIG_SAVED,
+};
+
+/// Keys of the configuration store, never reuse a retired key.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Key {
+    /// f32
+    TicksPerCm = 1,
+    /// KL15 divider r1, r2 in ohm as u32
+    Kl15Divider = 2,
+    /// left, center, right pulse in us as u16
+    Steering = 3,
+    /// sensor channel of every reported position, 0xff when not fitted
+    UltrasoundMap = 4,
+    /// nominal and FD data bitrate as u32
+    CanBitrate = 5,
+    /// `FiringSchedule` as u8
+    UltrasoundSchedule = 6,
+}
+
+/// Tuning parameter `index` of `PARAMS` is saved under this key plus the
+/// index as its raw i32.
+const PARAM_KEY_BASE: u16 = 0x100;
+
+fn u16_at(value: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([value[i], value[i + 1]])
+}
+
+fn u32_at(value: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]])
+}
+
+fn join(a: u32, b: u32) -> [u8; 8] {
+    let mut value = [0; 8];
+    value[..4].copy_from_slice(&a.to_le_bytes());
+    value[4..].copy_from_slice(&b.to_le_bytes());
+    value
+}
+
+/// Configuration saved in the store, missing or invalid keys keep the default.
+pub fn from_store<S: Storage>(store: &ConfigStore<S>) -> Config {
+    let mut config = DEFAULT_CONFIG;
+    let get = |key: Key, len: usize| store.get(key as u16).filter(|value| value.len() == len);
+
+    if let Some(value) = get(Key::TicksPerCm, 4) {
+        let ticks = f32::from_bits(u32_at(value, 0));
+        if ticks > 0.0 {
+            config.ticks_per_cm = ticks;
+        }
+    }
+    if let Some(value) = get(Key::Kl15Divider, 8) {
+        let (r1, r2) = (u32_at(value, 0), u32_at(value, 4));
+        if r2 > 0 {
+            (config.kl15_r1_ohm, config.kl15_r2_ohm) = (r1, r2);
+        }
+    }
+    if let Some(value) = get(Key::Steering, 6) {
+        let steering = CalibrationRecord {
+            left_us: u16_at(value, 0),
+            center_us: u16_at(value, 2),
+            right_us: u16_at(value, 4),
+        };
+        if steering.is_valid() {
+            config.steering = steering;
+        }
+    }
+    if let Some(value) = get(Key::UltrasoundMap, ULTRASOUND_CHANNELS) {
+        if valid_ultrasound_map(value) {
+            config.ultrasound_map.copy_from_slice(value);
+        }
+    }
+    if let Some(value) = get(Key::UltrasoundSchedule, 1) {
+        if let Some(schedule) = FiringSchedule::from_u8(value[0]) {
+            config.ultrasound_schedule = schedule;
+        }
+    }
+    if let Some(value) = get(Key::CanBitrate, 8) {
+        let (bitrate, data_bitrate) = (u32_at(value, 0), u32_at(value, 4));
+        if valid_bitrates(bitrate, data_bitrate) {
+            (config.can_bitrate, config.can_data_bitrate) = (bitrate, data_bitrate);
+        }
+    }
+    for (index, param) in PARAMS.iter().enumerate() {
+        if let Some(raw) = get_param(store, index) {
+            // out of range values keep the default
+            let _ = param.write(&mut config, raw);
+        }
+    }
+    if !config.is_consistent() {
+        warn!("Inconsistent tuning parameters, using the defaults");
+        config.motor = DEFAULT_MOTOR;
+        config.steering_limits = DEFAULT_STEERING;
+        config.filter = DEFAULT_FILTER;
+    }
+    config
+}
+
+/// Writes every key in one transaction.
+pub fn save<S: Storage>(config: &Config, store: &mut ConfigStore<S>) -> Result<(), StoreError> {
+    let steering = &config.steering;
+    let mut steering_value = [0; 6];
+    steering_value[0..2].copy_from_slice(&steering.left_us.to_le_bytes());
+    steering_value[2..4].copy_from_slice(&steering.center_us.to_le_bytes());
+    steering_value[4..6].copy_from_slice(&steering.right_us.to_le_bytes());
+
+    let ticks_value = config.ticks_per_cm.to_bits().to_le_bytes();
+    let divider_value = join(config.kl15_r1_ohm, config.kl15_r2_ohm);
+    let bitrate_value = join(config.can_bitrate, config.can_data_bitrate);
+    let schedule_value = [config.ultrasound_schedule as u8];
+    let typed: [(u16, &[u8]); TYPED_KEYS] = [
+        (Key::TicksPerCm as u16, &ticks_value),
+        (Key::Kl15Divider as u16, &divider_value),
+        (Key::Steering as u16, &steering_value),
+        (Key::UltrasoundMap as u16, &config.ultrasound_map),
+        (Key::CanBitrate as u16, &bitrate_value),
+        (Key::UltrasoundSchedule as u16, &schedule_value),
+    ];
+
+    let param_values = PARAMS.map(|param| param.read(config).to_le_bytes());
+    let mut changes: [(u16, &[u8]); TYPED_KEYS + PARAMS.len()] =
+        [(0, &[]); TYPED_KEYS + PARAMS.len()];
+    changes[..TYPED_KEYS].copy_from_slice(&typed);
+    let mut len = TYPED_KEYS;
+    for (index, param) in PARAMS.iter().enumerate() {
+        // read only parameters mirror the typed keys
+        if param.access != Access::ReadOnly {
+            changes[len] = (param_key(index), &param_values[index]);
+            len += 1;
+        }
+    }
+    store.commit(&changes[..len])
+}
+
+const TYPED_KEYS: usize = 6;
+
+fn param_key(index: usize) -> u16 {
+    PARAM_KEY_BASE + index as u16
+}
+
+fn get_param<S: Storage>(store: &ConfigStore<S>, index: usize) -> Option<i32> {
+    let value: [u8; 4] = store.get(param_key(index))?.try_into().ok()?;
+    Some(i32::from_le_bytes(value))
+}
+
+/// Task waiting for the result of a save.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigClient {
+    SteeringCalibration,
+    /// the parameter service, its changes are already in `CONFIG`
+    Parameters,
+    /// diagnostic writes of values only read at startup
+    Diagnostics,
+}
+
+/// Saves configurations, every save fails when the store could not be
+/// mounted.
+#[task]
+pub async fn config_task(mut store: Option<ConfigStore<FlashStorage>>) {
+    loop {
+        let (client, config) = CONFIG_SAVE.receive().await;
+        let result = match store.as_mut() {
+            Some(store) => save(&config, store),
+            None => Err(StoreError::Flash),
+        };
+        let saved = match result {
+            Ok(()) => {
+                CONFIG.lock(|current| {
+                    let mut current = current.borrow_mut();
+                    match client {
+                        ConfigClient::SteeringCalibration => current.steering = config.steering,
+                        ConfigClient::Parameters => {}
+                        // reported as saved, in effect after the next reset
+                        ConfigClient::Diagnostics => {
+                            current.ticks_per_cm = config.ticks_per_cm;
+                            (current.kl15_r1_ohm, current.kl15_r2_ohm) =
+                                (config.kl15_r1_ohm, config.kl15_r2_ohm);
+                            current.ultrasound_map = config.ultrasound_map;
+                            current.ultrasound_schedule = config.ultrasound_schedule;
+                            (current.can_bitrate, current.can_data_bitrate) =
+                                (config.can_bitrate, config.can_data_bitrate);
+                        }
+                    }
+                });
+                true
+            }
+            Err(err) => {
+                warn!("Saving the configuration failed: {}", err);
+                false
+            }
+        };
+        info!("Configuration saved for {}: {}", client, saved);
+        match client {
+            ConfigClient::SteeringCalibration => STEERING_CAL_SAVED.signal(saved),
+            ConfigClient::Parameters => PARAMS_SAVED.signal(saved),
+            ConfigClient::Diagnostics => UDS_CONFIG_SAVED.signal(saved),
+        }
+    }
+}
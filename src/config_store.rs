@@ -0,0 +1,37 @@
+use embassy_stm32::flash::{Blocking, Flash};
+
+pub use stm_board_logic::config_store::{ConfigStore, Storage, StoreError, PAGE_SIZE};
+
+// the last 4 pages of the 512 KiB flash, memory.x keeps the program out
+const REGION_OFFSET: u32 = 0x7_e000;
+
+pub struct FlashStorage {
+    flash: Flash<'static, Blocking>,
+}
+
+impl FlashStorage {
+    pub fn new(flash: Flash<'static, Blocking>) -> Self {
+        Self { flash }
+    }
+}
+
+impl Storage for FlashStorage {
+    fn read(&mut self, offset: u32, data: &mut [u8]) -> Result<(), StoreError> {
+        self.flash
+            .blocking_read(REGION_OFFSET + offset, data)
+            .map_err(|_| StoreError::Flash)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StoreError> {
+        self.flash
+            .blocking_write(REGION_OFFSET + offset, data)
+            .map_err(|_| StoreError::Flash)
+    }
+
+    fn erase_page(&mut self, page: u32) -> Result<(), StoreError> {
+        let start = REGION_OFFSET + page * PAGE_SIZE;
+        self.flash
+            .blocking_erase(start, start + PAGE_SIZE)
+            .map_err(|_| StoreError::Flash)
+    }
+}
@@ -0,0 +1,54 @@
+use defmt::{info, warn};
+use embassy_executor::task;
+use embassy_time::{Instant, Timer};
+
+pub use stm_board_logic::failsafe::{Command, CommandMonitor, COMMAND_COUNT};
+
+use crate::{
+    messages::{self, MessageTiming},
+    status_led::{self, Status},
+    COMMAND_MONITOR, FAILSAFE_STATUS, MOTOR_STOP, SERVO_DEGREE,
+};
+
+const CHECK_PERIOD_MS: u64 = 10;
+// number of missed cycles after which a command is considered lost
+const COMMAND_TIMEOUT_CYCLES: u64 = 3;
+
+/// Timeouts of the commands, indexed by `Command`.
+pub const COMMAND_TIMEOUT_MS: [u64; COMMAND_COUNT] = [
+    messages::WheelAngle::CYCLE_TIME_MS * COMMAND_TIMEOUT_CYCLES,
+    messages::DriveCmd::CYCLE_TIME_MS * COMMAND_TIMEOUT_CYCLES,
+];
+
+// signalled once on entry, a timed out command sends no new setpoints
+fn enter_safe_state(command: Command) {
+    match command {
+        Command::WheelAngle => SERVO_DEGREE.signal(0.0),
+        Command::Drive => MOTOR_STOP.signal(()),
+    }
+}
+
+#[task]
+pub async fn failsafe_task() {
+    loop {
+        let now = Instant::now().as_millis();
+        let transition = COMMAND_MONITOR.lock(|monitor| monitor.borrow_mut().update(now));
+
+        for command in Command::ALL {
+            if transition.lost & command.mask() != 0 {
+                warn!("Command {} timed out, entering safe state", command);
+                enter_safe_state(command);
+            }
+            if transition.recovered & command.mask() != 0 {
+                info!("Command {} recovered", command);
+            }
+        }
+
+        if transition.lost != 0 || transition.recovered != 0 {
+            FAILSAFE_STATUS.signal(transition.timed_out);
+            status_led::post(Status::FailsafeActive, transition.timed_out != 0);
+        }
+
+        Timer::after_millis(CHECK_PERIOD_MS).await;
+    }
+}
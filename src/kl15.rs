@@ -6,16 +6,30 @@ use embassy_stm32::{
 };
 use embassy_time::Timer;
 
-use crate::KL15;
+use crate::{
+    status_led::{self, Status},
+    KL15, LIN_KL15, UDS_KL15,
+};
+
+/// KL15 voltage above which the ignition is considered on.
+pub const KL15_ON_MV: u16 = 11000;
 
 pub struct KL15 {
     adc: Adc<'static, ADC1>,
     pin: PC0,
+    r1_ohm: u32,
+    r2_ohm: u32,
 }
 
 impl KL15 {
-    pub fn new(adc: Adc<'static, ADC1>, pin: PC0) -> Self {
-        Self { adc, pin }
+    /// `r1_ohm` and `r2_ohm` form the divider in front of the ADC pin.
+    pub fn new(adc: Adc<'static, ADC1>, pin: PC0, r1_ohm: u32, r2_ohm: u32) -> Self {
+        Self {
+            adc,
+            pin,
+            r1_ohm,
+            r2_ohm,
+        }
     }
 
     pub fn read(&mut self) -> u16 {
@@ -31,16 +45,20 @@ impl KL15 {
         let millivolts = u32::from(sample) * VREFINT_MV / u32::from(vrefint_sample);
 
         // calculate voltage before divider
-        let r1: u32 = 4700;
-        let r2: u32 = 1500;
-        (millivolts * (r1 + r2) / r2) as u16
+        (millivolts * (self.r1_ohm + self.r2_ohm) / self.r2_ohm) as u16
     }
 }
 
 #[task]
 pub async fn measure_kl15(mut kl15: KL15) {
     loop {
-        KL15.signal(kl15.read());
+        let millivolts = kl15.read();
+        KL15.signal(millivolts);
+        LIN_KL15.signal(millivolts);
+        UDS_KL15.signal(millivolts);
+        status_led::post(Status::Kl15Low, millivolts <= KL15_ON_MV);
+        #[cfg(feature = "lin-slave")]
+        crate::LIN_SLAVE_KL15.signal(millivolts);
         Timer::after_millis(100).await;
     }
 }
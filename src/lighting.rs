@@ -0,0 +1,61 @@
+use defmt::info;
+use embassy_executor::task;
+use embassy_time::{Instant, Timer};
+pub use stm_board_logic::lighting::{LightOverride, LightState};
+use stm_board_logic::lighting::{LightingController, DEFAULT_LIGHTING};
+
+use crate::{
+    lin_frames::{LIN_FRAME_LEDS, LIN_FRAME_PHOTORES, LIN_FRAME_RGB},
+    lin_master::{publish, take_received},
+    LIGHTING_SPEED, LIGHTING_STEERING, LIGHT_OVERRIDE, LIGHT_STATUS, RGB_EFFECT,
+};
+
+const LIGHTING_PERIOD_MS: u64 = 50;
+
+#[task]
+pub async fn lighting_task() {
+    let mut controller = LightingController::new(DEFAULT_LIGHTING);
+    let mut reported = None;
+    // effect and its start time
+    let mut effect = None;
+
+    loop {
+        let now = Instant::now().as_millis();
+
+        // polled by the schedule table, None until a new reading arrived
+        if let Some(fr) = take_received(LIN_FRAME_PHOTORES) {
+            let data = fr.data();
+            controller.brightness(u16::from_le_bytes([data[0], data[1]]), now);
+        }
+        if let Some(kmh) = LIGHTING_SPEED.try_take() {
+            controller.speed(kmh, now);
+        }
+        if let Some(angle) = LIGHTING_STEERING.try_take() {
+            controller.steering(angle);
+        }
+        if let Some(light_override) = LIGHT_OVERRIDE.try_take() {
+            info!("Light override {}", light_override);
+            controller.set_override(light_override);
+        }
+        if let Some(selected) = RGB_EFFECT.try_take() {
+            info!("RGB effect {}", selected);
+            effect = selected.map(|selected| (selected, now));
+        }
+
+        let state = controller.state();
+        publish(LIN_FRAME_LEDS, &[state.leds(controller.blink(now))]);
+        // brake and reverse light take precedence over effects
+        let (r, g, b) = match effect {
+            Some((effect, start)) if !state.brake && !state.reverse => effect.render(now - start),
+            _ => state.rear_lamp(),
+        };
+        publish(LIN_FRAME_RGB, &[r, g, b]);
+
+        if reported != Some(state) {
+            reported = Some(state);
+            LIGHT_STATUS.signal(state);
+        }
+
+        Timer::after_millis(LIGHTING_PERIOD_MS).await;
+    }
+}
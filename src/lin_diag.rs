@@ -0,0 +1,89 @@
+use defmt::{info, warn};
+use embassy_executor::task;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    lin_frames::{Schedule, LIN_FRAME_MASTER_REQ, LIN_FRAME_SLAVE_RESP},
+    lin_master::{cancel, is_pending, publish, request, take_received},
+    LIN_DIAG_REQUEST, LIN_DIAG_RESPONSE, LIN_SCHEDULE,
+};
+
+pub use stm_board_logic::lin_diag::{
+    DiagResponse, DiagStatus, NodeService, Reassembler, Segmenter, NAD_BROADCAST,
+};
+
+// time a slave may take until its response is complete
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+const POLL_PERIOD_MS: u64 = 10;
+
+/// Waits until `done` returns a value or the response timeout expires.
+async fn wait_for<T>(mut done: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    loop {
+        if let Some(value) = done() {
+            return Some(value);
+        }
+        if Instant::now() > deadline {
+            return None;
+        }
+        Timer::after_millis(POLL_PERIOD_MS).await;
+    }
+}
+
+async fn transaction(service: &NodeService) -> DiagResponse {
+    let (message, len) = service.encode();
+    let segments = match Segmenter::new(service.nad(), &message[..len]) {
+        Ok(segments) => segments,
+        Err(_) => return DiagResponse::status(service, DiagStatus::Transport),
+    };
+
+    for pdu in segments {
+        publish(LIN_FRAME_MASTER_REQ, &pdu);
+        if wait_for(|| (!is_pending(LIN_FRAME_MASTER_REQ)).then_some(()))
+            .await
+            .is_none()
+        {
+            cancel(LIN_FRAME_MASTER_REQ);
+            return DiagResponse::status(service, DiagStatus::Timeout);
+        }
+    }
+
+    let mut reassembler = Reassembler::new();
+    loop {
+        request(LIN_FRAME_SLAVE_RESP);
+        let Some(pdu) = wait_for(|| take_received(LIN_FRAME_SLAVE_RESP)).await else {
+            cancel(LIN_FRAME_SLAVE_RESP);
+            return DiagResponse::status(service, DiagStatus::Timeout);
+        };
+
+        let expected_nad = service.nad();
+        match reassembler.feed(pdu.data()) {
+            Ok(None) => {}
+            Ok(Some((nad, _))) if expected_nad != NAD_BROADCAST && nad != expected_nad => {
+                return DiagResponse::status(service, DiagStatus::Unexpected);
+            }
+            Ok(Some((nad, message))) => return DiagResponse::parse(service, nad, message),
+            Err(err) => {
+                warn!("LIN diag transport error: {}", err);
+                return DiagResponse::status(service, DiagStatus::Transport);
+            }
+        }
+    }
+}
+
+/// Runs node configuration requests received from CAN on the diagnostic
+/// schedule and reports the slave responses back.
+#[task]
+pub async fn lin_diag() {
+    loop {
+        let service = LIN_DIAG_REQUEST.wait().await;
+        info!("LIN diag request {}", service);
+
+        LIN_SCHEDULE.signal(Schedule::Diagnostic);
+        let response = transaction(&service).await;
+        LIN_SCHEDULE.signal(Schedule::Normal);
+
+        info!("LIN diag response {}", response);
+        LIN_DIAG_RESPONSE.signal(response);
+    }
+}
@@ -1,32 +1,416 @@
-use defmt::{info, println};
+use defmt::{info, println, warn};
 use embassy_executor::task;
 use embassy_stm32::{
     mode::Async,
-    usart::{BufferedUart, Uart},
+    usart::{self, BufferedUart, Uart},
 };
-use embassy_time::{with_timeout, Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 use lin_bus::{Frame, PID};
+pub use stm_board_logic::lin::{Direction, MASTER_REQ_ID, SLAVE_RESP_ID};
+use stm_board_logic::lin_power::{PowerAction, PowerManager, GO_TO_SLEEP, SLEEP_DELAY_MS};
 
-use crate::color_transition::ColorTransition;
+use crate::{
+    kl15::KL15_ON_MV,
+    lin_frames::{Schedule, LIN_FRAMES, LIN_NODES, LIN_NODE_COUNT},
+    status_led::{self, Status},
+    LIN_BUFFERS, LIN_HEALTH, LIN_KL15, LIN_SCHEDULE, LIN_STATS,
+};
+
+pub struct FrameInfo {
+    pub id: u8,
+    pub len: usize,
+    pub direction: Direction,
+    /// index of the slave node the frame is exchanged with
+    pub node: usize,
+    pub kind: FrameKind,
+}
+
+pub enum FrameKind {
+    Unconditional,
+    /// sends the first of the associated frames with new data, the slot
+    /// stays silent when none changed
+    Sporadic(&'static [usize]),
+    /// slaves answer with an associated frame only when its data changed,
+    /// colliding answers are resolved by polling them in the `collision` table
+    EventTriggered {
+        frames: &'static [usize],
+        collision: Schedule,
+    },
+}
+
+impl FrameInfo {
+    /// Diagnostic frames are only transferred when there is a request to
+    /// send or a response to collect.
+    pub fn on_demand(&self) -> bool {
+        self.id == MASTER_REQ_ID || self.id == SLAVE_RESP_ID
+    }
+}
+
+pub struct Slot {
+    /// index into `LIN_FRAMES`
+    pub frame: usize,
+    /// time until the next slot starts
+    pub delay_ms: u64,
+}
+
+pub struct ScheduleTable {
+    pub slots: &'static [Slot],
+}
+
+/// Response data of one frame shared between the scheduler and other tasks.
+#[derive(Copy, Clone)]
+pub struct FrameBuffer {
+    data: [u8; 8],
+    len: usize,
+    valid: bool,
+    updated: bool,
+    requested: bool,
+}
+
+impl FrameBuffer {
+    pub const EMPTY: FrameBuffer = FrameBuffer {
+        data: [0; 8],
+        len: 0,
+        valid: false,
+        updated: false,
+        requested: false,
+    };
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        self.data[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+        self.valid = true;
+        self.updated = true;
+        self.requested = false;
+    }
+}
+
+/// Sets the response the master sends in the next slot of the frame.
+///
+/// Publishing unchanged data does not trigger sporadic frames.
+pub fn publish(frame: usize, data: &[u8]) {
+    LIN_BUFFERS.lock(|buffers| {
+        let buffer = &mut buffers.borrow_mut()[frame];
+        if !buffer.valid || buffer.data() != data {
+            buffer.store(data);
+        }
+    });
+}
+
+/// Whether published data of an on-demand frame still waits for its slot.
+pub fn is_pending(frame: usize) -> bool {
+    LIN_BUFFERS.lock(|buffers| buffers.borrow()[frame].valid)
+}
+
+/// Withdraws unsent data or an outstanding poll of an on-demand frame.
+pub fn cancel(frame: usize) {
+    LIN_BUFFERS.lock(|buffers| {
+        let buffer = &mut buffers.borrow_mut()[frame];
+        buffer.valid = false;
+        buffer.requested = false;
+    });
+}
+
+/// Polls an on-demand subscribed frame in its next slot.
+pub fn request(frame: usize) {
+    LIN_BUFFERS.lock(|buffers| buffers.borrow_mut()[frame].requested = true);
+}
+
+/// Latest response received in a subscribed frame.
+pub fn received(frame: usize) -> Option<FrameBuffer> {
+    LIN_BUFFERS.lock(|buffers| {
+        let buffer = buffers.borrow()[frame];
+        buffer.valid.then_some(buffer)
+    })
+}
+
+/// Response received in a subscribed frame since the last call.
+pub fn take_received(frame: usize) -> Option<FrameBuffer> {
+    LIN_BUFFERS.lock(|buffers| {
+        let buffer = &mut buffers.borrow_mut()[frame];
+        let updated = buffer.updated;
+        buffer.updated = false;
+        updated.then_some(*buffer)
+    })
+}
+
+/// LIN bus errors, `lin_bus::Error` extended with the checks of the echo the
+/// transceiver returns for everything the master sends.
+///
+/// The values are the `Lin_Last_Error` codes of the `LIN_STATUS` CAN message.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinError {
+    Timeout = 1,
+    PhysicalBus = 2,
+    Checksum = 3,
+    /// bytes read back differ from the sent ones, collision or shorted bus
+    ReadbackMismatch = 4,
+    /// the break was not echoed, transceiver unpowered or bus stuck
+    BreakNotDetected = 5,
+    /// stop bit of a received byte was dominant
+    Framing = 6,
+    /// own transmission read back as dominant, bus shorted to ground
+    StuckDominant = 7,
+}
 
-const LIN_FRAME_OFFSET: u8 = 5;
-const LIN_FRAME_RGB: u8 = LIN_FRAME_OFFSET;
-const LIN_FRAME_LEDS: u8 = 1 + LIN_FRAME_OFFSET;
-const LIN_FRAME_PHOTORES: u8 = 2 + LIN_FRAME_OFFSET;
+impl From<lin_bus::Error> for LinError {
+    fn from(err: lin_bus::Error) -> Self {
+        match err {
+            lin_bus::Error::Timeout => LinError::Timeout,
+            lin_bus::Error::PhysicalBus => LinError::PhysicalBus,
+            lin_bus::Error::Checksum => LinError::Checksum,
+        }
+    }
+}
+
+/// Error counters and back-off state of one slave node.
+///
+/// After `BACKOFF_AFTER` consecutive errors the frames of the node are
+/// skipped, probing it again with exponentially growing pauses.
+#[derive(Copy, Clone)]
+pub struct NodeHealth {
+    errors: u16,
+    consecutive: u8,
+    backoff_ms: u64,
+    retry_at_ms: u64,
+}
+
+impl NodeHealth {
+    const BACKOFF_AFTER: u8 = 3;
+    const BACKOFF_MIN_MS: u64 = 100;
+    const BACKOFF_MAX_MS: u64 = 5000;
+
+    pub const fn new() -> Self {
+        Self {
+            errors: 0,
+            consecutive: 0,
+            backoff_ms: 0,
+            retry_at_ms: 0,
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.consecutive = 0;
+        self.backoff_ms = 0;
+        self.retry_at_ms = 0;
+    }
+
+    pub fn failure(&mut self, now_ms: u64) {
+        self.errors = self.errors.saturating_add(1);
+        self.consecutive = self.consecutive.saturating_add(1);
+        if self.consecutive >= Self::BACKOFF_AFTER {
+            self.backoff_ms =
+                (self.backoff_ms * 2).clamp(Self::BACKOFF_MIN_MS, Self::BACKOFF_MAX_MS);
+            self.retry_at_ms = now_ms + self.backoff_ms;
+        }
+    }
+
+    /// Whether the frames of the node should be scheduled at `now_ms`.
+    pub fn is_available(&self, now_ms: u64) -> bool {
+        now_ms >= self.retry_at_ms
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.consecutive >= Self::BACKOFF_AFTER
+    }
+
+    pub fn errors(&self) -> u16 {
+        self.errors
+    }
+}
+
+/// Summary of the LIN bus state forwarded onto CAN.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinHealth {
+    /// bit n set while node n is backed off
+    pub fault_mask: u8,
+    pub error_count: u16,
+    pub last_error: Option<LinError>,
+    /// cluster put to sleep while the ignition is off
+    pub sleeping: bool,
+}
+
+const _: () = assert!(LIN_NODE_COUNT <= 8, "fault mask holds 8 nodes");
+
+impl LinHealth {
+    pub const OK: LinHealth = LinHealth {
+        fault_mask: 0,
+        error_count: 0,
+        last_error: None,
+        sleeping: false,
+    };
+
+    fn of(nodes: &[NodeHealth], last_error: Option<LinError>) -> Self {
+        let mut health = LinHealth {
+            last_error,
+            ..LinHealth::OK
+        };
+        for (index, node) in nodes.iter().enumerate() {
+            if node.is_faulted() {
+                health.fault_mask |= 1 << index;
+            }
+            health.error_count = health.error_count.saturating_add(node.errors());
+        }
+        health
+    }
+}
+
+/// Transfer statistics of one frame.
+#[derive(Copy, Clone)]
+pub struct FrameStats {
+    /// headers sent
+    pub sent: u32,
+    pub checksum_errors: u32,
+    pub no_response: u32,
+    /// bit, framing and stuck dominant errors
+    pub bus_errors: u32,
+    /// time from the break to the end of the response
+    pub last_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl FrameStats {
+    pub const EMPTY: FrameStats = FrameStats {
+        sent: 0,
+        checksum_errors: 0,
+        no_response: 0,
+        bus_errors: 0,
+        last_latency: Duration::from_ticks(0),
+        max_latency: Duration::from_ticks(0),
+    };
+
+    fn record(&mut self, result: Result<(), LinError>, latency: Duration) {
+        self.sent = self.sent.wrapping_add(1);
+        let counter = match result {
+            Ok(()) => {
+                self.last_latency = latency;
+                self.max_latency = self.max_latency.max(latency);
+                return;
+            }
+            Err(LinError::Checksum) => &mut self.checksum_errors,
+            Err(LinError::Timeout) => &mut self.no_response,
+            Err(_) => &mut self.bus_errors,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+/// Statistics of the frame with LIN identifier `id`.
+pub fn frame_stats(id: u8) -> Option<FrameStats> {
+    let index = LIN_FRAMES.iter().position(|frame| frame.id == id)?;
+    Some(LIN_STATS.lock(|stats| stats.borrow()[index]))
+}
+
+// a header takes about 2 ms at 19200 baud
+const HEADER_TIMEOUT: Duration = Duration::from_millis(10);
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(50);
 
 pub struct LinMaster {
     pub driver: BufferedUart<'static>,
 }
 
 impl LinMaster {
-    async fn write_frame(&mut self, frame: &Frame) -> Result<(), lin_bus::Error> {
+    /// Runs the frame of one schedule slot.
+    async fn transfer(&mut self, index: usize) -> Result<Transfer, LinError> {
+        match LIN_FRAMES[index].kind {
+            FrameKind::Unconditional => self.unconditional(index).await,
+            FrameKind::Sporadic(frames) => {
+                let updated = LIN_BUFFERS.lock(|buffers| {
+                    let buffers = buffers.borrow();
+                    frames
+                        .iter()
+                        .copied()
+                        .find(|&f| buffers[f].valid && buffers[f].updated)
+                });
+                match updated {
+                    Some(frame) => self.unconditional(frame).await,
+                    None => Ok(Transfer::Idle),
+                }
+            }
+            FrameKind::EventTriggered { frames, collision } => {
+                self.event_triggered(index, frames, collision).await
+            }
+        }
+    }
+
+    async fn unconditional(&mut self, index: usize) -> Result<Transfer, LinError> {
+        let frame = &LIN_FRAMES[index];
+        let pid = PID::from_id(frame.id);
+
+        match frame.direction {
+            Direction::Publish => {
+                let buffer = LIN_BUFFERS.lock(|buffers| buffers.borrow()[index]);
+                if !buffer.valid {
+                    return Ok(Transfer::Idle);
+                }
+                self.write_frame(&Frame::from_data(pid, buffer.data()))
+                    .await?;
+                LIN_BUFFERS.lock(|buffers| {
+                    let buffer = &mut buffers.borrow_mut()[index];
+                    buffer.updated = false;
+                    // a request is sent once
+                    buffer.valid &= !frame.on_demand();
+                });
+            }
+            Direction::Subscribe => {
+                let requested = LIN_BUFFERS.lock(|buffers| buffers.borrow()[index].requested);
+                if frame.on_demand() && !requested {
+                    return Ok(Transfer::Idle);
+                }
+                let fr = self.read_frame(pid, frame.len).await?;
+                LIN_BUFFERS.lock(|buffers| buffers.borrow_mut()[index].store(fr.get_data()));
+            }
+        }
+        Ok(Transfer::Done)
+    }
+
+    async fn event_triggered(
+        &mut self,
+        index: usize,
+        frames: &[usize],
+        collision: Schedule,
+    ) -> Result<Transfer, LinError> {
+        let frame = &LIN_FRAMES[index];
+        let fr = match self.read_frame(PID::from_id(frame.id), frame.len).await {
+            Ok(fr) => fr,
+            // no slave had new data
+            Err(LinError::Timeout) => return Ok(Transfer::Idle),
+            // overlapping responses of several slaves
+            Err(LinError::Checksum | LinError::Framing | LinError::PhysicalBus) => {
+                return Ok(Transfer::Collision(collision))
+            }
+            Err(err) => return Err(err),
+        };
+
+        // the first data byte carries the PID of the associated frame
+        let data = fr.get_data();
+        let associated = frames
+            .iter()
+            .copied()
+            .find(|&f| data.first() == Some(&PID::from_id(LIN_FRAMES[f].id).get()));
+        match associated {
+            Some(associated) => {
+                LIN_BUFFERS.lock(|buffers| buffers.borrow_mut()[associated].store(data));
+                Ok(Transfer::Done)
+            }
+            None => Ok(Transfer::Collision(collision)),
+        }
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> Result<(), LinError> {
         self.send_header(frame.get_pid()).await?;
         self.write(frame.get_data_with_checksum()).await
     }
 
-    async fn read_frame(&mut self, pid: PID, data_length: usize) -> Result<Frame, lin_bus::Error> {
-        assert!(data_length <= 8, "Maximum data length is 8 bytes");
+    /// `data_length` is at most 8, frame lengths are checked by build.rs.
+    async fn read_frame(&mut self, pid: PID, data_length: usize) -> Result<Frame, LinError> {
         self.send_header(pid).await?;
 
         let mut data = [0; 9];
@@ -37,107 +421,237 @@ impl LinMaster {
         if frame.get_checksum() == data[data_length] {
             Ok(frame)
         } else {
-            Err(lin_bus::Error::Checksum)
+            Err(lin_bus::Error::Checksum.into())
         }
     }
 
-    async fn send_header(&mut self, pid: lin_bus::PID) -> Result<(), lin_bus::Error> {
-        let timeout: Duration = Duration::from_secs(1);
-
+    async fn send_header(&mut self, pid: lin_bus::PID) -> Result<(), LinError> {
         self.driver.send_break();
         let mut inbuffer = [0u8; 1];
-        match with_timeout(timeout, self.driver.read(&mut inbuffer)).await {
-            Ok(Ok(n)) => {
-                //info!("break got: {} {}", n, inbuffer);
-            }
-            Ok(Err(e)) => {
-                info!("break resp: {}", e);
-            }
-            Err(_) => return Err(lin_bus::Error::Timeout),
+        match with_timeout(HEADER_TIMEOUT, self.driver.read(&mut inbuffer)).await {
+            // the echoed break arrives as a zero byte or a framing error
+            Ok(Ok(_)) if inbuffer[0] == 0 => {}
+            Ok(Err(usart::Error::Framing)) => {}
+            Ok(Ok(_)) | Err(_) => return Err(LinError::BreakNotDetected),
+            Ok(Err(_)) => return Err(LinError::PhysicalBus),
         };
-        //crate::assert!(Err(usart::Error::Framing) == err);
 
-        let buffer = [0x55, pid.get()];
-        with_timeout(timeout, self.driver.write_all(&buffer))
-            .await
-            .unwrap()
-            .unwrap();
-
-        let mut inbuffer = [0u8; 2];
-        with_timeout(timeout, self.driver.read_exact(&mut inbuffer))
-            .await
-            .unwrap()
-            .unwrap();
-        crate::assert!(buffer == inbuffer);
-
-        Ok(())
+        self.write(&[0x55, pid.get()]).await
     }
 
-    async fn read(&mut self, buf: &mut [u8]) -> Result<(), lin_bus::Error> {
-        let res = with_timeout(
-            embassy_time::Duration::from_millis(50),
-            self.driver.read_exact(buf),
-        )
-        .await;
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(), LinError> {
+        let res = with_timeout(RESPONSE_TIMEOUT, self.driver.read_exact(buf)).await;
 
         match res {
             Ok(Ok(_)) => Ok(()),
-            Ok(Err(_)) => Err(lin_bus::Error::PhysicalBus),
-            Err(_) => Err(lin_bus::Error::Timeout),
+            Ok(Err(usart::Error::Framing)) => Err(LinError::Framing),
+            Ok(Err(_)) => Err(lin_bus::Error::PhysicalBus.into()),
+            Err(_) => Err(lin_bus::Error::Timeout.into()),
         }
     }
 
-    async fn write(&mut self, data: &[u8]) -> Result<(), lin_bus::Error> {
-        let timeout: Duration = Duration::from_secs(1);
+    /// Sends `data` and compares every byte read back from the bus.
+    async fn write(&mut self, data: &[u8]) -> Result<(), LinError> {
+        match with_timeout(RESPONSE_TIMEOUT, self.driver.write_all(data)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(LinError::PhysicalBus),
+            Err(_) => return Err(LinError::Timeout),
+        }
 
-        with_timeout(timeout, self.driver.write_all(data))
-            .await
-            .unwrap()
-            .unwrap();
+        let mut echo = [0u8; 9];
+        let echo = &mut echo[..data.len()];
+        match self.read(echo).await {
+            // only a bus held low makes our own stop bit dominant
+            Err(LinError::Framing) => return Err(LinError::StuckDominant),
+            result => result?,
+        }
+        if echo == data {
+            Ok(())
+        } else if echo.iter().all(|byte| *byte == 0) {
+            Err(LinError::StuckDominant)
+        } else {
+            // single bits overwritten by another node or a noisy line
+            Err(LinError::ReadbackMismatch)
+        }
+    }
 
-        for _ in 0..data.len() {
-            let mut buffer = [0u8; 1];
-            let _ = with_timeout(timeout, self.driver.read(&mut buffer)).await;
+    /// Drops bytes left over from an aborted frame, they would otherwise be
+    /// taken for the echo of the next one.
+    async fn discard_input(&mut self) {
+        let mut buffer = [0u8; 8];
+        while let Ok(Ok(n)) =
+            with_timeout(Duration::from_millis(2), self.driver.read(&mut buffer)).await
+        {
+            if n == 0 {
+                break;
+            }
         }
-        Ok(())
     }
+
+    async fn go_to_sleep(&mut self) {
+        let frame = Frame::from_data(PID::from_id(MASTER_REQ_ID), &GO_TO_SLEEP);
+        if let Err(err) = self.write_frame(&frame).await {
+            warn!("LIN go to sleep failed: {}", err);
+        }
+    }
+
+    /// Waits on the sleeping bus until the ignition returns or a slave sends
+    /// a wake-up pulse, then wakes the cluster.
+    async fn sleep(&mut self, power: &mut PowerManager) {
+        self.discard_input().await;
+        loop {
+            // wake-up pulses arrive as a zero byte or a framing error
+            let mut byte = [0u8; 1];
+            let pulse = with_timeout(SLEEP_POLL, self.driver.read(&mut byte))
+                .await
+                .is_ok();
+
+            let now = Instant::now().as_millis();
+            if let Some(millivolts) = LIN_KL15.try_take() {
+                power.ignition(millivolts > KL15_ON_MV, now);
+            }
+            if power.update(now, pulse) == Some(PowerAction::WakeUp) {
+                break;
+            }
+        }
+
+        // a zero byte holds the bus dominant for 9 bits, about 470 us, which
+        // is harmless when a slave already woke the cluster
+        if let Err(err) = self.write(&[0x00]).await {
+            warn!("LIN wake up failed: {}", err);
+        }
+        Timer::after_millis(WAKEUP_RECOVERY_MS).await;
+    }
+}
+
+const SLEEP_POLL: Duration = Duration::from_millis(50);
+// slaves are ready for headers this long after a wake-up pulse
+const WAKEUP_RECOVERY_MS: u64 = 100;
+
+/// Outcome of one schedule slot.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Transfer {
+    /// nothing to send or no slave with new data
+    Idle,
+    Done,
+    /// event triggered responses collided, resolve with the given table
+    Collision(Schedule),
 }
 
+// immediate retries of a failed frame before waiting for its next slot
+const FRAME_RETRIES: usize = 1;
+
 #[task]
 pub async fn lin_scheduler(mut lin: LinMaster) {
-    let mut led = 1u8;
-    let mut color = ColorTransition::new(&[(255, 0, 0), (0, 255, 0), (0, 0, 255)]);
+    let mut schedule = Schedule::Normal;
+    let mut slot = 0;
+    let mut slot_start = Instant::now();
+    let mut nodes = [NodeHealth::new(); LIN_NODE_COUNT];
+    let mut last_error = None;
+    let mut health = LinHealth::OK;
+    // schedule and slot to continue with after a collision was resolved
+    let mut resume = None;
+    let mut power = PowerManager::new(SLEEP_DELAY_MS);
 
     loop {
-        let f = lin_bus::Frame::from_data(PID::from_id(LIN_FRAME_LEDS), &[led]);
-        lin.write_frame(&f).await.unwrap();
+        let now = Instant::now().as_millis();
+        if let Some(millivolts) = LIN_KL15.try_take() {
+            power.ignition(millivolts > KL15_ON_MV, now);
+        }
+        if power.update(now, false) == Some(PowerAction::GoToSleep) {
+            info!("LIN cluster going to sleep");
+            lin.go_to_sleep().await;
+            health.sleeping = true;
+            LIN_HEALTH.signal(health);
 
-        led = (led * 2) & 0xF;
-        if led == 0 {
-            led = 1;
+            lin.sleep(&mut power).await;
+            info!("LIN cluster woken up");
+            slot_start = Instant::now();
         }
 
-        let (r, g, b) = color.next();
-        let f = lin_bus::Frame::from_data(PID::from_id(LIN_FRAME_RGB), &[r, g, b]);
-        lin.write_frame(&f).await.unwrap();
+        if let Some(requested) = LIN_SCHEDULE.try_take() {
+            if requested != schedule {
+                info!("LIN schedule {}", requested);
+                schedule = requested;
+                slot = 0;
+                resume = None;
+            }
+        }
 
-        Timer::after_millis(100).await;
+        let table = schedule.table();
+        let entry = &table.slots[slot % table.slots.len()];
+        let frame = &LIN_FRAMES[entry.frame];
+        let node = &mut nodes[frame.node];
+        let mut collision = None;
 
-        let fr = lin.read_frame(PID::from_id(LIN_FRAME_PHOTORES), 2).await;
-        match fr {
-            Ok(fr) => {
-                info!("LIN RX {} {:?}", fr.get_pid().get_id(), fr.get_data())
+        for _ in 0..=FRAME_RETRIES {
+            let now = Instant::now().as_millis();
+            if !node.is_available(now) {
+                break;
             }
-            Err(err) => info!(
-                "Error reading LIN: {}",
-                match err {
-                    lin_bus::Error::Timeout => "timeout",
-                    lin_bus::Error::PhysicalBus => "physicalbus",
-                    lin_bus::Error::Checksum => "checksum",
+
+            let started = Instant::now();
+            let result = lin.transfer(entry.frame).await;
+            if result != Ok(Transfer::Idle) {
+                let elapsed = started.elapsed();
+                LIN_STATS.lock(|stats| {
+                    stats.borrow_mut()[entry.frame].record(result.map(|_| ()), elapsed)
+                });
+            }
+
+            match result {
+                Ok(transfer) => {
+                    match transfer {
+                        Transfer::Done => node.success(),
+                        Transfer::Collision(table) => collision = Some(table),
+                        Transfer::Idle => {}
+                    }
+                    break;
+                }
+                // no node had a response for the requested NAD
+                Err(LinError::Timeout) if frame.on_demand() => break,
+                Err(err) => {
+                    warn!("LIN frame {} failed: {}", frame.id, err);
+                    lin.discard_input().await;
+                    node.failure(now);
+                    last_error = Some(err);
+                    if node.is_faulted() {
+                        warn!("LIN node {} backed off", LIN_NODES[frame.node]);
+                    }
                 }
-            ),
+            }
+        }
+
+        let current = LinHealth {
+            sleeping: power.is_sleeping(),
+            ..LinHealth::of(&nodes, last_error)
         };
+        if current != health {
+            health = current;
+            LIN_HEALTH.signal(health);
+            status_led::post(Status::LinFault, health.fault_mask != 0);
+        }
 
-        Timer::after_millis(100).await;
+        slot = (slot + 1) % table.slots.len();
+        match (collision, resume) {
+            (Some(table), None) => {
+                resume = Some((schedule, slot));
+                schedule = table;
+                slot = 0;
+            }
+            // the collision resolving table ran once
+            (_, Some((previous, previous_slot))) if slot == 0 => {
+                schedule = previous;
+                slot = previous_slot;
+                resume = None;
+            }
+            _ => {}
+        }
+        slot_start += Duration::from_millis(entry.delay_ms);
+        if slot_start < Instant::now() {
+            // a slot overran, restart timing instead of bursting frames
+            slot_start = Instant::now();
+        }
+        Timer::at(slot_start).await;
     }
 }
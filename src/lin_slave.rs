@@ -0,0 +1,121 @@
+use defmt::{info, warn};
+use embassy_executor::task;
+use embassy_stm32::{
+    interrupt, pac,
+    peripherals::UART4,
+    usart::{self, BufferedInterruptHandler, BufferedUart, RxPin, TxPin},
+    Peripheral,
+};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+pub use stm_board_logic::lin_slave::{Event, SlaveFrame, SlaveResponder};
+
+use crate::{
+    kl15::KL15_ON_MV,
+    lin_master::{Direction, MASTER_REQ_ID},
+    LIN_SLAVE_KL15, LIN_SLAVE_SPEED,
+};
+
+pub const SLAVE_FRAME_KL15: usize = 0;
+pub const SLAVE_FRAME_SPEED: usize = 1;
+pub const SLAVE_FRAME_MASTER_REQ: usize = 2;
+pub const SLAVE_FRAME_COUNT: usize = 3;
+
+pub const SLAVE_FRAMES: [SlaveFrame; SLAVE_FRAME_COUNT] = [
+    // bit 0 of byte 0: KL15 on, bytes 1..3: voltage in mV
+    SlaveFrame {
+        id: 0x10,
+        len: 3,
+        direction: Direction::Publish,
+    },
+    // signed speed in 0.01 km/h
+    SlaveFrame {
+        id: 0x11,
+        len: 2,
+        direction: Direction::Publish,
+    },
+    SlaveFrame {
+        id: MASTER_REQ_ID,
+        len: 8,
+        direction: Direction::Subscribe,
+    },
+];
+
+// a byte takes 0.52 ms at 19200 baud, a longer gap ends the frame
+const INTER_BYTE_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// UART the slave can run on, the driver does not expose the LIN mode.
+pub trait LinUart: usart::Instance {
+    fn regs() -> pac::usart::Usart;
+}
+
+impl LinUart for UART4 {
+    fn regs() -> pac::usart::Usart {
+        pac::UART4
+    }
+}
+
+pub struct LinSlave {
+    driver: BufferedUart<'static>,
+}
+
+impl LinSlave {
+    pub fn new<T: LinUart>(
+        uart: T,
+        irq: impl interrupt::typelevel::Binding<T::Interrupt, BufferedInterruptHandler<T>> + 'static,
+        rx: impl Peripheral<P = impl RxPin<T>> + 'static,
+        tx: impl Peripheral<P = impl TxPin<T>> + 'static,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        config: usart::Config,
+    ) -> Result<Self, usart::ConfigError> {
+        let driver = BufferedUart::new(uart, irq, rx, tx, tx_buffer, rx_buffer, config)?;
+        // LIN mode detects breaks of 10 bits and more as framing errors
+        let regs = T::regs();
+        regs.cr1().modify(|w| w.set_ue(false));
+        regs.cr2().modify(|w| w.set_linen(true));
+        regs.cr1().modify(|w| w.set_ue(true));
+        Ok(Self { driver })
+    }
+
+    async fn next_event(&mut self) -> Event {
+        let mut byte = [0u8; 1];
+        match with_timeout(INTER_BYTE_TIMEOUT, self.driver.read(&mut byte)).await {
+            Ok(Ok(_)) => Event::Byte(byte[0]),
+            Ok(Err(usart::Error::Framing)) => Event::Break,
+            Ok(Err(_)) | Err(_) => Event::Idle,
+        }
+    }
+}
+
+#[task]
+pub async fn lin_slave(mut slave: LinSlave) {
+    let mut responder = SlaveResponder::new(&SLAVE_FRAMES);
+    let mut errors = 0;
+
+    loop {
+        if let Some(millivolts) = LIN_SLAVE_KL15.try_take() {
+            let [lo, hi] = millivolts.to_le_bytes();
+            responder.publish(SLAVE_FRAME_KL15, &[(millivolts > KL15_ON_MV) as u8, lo, hi]);
+        }
+        if let Some(kmh) = LIN_SLAVE_SPEED.try_take() {
+            let speed = (kmh * 100.0) as i16;
+            responder.publish(SLAVE_FRAME_SPEED, &speed.to_le_bytes());
+        }
+
+        let event = slave.next_event().await;
+        if let Some(response) = responder.feed(event) {
+            if slave.driver.write_all(response).await.is_err() {
+                warn!("LIN slave response failed");
+            }
+        }
+
+        if let Some(request) = responder.take_received(SLAVE_FRAME_MASTER_REQ) {
+            info!("LIN slave master request {:02x}", request);
+        }
+        if responder.errors() != errors {
+            errors = responder.errors();
+            warn!("LIN slave errors: {}", errors);
+        }
+    }
+}
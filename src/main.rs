@@ -6,6 +6,7 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
 use core::time::Duration;
 
 use cortex_m::singleton;
@@ -14,6 +15,7 @@ use embassy_executor::Spawner;
 use embassy_stm32::adc::Adc;
 use embassy_stm32::adc::SampleTime;
 use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::Level;
 use embassy_stm32::gpio::Output;
 use embassy_stm32::gpio::OutputType;
@@ -22,6 +24,7 @@ use embassy_stm32::gpio::Speed;
 use embassy_stm32::pac::IWDG;
 use embassy_stm32::peripherals::*;
 use embassy_stm32::time::hz;
+use embassy_stm32::timer::input_capture::CapturePin;
 use embassy_stm32::timer::qei::Qei;
 use embassy_stm32::timer::qei::QeiPin;
 use embassy_stm32::timer::simple_pwm::PwmPin;
@@ -32,22 +35,38 @@ use embassy_stm32::usart::Uart;
 use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_stm32::{bind_interrupts, can, usart, Config};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel as SyncChannel;
 use embassy_sync::signal::Signal;
 use embassy_time::Timer;
+use stm_board_logic::collision;
+use stm_board_logic::filter;
+use stm_board_logic::isotp;
+use stm_board_logic::rgb_effects;
+use stm_board_logic::steering;
 use {defmt_rtt as _, panic_probe as _};
 
-mod blinky;
 mod can_scheduler;
-mod color_transition;
+mod config;
+mod config_store;
+mod failsafe;
 mod kl15;
+mod lighting;
+mod lin_diag;
+mod lin_frames;
 mod lin_master;
+#[cfg(feature = "lin-slave")]
+mod lin_slave;
 mod messages;
+mod motor;
+mod params;
 mod rotary_encoder;
 mod servo;
+mod status_led;
+mod steering_cal;
+mod uds;
 mod ultrasound;
 
-const SLAVE: bool = false;
-
 bind_interrupts!(struct Irqs {
     FDCAN1_IT0 => can::IT0InterruptHandler<FDCAN1>;
     FDCAN1_IT1 => can::IT1InterruptHandler<FDCAN1>;
@@ -57,11 +76,91 @@ bind_interrupts!(struct UARTIRqs {
     UART4 => usart::BufferedInterruptHandler<UART4>;
 });
 
+#[cfg(feature = "capture-echo")]
+bind_interrupts!(struct CaptureIrqs {
+    TIM1_CC => ultrasound::CaptureInterruptHandler;
+});
+
 static SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
 static ULTRASOUNDS: Signal<CriticalSectionRawMutex, [ultrasound::UltrasoundResult; 6]> =
     Signal::new();
+static BRAKE_DISTANCES: Signal<CriticalSectionRawMutex, [ultrasound::UltrasoundResult; 6]> =
+    Signal::new();
+static BRAKE_INTERVENTION: Signal<CriticalSectionRawMutex, bool> = Signal::new();
 static SERVO_DEGREE: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static STEERING_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static STEERING_CAL_CMD: Signal<CriticalSectionRawMutex, steering_cal::CalCommand> = Signal::new();
+static STEERING_CAL_STATUS: Signal<CriticalSectionRawMutex, steering_cal::CalStatus> =
+    Signal::new();
+static STEERING_CAL_SAVED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<config::Config>> =
+    Mutex::new(RefCell::new(config::DEFAULT_CONFIG));
+// one slot per `ConfigClient`, each waits for its result before saving again
+static CONFIG_SAVE: SyncChannel<
+    CriticalSectionRawMutex,
+    (config::ConfigClient, config::Config),
+    3,
+> = SyncChannel::new();
+static UDS_CONFIG_SAVED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+// consecutive frames arrive back to back, a signal would drop them
+static UDS_RX: SyncChannel<CriticalSectionRawMutex, uds::UdsFrame, 8> = SyncChannel::new();
+static UDS_TX: SyncChannel<CriticalSectionRawMutex, isotp::Frame, 8> = SyncChannel::new();
+static UDS_KL15: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+static UDS_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static UDS_DISTANCES: Signal<CriticalSectionRawMutex, [ultrasound::UltrasoundResult; 6]> =
+    Signal::new();
+// every request is answered, a signal would drop requests sent back to back
+static PARAM_REQUEST: SyncChannel<CriticalSectionRawMutex, params::ParamRequest, 4> =
+    SyncChannel::new();
+static PARAM_RESPONSE: SyncChannel<CriticalSectionRawMutex, params::ParamResponse, 4> =
+    SyncChannel::new();
+static PARAM_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+// ultrasound filter from `US_FILTER_CFG`, written through the parameter table
+static PARAM_FILTER: Signal<CriticalSectionRawMutex, filter::FilterConfig> = Signal::new();
+static PARAMS_SAVED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+static MOTOR_CONFIG: Signal<CriticalSectionRawMutex, motor::MotorConfig> = Signal::new();
+static STEERING_LIMITS: Signal<CriticalSectionRawMutex, steering::SteeringLimits> = Signal::new();
+static MOTOR_TARGET_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static MOTOR_STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static MOTOR_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
 static KL15: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+static AMBIENT_TEMPERATURE: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static ULTRASOUND_FILTER: Signal<CriticalSectionRawMutex, filter::FilterConfig> = Signal::new();
+static FAILSAFE_STATUS: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+static LIN_BUFFERS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[lin_master::FrameBuffer; lin_frames::LIN_FRAME_COUNT]>,
+> = Mutex::new(RefCell::new(
+    [lin_master::FrameBuffer::EMPTY; lin_frames::LIN_FRAME_COUNT],
+));
+static LIN_SCHEDULE: Signal<CriticalSectionRawMutex, lin_frames::Schedule> = Signal::new();
+static LIN_KL15: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+static LIN_HEALTH: Signal<CriticalSectionRawMutex, lin_master::LinHealth> = Signal::new();
+static LIN_STATS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[lin_master::FrameStats; lin_frames::LIN_FRAME_COUNT]>,
+> = Mutex::new(RefCell::new(
+    [lin_master::FrameStats::EMPTY; lin_frames::LIN_FRAME_COUNT],
+));
+static LIN_STATS_RESPONSE: Signal<CriticalSectionRawMutex, (u8, lin_master::FrameStats)> =
+    Signal::new();
+static LIN_DIAG_REQUEST: Signal<CriticalSectionRawMutex, lin_diag::NodeService> = Signal::new();
+static LIN_DIAG_RESPONSE: Signal<CriticalSectionRawMutex, lin_diag::DiagResponse> = Signal::new();
+static LIGHTING_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static LIGHTING_STEERING: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static LIGHT_OVERRIDE: Signal<CriticalSectionRawMutex, lighting::LightOverride> = Signal::new();
+static LIGHT_STATUS: Signal<CriticalSectionRawMutex, lighting::LightState> = Signal::new();
+static RGB_EFFECT: Signal<CriticalSectionRawMutex, Option<rgb_effects::Effect>> = Signal::new();
+#[cfg(feature = "lin-slave")]
+static LIN_SLAVE_KL15: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+#[cfg(feature = "lin-slave")]
+static LIN_SLAVE_SPEED: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+static STATUS_CONDITIONS: Mutex<CriticalSectionRawMutex, RefCell<u8>> = Mutex::new(RefCell::new(0));
+static STATUS_HISTORY: Mutex<CriticalSectionRawMutex, RefCell<u8>> = Mutex::new(RefCell::new(0));
+static COMMAND_MONITOR: Mutex<CriticalSectionRawMutex, RefCell<failsafe::CommandMonitor>> =
+    Mutex::new(RefCell::new(failsafe::CommandMonitor::new(
+        failsafe::COMMAND_TIMEOUT_MS,
+    )));
 
 #[embassy_executor::task]
 async fn watchdog_task(mut wdg: IndependentWatchdog<'static, IWDG>) {
@@ -91,6 +190,28 @@ async fn main(spawner: Spawner) {
     }
     let peripherals = embassy_stm32::init(config);
 
+    let rcc = embassy_stm32::pac::RCC;
+    if rcc.csr().read().iwdgrstf() {
+        warn!("Reset by watchdog");
+        status_led::post(status_led::Status::WatchdogReset, true);
+    }
+    // reset flags are sticky, clear them to tell the next reset apart
+    rcc.csr().modify(|w| w.set_rmvf(true));
+
+    let flash = Flash::new_blocking(peripherals.FLASH);
+    let store = match config_store::ConfigStore::mount(config_store::FlashStorage::new(flash)) {
+        Ok(store) => Some(store),
+        Err(err) => {
+            error!("Mounting the configuration store failed: {}", err);
+            None
+        }
+    };
+    let config = match store.as_ref() {
+        Some(store) => config::from_store(store),
+        None => config::DEFAULT_CONFIG,
+    };
+    CONFIG.lock(|current| *current.borrow_mut() = config);
+
     let wdg = IndependentWatchdog::new(peripherals.IWDG, 2_000_000);
     spawner.spawn(watchdog_task(wdg)).unwrap();
 
@@ -108,11 +229,11 @@ async fn main(spawner: Spawner) {
         can::filter::ExtendedFilter::accept_all_into_fifo1(),
     );
 
-    can.set_bitrate(500_000);
-    can.set_fd_data_bitrate(1_000_000, false);
+    can.set_bitrate(config.can_bitrate);
+    can.set_fd_data_bitrate(config.can_data_bitrate, false);
     let can = can.start(can::OperatingMode::NormalOperationMode);
 
-    let config = {
+    let uart_config = {
         let mut config = usart::Config::default();
         config.baudrate = 19200;
         config
@@ -133,75 +254,149 @@ async fn main(spawner: Spawner) {
         pwm_freq,
         Default::default(),
     );
-    let min_us = Duration::from_micros(1075);
-    let max_us = Duration::from_micros(1896);
-    let servo = servo::Servo::new(pwm, Channel::Ch1, pwm_time, min_us, max_us);
+    // the record is validated when loaded, only the angle could be refused
+    let calibration = config
+        .steering
+        .calibration(servo::FULL_ANGLE_DEG)
+        .expect("steering calibration angle");
+    let servo = servo::Servo::new(pwm, Channel::Ch1, pwm_time, calibration);
+
+    let ch1 = PwmPin::new_ch1(peripherals.PB6, OutputType::PushPull);
+    let pwm = SimplePwm::new(
+        peripherals.TIM4,
+        Some(ch1),
+        None,
+        None,
+        None,
+        pwm_freq,
+        Default::default(),
+    );
+    let esc = motor::Esc::new(
+        pwm,
+        Channel::Ch1,
+        pwm_time,
+        Duration::from_micros(1000),
+        Duration::from_micros(1500),
+        Duration::from_micros(2000),
+    );
 
     let mut adc = Adc::new(peripherals.ADC1);
     adc.set_sample_time(SampleTime::CYCLES640_5);
-    let kl15 = kl15::KL15::new(adc, peripherals.PC0);
+    let kl15 = kl15::KL15::new(adc, peripherals.PC0, config.kl15_r1_ohm, config.kl15_r2_ohm);
 
     let led_pin = Output::new(peripherals.PA11, Level::Low, Speed::Low);
 
+    // outer front echoes latched by TIM1 in PWM input mode, every echo takes
+    // a channel pair so the middle one stays on EXTI
+    #[cfg(feature = "capture-echo")]
+    let [echo0, echo1, echo2]: [ultrasound::Echo; 3] = {
+        let capture: &'static ultrasound::CaptureTimer = singleton!(
+            CAPTURE: ultrasound::CaptureTimer =
+                ultrasound::CaptureTimer::new(peripherals.TIM1, CaptureIrqs)
+        )
+        .unwrap();
+        CapturePin::new_ch1(peripherals.PA8, Pull::Down);
+        CapturePin::new_ch3(peripherals.PA10, Pull::Down);
+        [
+            capture.echo(ultrasound::CaptureInput::Ti1).into(),
+            ExtiInput::new(peripherals.PB5, peripherals.EXTI5, Pull::Down).into(),
+            capture.echo(ultrasound::CaptureInput::Ti3).into(),
+        ]
+    };
+    #[cfg(not(feature = "capture-echo"))]
+    let [echo0, echo1, echo2]: [ultrasound::Echo; 3] = [
+        ExtiInput::new(peripherals.PB4, peripherals.EXTI4, Pull::Down).into(),
+        ExtiInput::new(peripherals.PB5, peripherals.EXTI5, Pull::Down).into(),
+        ExtiInput::new(peripherals.PB2, peripherals.EXTI2, Pull::Down).into(),
+    ];
+
     let ultrasounds = [
-        /*
-        (
-            4,
+        Some(ultrasound::UltrasoundSensor::new(
+            Output::new(peripherals.PB13, Level::Low, Speed::VeryHigh),
+            echo0,
+        )),
+        Some(ultrasound::UltrasoundSensor::new(
+            Output::new(peripherals.PB14, Level::Low, Speed::VeryHigh),
+            echo1,
+        )),
+        Some(ultrasound::UltrasoundSensor::new(
+            Output::new(peripherals.PB11, Level::Low, Speed::VeryHigh),
+            echo2,
+        )),
+        Some(ultrasound::UltrasoundSensor::new(
+            Output::new(peripherals.PB12, Level::Low, Speed::VeryHigh),
+            ExtiInput::new(peripherals.PB3, peripherals.EXTI3, Pull::Down),
+        )),
+        Some(ultrasound::UltrasoundSensor::new(
             Output::new(peripherals.PB9, Level::Low, Speed::VeryHigh),
             ExtiInput::new(peripherals.PB0, peripherals.EXTI0, Pull::Down),
-        ),
-        (
-            5,
+        )),
+        Some(ultrasound::UltrasoundSensor::new(
             Output::new(peripherals.PB10, Level::Low, Speed::VeryHigh),
             ExtiInput::new(peripherals.PB1, peripherals.EXTI1, Pull::Down),
-        ),
-        (
-            2,
-            Output::new(peripherals.PB11, Level::Low, Speed::VeryHigh),
-            ExtiInput::new(peripherals.PB2, peripherals.EXTI2, Pull::Down),
-        ),
-        (
-            3,
-            Output::new(peripherals.PB12, Level::Low, Speed::VeryHigh),
-            ExtiInput::new(peripherals.PB3, peripherals.EXTI3, Pull::Down),
-        ),
-        */
-        (
-            0,
-            Output::new(peripherals.PB13, Level::Low, Speed::VeryHigh),
-            ExtiInput::new(peripherals.PB4, peripherals.EXTI4, Pull::Down),
-        ),
-        (
-            1,
-            Output::new(peripherals.PB14, Level::Low, Speed::VeryHigh),
-            ExtiInput::new(peripherals.PB5, peripherals.EXTI5, Pull::Down),
-        ),
+        )),
     ];
 
     spawner.spawn(can_scheduler::can_rx(rx)).unwrap();
     spawner.spawn(can_scheduler::can_tx(tx)).unwrap();
+    spawner.spawn(failsafe::failsafe_task()).unwrap();
     //spawner.spawn(servo_tester(servo)).unwrap();
-    spawner.spawn(servo::servo_task(servo)).unwrap();
+    spawner
+        .spawn(servo::servo_task(servo, config.steering_limits))
+        .unwrap();
+    spawner.spawn(config::config_task(store)).unwrap();
+    spawner.spawn(params::param_task()).unwrap();
+    spawner.spawn(uds::uds_task()).unwrap();
+    spawner.spawn(motor::motor_task(esc, config.motor)).unwrap();
     spawner.spawn(kl15::measure_kl15(kl15)).unwrap();
-    spawner.spawn(blinky::blinky(led_pin)).unwrap();
-    spawner.spawn(ultrasound::ultrasound(ultrasounds)).unwrap();
+    spawner.spawn(status_led::status_led(led_pin)).unwrap();
+    spawner
+        .spawn(ultrasound::ultrasound(
+            ultrasounds,
+            config.ultrasound_schedule,
+            config.ultrasound_map,
+            config.filter,
+        ))
+        .unwrap();
     spawner
-        .spawn(rotary_encoder::rotary_encoder_task(qei))
+        .spawn(rotary_encoder::rotary_encoder_task(
+            qei,
+            config.ticks_per_cm,
+        ))
         .unwrap();
 
     let tx_buf: &mut [u8; 32] = singleton!(TX_BUF: [u8; 32] = [0; 32]).unwrap();
     let rx_buf: &mut [u8; 32] = singleton!(RX_BUF: [u8; 32] = [0; 32]).unwrap();
-    let uart = BufferedUart::new(
-        peripherals.UART4,
-        UARTIRqs,
-        peripherals.PC11,
-        peripherals.PC10,
-        tx_buf,
-        rx_buf,
-        config,
-    )
-    .unwrap();
-
-    let lin = lin_master::LinMaster { driver: uart };
-    spawner.spawn(lin_master::lin_scheduler(lin)).unwrap();
+
+    #[cfg(not(feature = "lin-slave"))]
+    {
+        let uart = BufferedUart::new(
+            peripherals.UART4,
+            UARTIRqs,
+            peripherals.PC11,
+            peripherals.PC10,
+            tx_buf,
+            rx_buf,
+            uart_config,
+        )
+        .unwrap();
+        let lin = lin_master::LinMaster { driver: uart };
+        spawner.spawn(lin_master::lin_scheduler(lin)).unwrap();
+        spawner.spawn(lighting::lighting_task()).unwrap();
+        spawner.spawn(lin_diag::lin_diag()).unwrap();
+    }
+    #[cfg(feature = "lin-slave")]
+    {
+        let slave = lin_slave::LinSlave::new(
+            peripherals.UART4,
+            UARTIRqs,
+            peripherals.PC11,
+            peripherals.PC10,
+            tx_buf,
+            rx_buf,
+            uart_config,
+        )
+        .unwrap();
+        spawner.spawn(lin_slave::lin_slave(slave)).unwrap();
+    }
 }
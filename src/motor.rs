@@ -0,0 +1,131 @@
+use core::time::Duration;
+
+use cortex_m::prelude::_embedded_hal_Pwm;
+use defmt::info;
+use embassy_executor::task;
+use embassy_stm32::{
+    peripherals::TIM4,
+    timer::{simple_pwm::SimplePwm, Channel, GeneralInstance4Channel},
+};
+use embassy_time::{Instant, Timer};
+use stm_board_logic::motor::SpeedController;
+
+use crate::{
+    collision::{BrakeAssist, Obstacle},
+    BRAKE_DISTANCES, BRAKE_INTERVENTION, MOTOR_CONFIG, MOTOR_SPEED, MOTOR_STOP, MOTOR_TARGET_SPEED,
+};
+
+pub use stm_board_logic::motor::{MotorConfig, DEFAULT_MOTOR};
+
+const CONTROL_PERIOD_MS: u64 = 20;
+
+// distances older than this are treated as unknown obstacles
+const DISTANCE_MAX_AGE_MS: u64 = 500;
+
+/// RC car ESC driven by a servo-style pulse.
+pub struct Esc<T: GeneralInstance4Channel> {
+    pwm: SimplePwm<'static, T>,
+    channel: Channel,
+    period: Duration,
+    min: Duration,
+    neutral: Duration,
+    max: Duration,
+}
+
+impl<T: GeneralInstance4Channel> Esc<T> {
+    pub fn new(
+        pwm: SimplePwm<'static, T>,
+        channel: Channel,
+        period: Duration,
+        min: Duration,
+        neutral: Duration,
+        max: Duration,
+    ) -> Self {
+        let mut esc = Self {
+            pwm,
+            channel,
+            period,
+            min,
+            neutral,
+            max,
+        };
+        // ESC arms only after seeing the neutral pulse
+        esc.set(0.0);
+        esc.pwm.enable(esc.channel);
+        esc
+    }
+
+    pub fn set(&mut self, throttle: f32) {
+        let throttle = throttle.clamp(-1.0, 1.0);
+
+        let tick_us = self.period / self.pwm.get_max_duty();
+        let neutral = self.neutral.as_secs_f32();
+        let calculated_time = if throttle >= 0.0 {
+            neutral + throttle * (self.max.as_secs_f32() - neutral)
+        } else {
+            neutral + throttle * (neutral - self.min.as_secs_f32())
+        };
+
+        let duty = (calculated_time / tick_us.as_secs_f32()) as u32;
+        self.pwm.set_duty(self.channel, duty);
+    }
+}
+
+#[task]
+pub async fn motor_task(mut esc: Esc<TIM4>, config: MotorConfig) {
+    let dt = CONTROL_PERIOD_MS as f32 / 1000.0;
+    let mut controller = SpeedController::new(config.speed_pid, config.max_acceleration);
+    let mut brake_assist = BrakeAssist::new(config.brake_assist);
+    let mut target = 0.0;
+    let mut measured = 0.0;
+    let mut obstacles = (Obstacle::Unknown, Obstacle::Unknown);
+    let mut obstacles_ts = Instant::now();
+    let mut intervention = false;
+
+    loop {
+        if let Some(config) = MOTOR_CONFIG.try_take() {
+            info!("Motor config {}", config);
+            controller.set_gains(config.speed_pid);
+            controller.set_max_acceleration(config.max_acceleration);
+            brake_assist.set_config(config.brake_assist);
+        }
+        if let Some(speed) = MOTOR_TARGET_SPEED.try_take() {
+            info!("Motor req to {}", speed);
+            target = speed;
+        }
+        // failsafe stop, skips the acceleration ramp
+        if MOTOR_STOP.try_take().is_some() {
+            target = 0.0;
+            controller.limit_setpoint(0.0, 0.0);
+        }
+        if let Some(speed) = MOTOR_SPEED.try_take() {
+            measured = speed;
+        }
+
+        if let Some(results) = BRAKE_DISTANCES.try_take() {
+            obstacles = (
+                Obstacle::nearest(&results[..3]),
+                Obstacle::nearest(&results[3..]),
+            );
+            obstacles_ts = Instant::now();
+        }
+        if obstacles_ts.elapsed().as_millis() > DISTANCE_MAX_AGE_MS {
+            obstacles = (Obstacle::Unknown, Obstacle::Unknown);
+        }
+
+        let limit = brake_assist.update(obstacles.0, obstacles.1, measured);
+        let limited_target = limit.apply(target);
+        controller.limit_setpoint(-limit.max_reverse_kmh, limit.max_forward_kmh);
+
+        if (limited_target != target) != intervention {
+            intervention = limited_target != target;
+            info!("Brake assist intervention {} limit {}", intervention, limit);
+            BRAKE_INTERVENTION.signal(intervention);
+        }
+
+        let throttle = controller.update(limited_target, measured, dt);
+        esc.set(throttle);
+
+        Timer::after_millis(CONTROL_PERIOD_MS).await;
+    }
+}
@@ -0,0 +1,94 @@
+use defmt::{info, warn};
+use embassy_executor::task;
+use embassy_futures::select::{select3, Either3};
+use stm_board_logic::math::abs_f32;
+use stm_board_logic::params::{apply, apply_filter, ParamCommand, ParamError};
+pub use stm_board_logic::params::{Access, ParamRequest, ParamResponse, PARAMS};
+
+use crate::{
+    config::{Config, ConfigClient},
+    CONFIG, CONFIG_SAVE, MOTOR_CONFIG, PARAMS_SAVED, PARAM_FILTER, PARAM_REQUEST, PARAM_RESPONSE,
+    PARAM_SPEED, STEERING_LIMITS, ULTRASOUND_FILTER,
+};
+
+// below this speed parameters restricted to standstill may be written
+const STANDSTILL_KMH: f32 = 0.1;
+
+/// Hands the tunables to the tasks using them.
+fn publish(config: &Config) {
+    MOTOR_CONFIG.signal(config.motor);
+    STEERING_LIMITS.signal(config.steering_limits);
+    ULTRASOUND_FILTER.signal(config.filter);
+}
+
+#[task]
+pub async fn param_task() {
+    let mut speed = 0.0f32;
+    loop {
+        let event = select3(
+            PARAM_REQUEST.receive(),
+            PARAM_SPEED.wait(),
+            PARAM_FILTER.wait(),
+        )
+        .await;
+        let moving = abs_f32(speed) > STANDSTILL_KMH;
+        let request = match event {
+            Either3::First(request) => request,
+            Either3::Second(kmh) => {
+                speed = kmh;
+                continue;
+            }
+            Either3::Third(filter) => {
+                let mut config = CONFIG.lock(|config| *config.borrow());
+                match apply_filter(&mut config, filter, moving) {
+                    Ok(()) => {
+                        info!("Ultrasound filter {}", filter);
+                        CONFIG.lock(|current| *current.borrow_mut() = config);
+                        publish(&config);
+                    }
+                    Err(err) => warn!("Ultrasound filter {} rejected: {}", filter, err),
+                }
+                continue;
+            }
+        };
+
+        let result = match ParamCommand::from_request(&request) {
+            None => Err(ParamError::UnknownCommand),
+            Some(command) => {
+                let mut config = CONFIG.lock(|config| *config.borrow());
+                let result = apply(&mut config, command, moving);
+                match command {
+                    ParamCommand::Save if result.is_ok() => {
+                        CONFIG_SAVE.send((ConfigClient::Parameters, config)).await;
+                        match PARAMS_SAVED.wait().await {
+                            true => Ok(0),
+                            false => Err(ParamError::Flash),
+                        }
+                    }
+                    ParamCommand::Write(..) | ParamCommand::Defaults if result.is_ok() => {
+                        CONFIG.lock(|current| *current.borrow_mut() = config);
+                        publish(&config);
+                        result
+                    }
+                    _ => result,
+                }
+            }
+        };
+
+        let name = PARAMS
+            .get(request.index as usize)
+            .map_or("-", |param| param.name);
+        match result {
+            Ok(value) => info!("Parameter request {} ({}): {}", request, name, value),
+            Err(err) => warn!("Parameter request {} ({}) rejected: {}", request, name, err),
+        }
+        PARAM_RESPONSE
+            .send(ParamResponse {
+                command: request.command,
+                index: request.index,
+                error: result.err(),
+                value: result.unwrap_or(0),
+            })
+            .await;
+    }
+}
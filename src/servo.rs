@@ -1,22 +1,38 @@
 use core::time::Duration;
 
 use cortex_m::prelude::_embedded_hal_Pwm;
-use defmt::info;
+use defmt::{info, warn};
 use embassy_executor::task;
 use embassy_stm32::{
     peripherals::TIM3,
     timer::{simple_pwm::SimplePwm, Channel, GeneralInstance4Channel},
 };
 use embassy_time::Timer;
+use stm_board_logic::math::abs_f32;
 
-use crate::SERVO_DEGREE;
+use crate::{
+    config::ConfigClient,
+    steering::{SteeringLimits, SteeringProfile},
+    steering_cal::{CalAction, CalibrationProcedure},
+    CONFIG, CONFIG_SAVE, SERVO_DEGREE, STEERING_CAL_CMD, STEERING_CAL_SAVED, STEERING_CAL_STATUS,
+    STEERING_LIMITS, STEERING_SPEED,
+};
+
+pub use stm_board_logic::servo::{CalibrationPoint, ServoCalibration, MAX_CALIBRATION_POINTS};
+
+// one setpoint per PWM period
+const SERVO_PERIOD_MS: u64 = 20;
+// calibration is only entered below this speed
+const STANDSTILL_KMH: f32 = 0.1;
+/// Wheel angle of the calibrated end stops, the range of `Wheel_Angle`.
+pub const FULL_ANGLE_DEG: f32 = 45.0;
 
 pub struct Servo<T: GeneralInstance4Channel> {
     pwm: SimplePwm<'static, T>,
     channel: Channel,
     period: Duration,
-    min: Duration,
-    max: Duration,
+    calibration: ServoCalibration,
+    pulse_us: f32,
 }
 
 impl<T: GeneralInstance4Channel> Servo<T> {
@@ -24,17 +40,16 @@ impl<T: GeneralInstance4Channel> Servo<T> {
         pwm: SimplePwm<'static, T>,
         channel: Channel,
         period: Duration,
-        min: Duration,
-        max: Duration,
+        calibration: ServoCalibration,
     ) -> Self {
         let mut servo = Self {
             pwm,
             channel,
             period,
-            min,
-            max,
+            calibration,
+            pulse_us: 0.0,
         };
-        servo.set(0);
+        servo.set_angle(0.0);
         servo.enable();
         servo
     }
@@ -43,37 +58,104 @@ impl<T: GeneralInstance4Channel> Servo<T> {
         self.pwm.enable(self.channel);
     }
 
-    pub fn set(&mut self, percent: i8) {
-        let percent = percent.clamp(-100, 100);
+    /// Steers to a wheel angle in degrees, positive angles steer left.
+    pub fn set_angle(&mut self, angle_deg: f32) {
+        self.set_pulse_us(self.calibration.pulse_us(angle_deg));
+    }
+
+    /// Outputs a raw pulse width, bypassing the calibration.
+    pub fn set_pulse_us(&mut self, pulse_us: f32) {
+        self.pulse_us = pulse_us;
+        let tick_us = self.period.as_micros() as f32 / self.pwm.get_max_duty() as f32;
+        let duty = (pulse_us / tick_us) as u32;
 
-        let tick_us = self.period / self.pwm.get_max_duty();
-        let half = (self.max - self.min) / 2;
-        let center = self.min + half;
+        self.pwm.set_duty(self.channel, duty);
+    }
 
-        let shift = percent as f32 * half.as_secs_f32() / 100.0;
-        let calculated_time = Duration::from_secs_f32(center.as_secs_f32() + shift);
+    pub fn pulse_us(&self) -> f32 {
+        self.pulse_us
+    }
 
-        let duty = (calculated_time.as_secs_f32() / tick_us.as_secs_f32()) as u32;
-        info!("{}", duty);
+    pub fn calibration(&self) -> &ServoCalibration {
+        &self.calibration
+    }
 
-        self.pwm.set_duty(self.channel, duty);
+    pub fn set_calibration(&mut self, calibration: ServoCalibration) {
+        self.calibration = calibration;
     }
 }
 
 #[task]
-pub async fn servo_task(mut servo: Servo<TIM3>) {
+pub async fn servo_task(mut servo: Servo<TIM3>, limits: SteeringLimits) {
+    let dt = SERVO_PERIOD_MS as f32 / 1000.0;
+    let mut profile = SteeringProfile::new(limits);
+    let mut procedure = CalibrationProcedure::new();
+    let mut target = 0.0;
+    let mut speed = 0.0;
+    // record handed to the config task, applied once it is in flash
+    let mut saving = None;
+
     loop {
-        let degree = SERVO_DEGREE.wait().await;
-        info!("Servo req to {}", degree);
-        servo.set(degree as i8);
+        if let Some(degree) = SERVO_DEGREE.try_take() {
+            info!("Servo req to {}", degree);
+            target = degree;
+        }
+        if let Some(limits) = STEERING_LIMITS.try_take() {
+            info!("Steering limits {}", limits);
+            profile.set_limits(limits);
+        }
+        if let Some(kmh) = STEERING_SPEED.try_take() {
+            speed = kmh;
+        }
+
+        if let Some(command) = STEERING_CAL_CMD.try_take() {
+            info!("Steering calibration {}", command);
+            let moving = abs_f32(speed) > STANDSTILL_KMH;
+            match procedure.handle(command, servo.pulse_us() as u16, moving) {
+                CalAction::Pulse(pulse_us) => servo.set_pulse_us(pulse_us as f32),
+                CalAction::Save(record) => {
+                    let mut config = CONFIG.lock(|config| *config.borrow());
+                    config.steering = record;
+                    CONFIG_SAVE
+                        .send((ConfigClient::SteeringCalibration, config))
+                        .await;
+                    saving = Some(record);
+                }
+                CalAction::None | CalAction::Exit => {}
+            }
+            STEERING_CAL_STATUS.signal(procedure.status());
+        }
+        if let Some(saved) = STEERING_CAL_SAVED.try_take() {
+            if let (true, Some(record)) = (saved, saving) {
+                match record.calibration(FULL_ANGLE_DEG) {
+                    Some(calibration) => servo.set_calibration(calibration),
+                    None => warn!("Steering calibration {} not applied", record),
+                }
+            }
+            saving = None;
+            procedure.saved(saved);
+            STEERING_CAL_STATUS.signal(procedure.status());
+        }
+        if procedure.is_active() {
+            // the servo holds the jogged pulse, angle requests are ignored
+            Timer::after_millis(SERVO_PERIOD_MS).await;
+            continue;
+        }
+
+        let calibration = servo.calibration();
+        let full_angle = calibration.max_angle_deg.max(-calibration.min_angle_deg);
+        let max_angle = profile.max_angle(speed, full_angle);
+        servo.set_angle(profile.update(target, max_angle, dt));
+
+        Timer::after_millis(SERVO_PERIOD_MS).await;
     }
 }
 
 #[task]
 pub async fn servo_tester(mut servo: Servo<TIM3>) {
     loop {
-        for i in -100..100 {
-            servo.set(i);
+        for i in -45..45 {
+            servo.set_angle(i as f32);
             Timer::after_millis(300).await;
         }
     }
@@ -0,0 +1,47 @@
+use embassy_executor::task;
+use embassy_stm32::gpio::Output;
+use embassy_time::{Instant, Timer};
+
+use crate::{STATUS_CONDITIONS, STATUS_HISTORY};
+
+pub use stm_board_logic::status_led::{Pattern, Sequencer, Status};
+
+/// Raises or clears a condition, may be called from any task.
+pub fn post(status: Status, active: bool) {
+    STATUS_CONDITIONS.lock(|conditions| {
+        let mut conditions = conditions.borrow_mut();
+        if active {
+            *conditions |= status.mask();
+        } else {
+            *conditions &= !status.mask();
+        }
+    });
+    if active {
+        STATUS_HISTORY.lock(|history| *history.borrow_mut() |= status.mask());
+    }
+}
+
+/// Conditions currently raised.
+pub fn conditions() -> u8 {
+    STATUS_CONDITIONS.lock(|conditions| *conditions.borrow())
+}
+
+/// Conditions raised at any time since startup.
+pub fn history() -> u8 {
+    STATUS_HISTORY.lock(|history| *history.borrow())
+}
+
+const STATUS_LED_PERIOD_MS: u64 = 10;
+
+#[task]
+pub async fn status_led(mut led_pin: Output<'static>) {
+    let mut sequencer = Sequencer::new(Instant::now().as_millis());
+    loop {
+        if sequencer.update(Instant::now().as_millis(), conditions()) {
+            led_pin.set_high();
+        } else {
+            led_pin.set_low();
+        }
+        Timer::after_millis(STATUS_LED_PERIOD_MS).await;
+    }
+}
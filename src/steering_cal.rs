@@ -0,0 +1,4 @@
+pub use stm_board_logic::steering_cal::{
+    CalAction, CalCommand, CalError, CalState, CalStatus, CalibrationProcedure, CalibrationRecord,
+    DEFAULT_CALIBRATION, PULSE_MAX_US, PULSE_MIN_US,
+};
@@ -0,0 +1,201 @@
+use cortex_m::peripheral::SCB;
+use defmt::{info, warn};
+use embassy_executor::task;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_can::Id;
+
+pub use stm_board_logic::uds::{
+    negative_response, Action, Addressing, DiagData, Nrc, Server, SID_WRITE_DATA,
+};
+
+use crate::{
+    config::ConfigClient,
+    isotp::{self, Frame, Receiver, RxStatus, Transmitter, TxStep, FRAME_LEN, MAX_PAYLOAD},
+    status_led,
+    ultrasound::{UltrasoundResult, ULTRASOUND_CHANNELS},
+    CONFIG, CONFIG_SAVE, UDS_CONFIG_SAVED, UDS_DISTANCES, UDS_KL15, UDS_RX, UDS_SPEED, UDS_TX,
+};
+
+pub const REQUEST_ID: u16 = 0x7e0;
+pub const RESPONSE_ID: u16 = 0x7e8;
+/// Requests to all ECUs, single frames only.
+pub const FUNCTIONAL_ID: u16 = 0x7df;
+
+pub fn addressing(id: &Id) -> Option<Addressing> {
+    match id {
+        Id::Standard(id) if id.as_raw() == REQUEST_ID => Some(Addressing::Physical),
+        Id::Standard(id) if id.as_raw() == FUNCTIONAL_ID => Some(Addressing::Functional),
+        _ => None,
+    }
+}
+
+/// CAN frame for the diagnostic server.
+#[derive(Copy, Clone)]
+pub struct UdsFrame {
+    pub addressing: Addressing,
+    len: u8,
+    data: Frame,
+}
+
+impl UdsFrame {
+    pub fn new(addressing: Addressing, payload: &[u8]) -> Self {
+        let len = payload.len().min(FRAME_LEN);
+        let mut data = [0; FRAME_LEN];
+        data[..len].copy_from_slice(&payload[..len]);
+        Self {
+            addressing,
+            len: len as u8,
+            data,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+// time for the response to leave through can_tx before the reset
+const RESET_DELAY_MS: u64 = 50;
+// below P2* of 5 s the tester waits after the pending response
+const SAVE_TIMEOUT_MS: u64 = 2000;
+
+/// Frames other than flow control received while a response is sent, in the
+/// order they arrived.
+type Deferred = Channel<NoopRawMutex, UdsFrame, 8>;
+
+/// Sends a response, waiting for flow control of the tester in between.
+///
+/// Other frames arriving meanwhile, new requests, are queued in `deferred`
+/// for the receiver.
+async fn transmit(transmitter: &mut Transmitter, payload: &[u8], deferred: &Deferred) {
+    match transmitter.start(payload, Instant::now().as_millis()) {
+        Ok(frame) => UDS_TX.send(frame).await,
+        Err(err) => {
+            warn!("UDS response not sent: {}", err);
+            return;
+        }
+    }
+    loop {
+        match transmitter.poll(Instant::now().as_millis()) {
+            TxStep::Send(frame) => UDS_TX.send(frame).await,
+            TxStep::WaitUntil(deadline_ms) => {
+                let timeout = Timer::at(Instant::from_millis(deadline_ms));
+                if let Either::First(frame) = select(UDS_RX.receive(), timeout).await {
+                    if frame.addressing != Addressing::Physical
+                        || !isotp::is_flow_control(frame.payload())
+                    {
+                        if deferred.try_send(frame).is_err() {
+                            warn!("UDS request dropped while responding");
+                        }
+                        continue;
+                    }
+                    let now = Instant::now().as_millis();
+                    if let Err(err) = transmitter.on_frame(frame.payload(), now) {
+                        warn!("UDS response aborted: {}", err);
+                        return;
+                    }
+                }
+            }
+            TxStep::Done => return,
+            TxStep::Failed(err) => {
+                warn!("UDS response aborted: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[task]
+pub async fn uds_task() {
+    let mut server = Server::new(env!("CARGO_PKG_VERSION"));
+    let mut receiver = Receiver::new();
+    let mut transmitter = Transmitter::new();
+    let mut response = [0; MAX_PAYLOAD];
+    let mut data = DiagData {
+        kl15_mv: 0,
+        speed_kmh: 0.0,
+        distances: [UltrasoundResult::NotFitted; ULTRASOUND_CHANNELS],
+        conditions: 0,
+        history: 0,
+    };
+
+    let deferred = Deferred::new();
+
+    loop {
+        let frame = match deferred.try_receive() {
+            Ok(frame) => frame,
+            Err(_) => UDS_RX.receive().await,
+        };
+        if frame.addressing == Addressing::Functional && !isotp::is_single_frame(frame.payload()) {
+            continue;
+        }
+        let now = Instant::now().as_millis();
+        match receiver.on_frame(frame.payload(), now) {
+            RxStatus::Pending => continue,
+            RxStatus::FlowControl(flow_control) => {
+                UDS_TX.send(flow_control).await;
+                continue;
+            }
+            RxStatus::Error(err) => {
+                warn!("ISO-TP reception failed: {}", err);
+                continue;
+            }
+            RxStatus::Complete => {}
+        }
+
+        if let Some(millivolts) = UDS_KL15.try_take() {
+            data.kl15_mv = millivolts;
+        }
+        if let Some(kmh) = UDS_SPEED.try_take() {
+            data.speed_kmh = kmh;
+        }
+        if let Some(distances) = UDS_DISTANCES.try_take() {
+            data.distances = distances;
+        }
+        data.conditions = status_led::conditions();
+        data.history = status_led::history();
+
+        let request = receiver.message();
+        info!("UDS request {:02x}", request);
+        let mut config = CONFIG.lock(|config| *config.borrow());
+        let action = server.handle(
+            request,
+            frame.addressing,
+            now,
+            &data,
+            &mut config,
+            &mut response,
+        );
+        match action {
+            Action::None => {}
+            Action::Respond(len) => transmit(&mut transmitter, &response[..len], &deferred).await,
+            Action::Reset(len) => {
+                if len > 0 {
+                    transmit(&mut transmitter, &response[..len], &deferred).await;
+                }
+                Timer::after_millis(RESET_DELAY_MS).await;
+                SCB::sys_reset();
+            }
+            Action::Save(len) => {
+                let pending = negative_response(SID_WRITE_DATA, Nrc::ResponsePending);
+                transmit(&mut transmitter, &pending, &deferred).await;
+                // a result arriving after the timeout must not answer the next save
+                UDS_CONFIG_SAVED.reset();
+                CONFIG_SAVE.send((ConfigClient::Diagnostics, config)).await;
+                let saved = with_timeout(
+                    Duration::from_millis(SAVE_TIMEOUT_MS),
+                    UDS_CONFIG_SAVED.wait(),
+                )
+                .await;
+                if matches!(saved, Ok(true)) {
+                    transmit(&mut transmitter, &response[..len], &deferred).await;
+                } else {
+                    let failed = negative_response(SID_WRITE_DATA, Nrc::GeneralProgrammingFailure);
+                    transmit(&mut transmitter, &failed, &deferred).await;
+                }
+            }
+        }
+    }
+}
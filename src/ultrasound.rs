@@ -1,71 +1,385 @@
+use core::{future::poll_fn, task::Poll};
+
 use defmt::info;
 use embassy_executor::task;
-use embassy_stm32::{exti::ExtiInput, gpio::Output};
-use embassy_time::{with_timeout, Instant, Timer};
-use movavg::MovAvg;
+use embassy_futures::join::join_array;
+use embassy_stm32::{
+    exti::ExtiInput,
+    gpio::Output,
+    interrupt::{self, typelevel::Interrupt as _},
+    pac,
+    peripherals::TIM1,
+    time::Hertz,
+    timer::{
+        low_level::{self, FilterValue, InputCaptureMode, InputTISelection},
+        Channel,
+    },
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::{
+    filter::{Filter, FilterChain, FilterConfig},
+    AMBIENT_TEMPERATURE, BRAKE_DISTANCES, UDS_DISTANCES, ULTRASOUNDS, ULTRASOUND_FILTER,
+};
+
+pub use stm_board_logic::ultrasound::{
+    echo_to_mm, speed_of_sound, FiringSchedule, SensorTracker, UltrasoundResult,
+    DEFAULT_AMBIENT_TEMPERATURE_C, DISTANCE_MAX_MM, DISTANCE_NOT_FITTED, DISTANCE_NO_ECHO,
+    DISTANCE_TIMEOUT, ULTRASOUND_CHANNELS,
+};
+
+const AMBIENT_TEMPERATURE_MAX_AGE_MS: u64 = 5000;
+
+// longest wait for each echo edge
+const ECHO_TIMEOUT: Duration = Duration::from_millis(10);
 
-use crate::ULTRASOUNDS;
+/// Source of the echo pulse width.
+pub trait EchoInput {
+    /// Waits for the echo pulse following a trigger and returns its width,
+    /// or the failure to report when the pulse did not start or end in time.
+    async fn pulse_width(&mut self, timeout: Duration) -> Result<Duration, UltrasoundResult>;
+}
+
+/// Echo edges timestamped in software when the EXTI interrupt is serviced.
+impl EchoInput for ExtiInput<'static> {
+    async fn pulse_width(&mut self, timeout: Duration) -> Result<Duration, UltrasoundResult> {
+        with_timeout(timeout, self.wait_for_high())
+            .await
+            .map_err(|_| UltrasoundResult::Timeout)?;
+
+        let start = Instant::now();
 
+        with_timeout(timeout, self.wait_for_low())
+            .await
+            .map_err(|_| UltrasoundResult::NoEcho)?;
+
+        Ok(Instant::now() - start)
+    }
+}
+
+/// TIM1 counting at `CAPTURE_FREQ`, shared by the capture echo inputs.
+///
+/// Every echo runs a channel pair in PWM input mode, the first channel
+/// latches the rising and the second the falling edge of the same input. The
+/// counter must not wrap during the longest echo, at 1 MHz the 16-bit counter
+/// covers 65 ms.
+pub struct CaptureTimer {
+    timer: low_level::Timer<'static, TIM1>,
+}
+
+pub const CAPTURE_FREQ: Hertz = Hertz::mhz(1);
+
+/// Timer input of a capture echo, indexes `CAPTURE_WAKERS`.
 #[derive(Copy, Clone)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum UltrasoundResult {
-    Fail,
-    Measurement(u64),
-}
-
-async fn measure_ultrasound(
-    trigger: &mut Output<'static>,
-    echo: &mut ExtiInput<'static>,
-) -> UltrasoundResult {
-    trigger.set_high();
-    Timer::after_micros(10).await;
-    trigger.set_low();
-
-    let x = with_timeout(
-        embassy_time::Duration::from_millis(10),
-        echo.wait_for_high(),
-    )
-    .await;
-    if x.is_err() {
-        return UltrasoundResult::Fail;
+pub enum CaptureInput {
+    /// CH1 rising, CH2 falling edge
+    Ti1 = 0,
+    /// CH3 rising, CH4 falling edge
+    Ti3 = 1,
+}
+
+impl CaptureInput {
+    fn channels(self) -> (Channel, Channel) {
+        match self {
+            CaptureInput::Ti1 => (Channel::Ch1, Channel::Ch2),
+            CaptureInput::Ti3 => (Channel::Ch3, Channel::Ch4),
+        }
+    }
+}
+
+static CAPTURE_WAKERS: [AtomicWaker; 2] = [AtomicWaker::new(), AtomicWaker::new()];
+
+/// Wakes the echo whose falling edge was captured.
+pub struct CaptureInterruptHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::TIM1_CC> for CaptureInterruptHandler {
+    unsafe fn on_interrupt() {
+        let regs = pac::TIM1;
+        let status = regs.sr().read();
+        let enabled = regs.dier().read();
+        for input in [CaptureInput::Ti1, CaptureInput::Ti3] {
+            let falling = input.channels().1.index();
+            if status.ccif(falling) && enabled.ccie(falling) {
+                // the flag is left for the task, it reads both captures
+                regs.dier().modify(|w| w.set_ccie(falling, false));
+                CAPTURE_WAKERS[input as usize].wake();
+            }
+        }
+    }
+}
+
+impl CaptureTimer {
+    pub fn new(
+        tim: TIM1,
+        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::TIM1_CC, CaptureInterruptHandler>
+            + 'static,
+    ) -> Self {
+        let timer = low_level::Timer::new(tim);
+        timer.set_tick_freq(CAPTURE_FREQ);
+        // required for advanced timers
+        timer.enable_outputs();
+        timer.start();
+
+        interrupt::typelevel::TIM1_CC::unpend();
+        unsafe { interrupt::typelevel::TIM1_CC::enable() };
+        Self { timer }
+    }
+
+    /// Echo on `input`, its pin has to be configured as `CapturePin` of the
+    /// rising edge channel.
+    pub fn echo(&'static self, input: CaptureInput) -> CaptureEcho {
+        let (rising, falling) = input.channels();
+        for (channel, selection, mode) in [
+            (rising, InputTISelection::Normal, InputCaptureMode::Rising),
+            // the falling edge channel listens to the input of its neighbour
+            (
+                falling,
+                InputTISelection::Alternate,
+                InputCaptureMode::Falling,
+            ),
+        ] {
+            self.timer.enable_channel(channel, false);
+            self.timer.set_input_ti_selection(channel, selection);
+            self.timer
+                .set_input_capture_filter(channel, FilterValue::NOFILTER);
+            self.timer.set_input_capture_prescaler(channel, 0);
+            self.timer.set_input_capture_mode(channel, mode);
+            self.timer.enable_channel(channel, true);
+        }
+        CaptureEcho { timer: self, input }
+    }
+}
+
+/// Echo edges latched by a channel pair of the shared `CaptureTimer`.
+pub struct CaptureEcho {
+    timer: &'static CaptureTimer,
+    input: CaptureInput,
+}
+
+impl EchoInput for CaptureEcho {
+    async fn pulse_width(&mut self, timeout: Duration) -> Result<Duration, UltrasoundResult> {
+        let timer = &self.timer.timer;
+        let (rising, falling) = self.input.channels();
+        let waker = &CAPTURE_WAKERS[self.input as usize];
+
+        // edges of the previous echo must not be taken for this one
+        timer.clear_input_interrupt(rising);
+        timer.clear_input_interrupt(falling);
+
+        let falling_edge = poll_fn(|cx| {
+            waker.register(cx.waker());
+            if timer.get_input_interrupt(falling) {
+                Poll::Ready(())
+            } else {
+                timer.enable_input_interrupt(falling, true);
+                Poll::Pending
+            }
+        });
+        // the pulse has to start and end within `timeout` each
+        let ended = with_timeout(timeout + timeout, falling_edge).await;
+        timer.enable_input_interrupt(falling, false);
+
+        let started = timer.get_input_interrupt(rising);
+        match (started, ended) {
+            (true, Ok(())) => {}
+            (true, Err(_)) => return Err(UltrasoundResult::NoEcho),
+            // a falling edge alone ends a pulse from before the trigger
+            (false, _) => return Err(UltrasoundResult::Timeout),
+        }
+
+        let ticks = (timer.get_capture_value(falling) as u16)
+            .wrapping_sub(timer.get_capture_value(rising) as u16);
+        Ok(Duration::from_micros(
+            ticks as u64 * 1_000_000 / CAPTURE_FREQ.0 as u64,
+        ))
     }
+}
 
-    let start = Instant::now();
+/// Echo backend selectable per sensor.
+pub enum Echo {
+    Exti(ExtiInput<'static>),
+    Capture(CaptureEcho),
+}
 
-    let x = with_timeout(embassy_time::Duration::from_millis(10), echo.wait_for_low()).await;
+impl From<ExtiInput<'static>> for Echo {
+    fn from(echo: ExtiInput<'static>) -> Self {
+        Echo::Exti(echo)
+    }
+}
 
-    if x.is_err() {
-        return UltrasoundResult::Fail;
+impl From<CaptureEcho> for Echo {
+    fn from(echo: CaptureEcho) -> Self {
+        Echo::Capture(echo)
+    }
+}
+
+impl EchoInput for Echo {
+    async fn pulse_width(&mut self, timeout: Duration) -> Result<Duration, UltrasoundResult> {
+        match self {
+            Echo::Exti(echo) => echo.pulse_width(timeout).await,
+            Echo::Capture(echo) => echo.pulse_width(timeout).await,
+        }
+    }
+}
+
+pub struct UltrasoundSensor {
+    trigger: Output<'static>,
+    echo: Echo,
+}
+
+impl UltrasoundSensor {
+    pub fn new(trigger: Output<'static>, echo: impl Into<Echo>) -> Self {
+        Self {
+            trigger,
+            echo: echo.into(),
+        }
     }
 
-    let time = Instant::now() - start;
-    let res = UltrasoundResult::Measurement((time.as_micros() as f32 / 57.5 * 10.0) as u64);
+    async fn measure(&mut self, temperature_c: f32) -> UltrasoundResult {
+        self.trigger.set_high();
+        Timer::after_micros(10).await;
+        self.trigger.set_low();
 
-    info!("{}us {}mm", time.as_micros(), res);
-    res
+        let time = match self.echo.pulse_width(ECHO_TIMEOUT).await {
+            Ok(time) => time,
+            Err(failure) => return failure,
+        };
+        let res = UltrasoundResult::Measurement(echo_to_mm(time.as_micros(), temperature_c));
+
+        info!("{}us {}mm", time.as_micros(), res);
+        res
+    }
 }
 
+// time for echoes of the previous group to fade out
+const GROUP_GUARD_MS: u64 = 10;
+const RATE_REPORT_PERIOD_MS: u64 = 1000;
+
 #[task]
-pub async fn ultrasound(mut ultrasounds: [(i32, Output<'static>, ExtiInput<'static>); 2]) {
-    let mut avg: [MovAvg<u64, i64, 12>; 2] = [MovAvg::new(), MovAvg::new()];
-    loop {
-        let mut results = [UltrasoundResult::Fail; 6];
+pub async fn ultrasound(
+    sensors: [Option<UltrasoundSensor>; ULTRASOUND_CHANNELS],
+    schedule: FiringSchedule,
+    channel_map: [u8; ULTRASOUND_CHANNELS],
+    filter: FilterConfig,
+) {
+    // tasks can not be generic
+    run(sensors, schedule, channel_map, filter).await
+}
 
-        for (ch, ref mut trigger, ref mut echo) in ultrasounds.iter_mut() {
-            let mut result = measure_ultrasound(trigger, echo).await;
+/// Measures up to 31 sensors, `channel_map` selects the sensor reported in
+/// each CAN distance signal.
+async fn run<const N: usize>(
+    mut sensors: [Option<UltrasoundSensor>; N],
+    schedule: FiringSchedule,
+    channel_map: [u8; ULTRASOUND_CHANNELS],
+    filter: FilterConfig,
+) {
+    let fitted = sensors
+        .iter()
+        .enumerate()
+        .filter(|(_, sensor)| sensor.is_some())
+        .fold(0u32, |mask, (ch, _)| mask | 1 << ch);
+    let groups = schedule.groups::<N>(fitted);
+    info!("ultrasound schedule {} groups {:?}", schedule, groups);
+    if fitted == 0 {
+        ULTRASOUNDS.signal([UltrasoundResult::NotFitted; ULTRASOUND_CHANNELS]);
+        return;
+    }
+
+    let mut filters: [FilterChain; N] = core::array::from_fn(|_| FilterChain::new(filter));
+    let mut trackers = [SensorTracker::new(); N];
+    let mut results = [UltrasoundResult::NotFitted; N];
+
+    let mut ambient: Option<(f32, Instant)> = None;
+    let mut rate_counts = [0u16; N];
+    let mut rate_start = Instant::now();
 
-            if let UltrasoundResult::Measurement(val) = result {
-                avg[*ch as usize].feed(val);
+    loop {
+        if let Some(config) = ULTRASOUND_FILTER.try_take() {
+            info!("ultrasound filter {}", config);
+            for filter in filters.iter_mut() {
+                filter.configure(config);
             }
+        }
 
-            if let Ok(val) = avg[*ch as usize].try_get() {
-                result = UltrasoundResult::Measurement(val);
+        if let Some(temperature) = AMBIENT_TEMPERATURE.try_take() {
+            ambient = Some((temperature, Instant::now()));
+        }
+        let temperature = match ambient {
+            Some((temperature, ts))
+                if ts.elapsed().as_millis() <= AMBIENT_TEMPERATURE_MAX_AGE_MS =>
+            {
+                temperature
             }
+            _ => DEFAULT_AMBIENT_TEMPERATURE_C,
+        };
+
+        for &group in groups.iter().filter(|&&group| group != 0) {
+            let mut slots = sensors.iter_mut();
+            let measurements = join_array(core::array::from_fn::<_, N, _>(|ch| {
+                let sensor = slots.next().unwrap().as_mut();
+                async move {
+                    match sensor {
+                        Some(sensor) if group & (1 << ch) != 0 => {
+                            Some(sensor.measure(temperature).await)
+                        }
+                        _ => None,
+                    }
+                }
+            }))
+            .await;
+
+            for (ch, measurement) in measurements.into_iter().enumerate() {
+                let Some(measurement) = measurement else {
+                    continue;
+                };
+                rate_counts[ch] += 1;
+
+                let now = Instant::now().as_millis();
+                if let UltrasoundResult::Measurement(val) = measurement {
+                    filters[ch].feed(val, now);
+                }
+
+                let mut result = trackers[ch].update(measurement, now);
+                match result {
+                    UltrasoundResult::Measurement(_) => {
+                        if let Some(val) = filters[ch].output() {
+                            result = UltrasoundResult::Measurement(val);
+                        }
+                    }
+                    // drop samples from before the outage
+                    _ => filters[ch].reset(),
+                }
 
-            results[*ch as usize] = result;
-            info!("ultrasound {} {:?}", ch, result);
+                results[ch] = result;
+                info!(
+                    "ultrasound {} {:?} quality {}%",
+                    ch,
+                    result,
+                    trackers[ch].quality()
+                );
+            }
+
+            Timer::after_millis(GROUP_GUARD_MS).await;
         }
 
-        ULTRASOUNDS.signal(results);
+        let reported = channel_map.map(|ch| {
+            results
+                .get(ch as usize)
+                .copied()
+                .unwrap_or(UltrasoundResult::NotFitted)
+        });
+        ULTRASOUNDS.signal(reported);
+        BRAKE_DISTANCES.signal(reported);
+        UDS_DISTANCES.signal(reported);
+
+        let elapsed = rate_start.elapsed().as_millis();
+        if elapsed >= RATE_REPORT_PERIOD_MS {
+            let rates = rate_counts.map(|count| count as u64 * 1000 / elapsed);
+            info!("ultrasound measurement rate {:?} Hz", rates);
+            rate_counts = [0; N];
+            rate_start = Instant::now();
+        }
     }
 }